@@ -19,7 +19,7 @@ required_vars=(
   CONTROLLER_STATE CONTROLLER_BIN CONTROLLER_LOG_FILE CONTROLLER_GID_PREFIX
   REMOTE_BIN REMOTE_STATE_DIR REMOTE_LOG_FILE
   CHURN_ROUNDS REMOVE_READD_PER_ROUND GROUP_UPDATE_PER_ROUND
-  SECRET_LABEL SECRET_LENGTH RETRY_MAX RETRY_DELAY_SEC
+  SECRET_LABEL SECRET_LENGTH DHT_MAX_ATTEMPTS
 )
 
 for v in "${required_vars[@]}"; do
@@ -40,34 +40,17 @@ if [[ ! -x "$CONTROLLER_BIN" ]]; then
   exit 1
 fi
 
+# exit code of mysgm for invalid arguments, e.g. removing a pid that is not a member
+EXIT_INVALID_ARGUMENT=11
+
 log() {
   printf '[%s] %s\n' "$(date '+%Y-%m-%d %H:%M:%S')" "$*"
 }
 
-retry_run() {
-  local attempt=1
-  local max_attempts="$RETRY_MAX"
-  local delay="$RETRY_DELAY_SEC"
-
-  while true; do
-    if "$@"; then
-      return 0
-    fi
-
-    if (( attempt >= max_attempts )); then
-      echo "Command failed after ${max_attempts} attempts: $*" >&2
-      return 1
-    fi
-
-    log "Retry ${attempt}/${max_attempts} failed. Sleeping ${delay}s before retry."
-    attempt=$((attempt + 1))
-    sleep "$delay"
-  done
-}
-
 controller_cmd() {
   "$CONTROLLER_BIN" "$CONTROLLER_STATE" \
     --adapter dht --dht-host "$DHT_HOST" --dht-port "$DHT_PORT" \
+    --dht-max-attempts "$DHT_MAX_ATTEMPTS" \
     --log-file "$CONTROLLER_LOG_FILE" "$@"
 }
 
@@ -83,6 +66,7 @@ remote_cmd() {
   local state_path="${REMOTE_STATE_DIR}/${host}.json"
   remote_run "$host" "$REMOTE_BIN" "$state_path" \
     --adapter dht --dht-host "$DHT_HOST" --dht-port "$DHT_PORT" \
+    --dht-max-attempts "$DHT_MAX_ATTEMPTS" \
     --log-file "$REMOTE_LOG_FILE" "$@"
 }
 
@@ -109,10 +93,10 @@ bootstrap_nodes() {
     log "Bootstrapping node ${host}"
 
     if [[ "${RESET_NODES}" == "1" ]]; then
-      retry_run remote_cmd "$host" --reset --pid "$host" Me >/dev/null
+      remote_cmd "$host" --reset --pid "$host" Me >/dev/null
     fi
 
-    retry_run remote_cmd "$host" Advertise >/dev/null
+    remote_cmd "$host" Advertise >/dev/null
 
     local pid
     pid="$(remote_cmd "$host" Me | extract_last_line)"
@@ -129,10 +113,10 @@ bootstrap_nodes() {
 bootstrap_controller() {
   if [[ "${RESET_CONTROLLER}" == "1" ]]; then
     log "Resetting controller state"
-    retry_run controller_cmd --reset --pid "${CONTROLLER_NAME:-controller}" Me >/dev/null
+    controller_cmd --reset --pid "${CONTROLLER_NAME:-controller}" Me >/dev/null
   fi
 
-  retry_run controller_cmd Advertise >/dev/null
+  controller_cmd Advertise >/dev/null
 
   CONTROLLER_PID="$(controller_cmd Me | extract_last_line)"
   if [[ -z "$CONTROLLER_PID" ]]; then
@@ -157,7 +141,7 @@ create_group() {
 
 sync_all_nodes() {
   for host in "${NODES[@]}"; do
-    retry_run remote_cmd "$host" Groups >/dev/null
+    remote_cmd "$host" Groups >/dev/null
   done
 }
 
@@ -169,7 +153,7 @@ controller_sync() {
 add_all_nodes() {
   log "Adding all node PIDs to ${GROUP_ID}"
   controller_sync
-  retry_run controller_cmd Group "$GROUP_ID" Add "${NODE_PIDS[@]}" >/dev/null
+  controller_cmd Group "$GROUP_ID" Add "${NODE_PIDS[@]}" >/dev/null
   sync_all_nodes
 }
 
@@ -189,24 +173,19 @@ export_secrets_snapshot() {
   done
 }
 
-member_index_for_pid() {
-  local pid="$1"
-  controller_cmd Group "$GROUP_ID" Members \
-    | awk -v target="$pid" '$2==target {print $1; exit}'
-}
-
 remove_pid_from_group() {
   local pid="$1"
-  local idx
-  idx="$(member_index_for_pid "$pid")"
+  local status=0
 
-  if [[ -z "$idx" ]]; then
+  log "Removing pid=${pid}"
+  controller_cmd Group "$GROUP_ID" Remove --pid "$pid" >/dev/null || status=$?
+  if (( status == EXIT_INVALID_ARGUMENT )); then
     log "PID not found in members list (skip remove): $pid"
     return 1
   fi
-
-  log "Removing pid=${pid} index=${idx}"
-  retry_run controller_cmd Group "$GROUP_ID" Remove "$idx" >/dev/null
+  if (( status != 0 )); then
+    controller_cmd Group "$GROUP_ID" Remove --pid "$pid" >/dev/null || return 1
+  fi
   sync_all_nodes
   return 0
 }
@@ -216,16 +195,16 @@ readd_pid_to_group() {
   local pid="$2"
 
   log "Re-advertise and re-add pid=${pid} host=${host}"
-  retry_run remote_cmd "$host" Advertise >/dev/null
+  remote_cmd "$host" Advertise >/dev/null
   controller_sync
-  retry_run controller_cmd Group "$GROUP_ID" Add "$pid" >/dev/null
+  controller_cmd Group "$GROUP_ID" Add "$pid" >/dev/null
   sync_all_nodes
 }
 
 node_group_update() {
   local host="$1"
   log "Node self-update from ${host}"
-  retry_run remote_cmd "$host" Group "$GROUP_ID" Update >/dev/null
+  remote_cmd "$host" Group "$GROUP_ID" Update >/dev/null
   sync_all_nodes
 }
 
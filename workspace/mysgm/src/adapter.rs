@@ -0,0 +1,212 @@
+//! Key/value storage shared between agents, and the keys agents agree on.
+
+use super::{
+    error::MySgmError, file_adapter::FileAdapter, opendht::OpenDhtRestAdapter,
+    provider::MySgmProvider,
+};
+
+use core::error::Error;
+use hex::encode as hex_encode;
+use openmls::group::MlsGroup;
+use openmls_traits::OpenMlsProvider;
+use std::{
+    sync::mpsc::{Sender, channel},
+    thread::spawn,
+};
+
+/// Error adapters return from `put_checked` when the key is taken.
+#[derive(Debug)]
+pub struct KeyExists;
+
+impl core::fmt::Display for KeyExists {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Key already exists")
+    }
+}
+
+impl Error for KeyExists {}
+
+/// What a watch reports when it ends; every watch reports exactly once.
+#[derive(Debug)]
+pub enum WatchEvent {
+    /// A value was stored under the key
+    Stored(String),
+    /// The watch ended without a value, e.g. because its connection timed out; the key
+    /// can be watched again
+    Ended(String),
+}
+
+/// A write-once key/value store reachable by every agent.
+pub trait StorageAdapter {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>>;
+    /// Every distinct value stored under `key`; stores that let concurrent writers race
+    /// on `put_checked` may hold more than one.
+    fn get_all(&self, key: &str) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        Ok(self.get(key)?.into_iter().collect())
+    }
+    fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>>;
+    /// Watches `key` in the background and sends one [`WatchEvent`] on `events` once a
+    /// value is stored under it or the watch ends. Stores that cannot notify fail, and
+    /// their agents only learn about new values by polling.
+    fn watch(&self, key: &str, events: Sender<WatchEvent>) -> Result<(), Box<dyn Error>> {
+        let _ = (key, events);
+        Err("Watching keys is not supported".into())
+    }
+}
+
+impl StorageAdapter for FileAdapter {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        FileAdapter::get(self, key)
+    }
+
+    fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        FileAdapter::put_checked(self, key, value)
+    }
+
+    fn watch(&self, key: &str, events: Sender<WatchEvent>) -> Result<(), Box<dyn Error>> {
+        FileAdapter::watch(self, key, events)
+    }
+}
+
+impl StorageAdapter for OpenDhtRestAdapter {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        OpenDhtRestAdapter::get(self, key)
+    }
+
+    fn get_all(&self, key: &str) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        OpenDhtRestAdapter::get_all(self, key)
+    }
+
+    fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        OpenDhtRestAdapter::put_checked(self, key, value)
+    }
+
+    fn watch(&self, key: &str, events: Sender<WatchEvent>) -> Result<(), Box<dyn Error>> {
+        OpenDhtRestAdapter::watch(self, key, events)
+    }
+}
+
+/// An adapter confined to a namespace: every key is prefixed with the namespace, so that
+/// deployments sharing a store, e.g. experiments on one DHT, never read each other's keys.
+pub struct NamespacedAdapter {
+    inner: Box<dyn StorageAdapter>,
+    prefix: String,
+}
+
+impl NamespacedAdapter {
+    pub fn new(inner: Box<dyn StorageAdapter>, namespace: &str) -> Self {
+        Self {
+            inner,
+            prefix: format!("{namespace}."),
+        }
+    }
+    fn key(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix)
+    }
+}
+
+impl StorageAdapter for NamespacedAdapter {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.inner.get(&self.key(key))
+    }
+
+    fn get_all(&self, key: &str) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        self.inner.get_all(&self.key(key))
+    }
+
+    fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.inner.put_checked(&self.key(key), value)
+    }
+
+    fn watch(&self, key: &str, events: Sender<WatchEvent>) -> Result<(), Box<dyn Error>> {
+        // the inner adapter reports the prefixed key, which the watcher does not know
+        let (inner_events, inner) = channel();
+        self.inner.watch(&self.key(key), inner_events)?;
+        let key = key.to_string();
+        spawn(move || {
+            let event = match inner.recv() {
+                Ok(WatchEvent::Stored(_)) => WatchEvent::Stored(key),
+                Ok(WatchEvent::Ended(_)) | Err(_) => WatchEvent::Ended(key),
+            };
+            let _ = events.send(event);
+        });
+        Ok(())
+    }
+}
+
+/// Checks that `namespace` can prefix keys: it has to be usable in file names and URLs,
+/// and cannot contain the `.` separating it from the key.
+pub fn check_namespace(namespace: &str) -> Result<(), MySgmError> {
+    check_key_part("Namespace", namespace)
+}
+
+/// Checks that `part`, a pid, gid or namespace named `what` in the error, can go into keys
+/// as it is. Peers choose their pids and the gids of the groups they invite us to, so a
+/// `/`, `..` or space would otherwise end up in file paths and URLs.
+pub fn check_key_part(what: &str, part: &str) -> Result<(), MySgmError> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if part.is_empty() || !part.chars().all(valid) {
+        return Err(MySgmError::InvalidArgument(format!(
+            "{what} {part:?} has to be made of ASCII letters, digits, '-' and '_'"
+        )));
+    }
+    Ok(())
+}
+
+pub fn key_package_key(index: u64) -> String {
+    format!("kp{index}")
+}
+/// Legacy key of the `index`-th welcome in the sequence shared by all agents; only read.
+pub fn welcome_message_key(index: u64) -> String {
+    format!("wm{index}")
+}
+/// Key of the `index`-th welcome in the mailbox of agent `pid`.
+pub fn welcome_mailbox_key(pid: &str, index: u64) -> String {
+    format!("wm_{pid}_{index}")
+}
+
+/// Key of the `index`-th signature key rotation announced by agent `pid`.
+pub fn key_rotation_key(pid: &str, index: u64) -> String {
+    format!("kr_{pid}_{index}")
+}
+
+/// Key of the `index`-th GroupInfo published for external joins to group `gid`.
+pub fn group_info_key(gid: &str, index: u64) -> String {
+    format!("gi_{gid}_{index}")
+}
+
+pub fn commit_key(group: &MlsGroup, provider: &MySgmProvider) -> Result<String, Box<dyn Error>> {
+    Ok(format!(
+        "cm{}",
+        hex_encode(group.export_secret(provider.crypto(), "post_commit", &[], 32)?)
+    ))
+}
+
+/// Key for the `index`-th application message sent in the group's current epoch.
+pub fn application_message_key(
+    group: &MlsGroup,
+    provider: &MySgmProvider,
+    index: u64,
+) -> Result<String, Box<dyn Error>> {
+    Ok(format!(
+        "am{}",
+        hex_encode(group.export_secret(
+            provider.crypto(),
+            "application_message",
+            &index.to_be_bytes(),
+            32
+        )?)
+    ))
+}
+
+/// Key for the `index`-th proposal sent in the group's current epoch.
+pub fn proposal_key(
+    group: &MlsGroup,
+    provider: &MySgmProvider,
+    index: u64,
+) -> Result<String, Box<dyn Error>> {
+    Ok(format!(
+        "pr{}",
+        hex_encode(group.export_secret(provider.crypto(), "proposal", &index.to_be_bytes(), 32)?)
+    ))
+}
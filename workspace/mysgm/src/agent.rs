@@ -0,0 +1,2487 @@
+//! The agent: local MLS state plus the storage adapter it shares messages through.
+
+use super::{
+    adapter::{
+        KeyExists, NamespacedAdapter, StorageAdapter, WatchEvent, application_message_key,
+        check_key_part, commit_key, group_info_key, key_package_key, key_rotation_key,
+        proposal_key, welcome_mailbox_key, welcome_message_key,
+    },
+    envelope::StateKey,
+    error::MySgmError,
+    identity::fingerprint,
+    keys::SignatureKeyPair,
+    metrics::{MetricsEvent, log_event, now_ms},
+    persist::{self, StateBackend},
+    provider::MySgmProvider,
+    rotation::KeyRotation,
+    sqlite,
+    state::{
+        ExternalJoin, GroupPolicy, Invite, JoinMode, JoinPolicy, MySgmState, OpenMlsKeyValueStore,
+        ReceivedMessage,
+    },
+    x509::{self, TrustAnchors, X509Identity},
+};
+
+use hex::{decode as hex_decode, encode as hex_encode};
+use openmls::{
+    credentials::{BasicCredential, Credential, CredentialType, CredentialWithKey},
+    extensions::ExtensionType,
+    framing::{
+        ContentType, MlsMessageBodyIn, MlsMessageIn, MlsMessageOut, ProcessedMessageContent,
+        ProtocolMessage,
+    },
+    group::{GroupId, MlsGroup, MlsGroupCreateConfig, StagedCommit, StagedWelcome},
+    key_packages::{KeyPackage, KeyPackageBundle},
+    messages::group_info::VerifiableGroupInfo,
+    prelude::{
+        Capabilities, LeafNodeIndex, Lifetime, NewSignerBundle, Proposal, ProposalStore,
+        PublicGroup, Sender,
+    },
+    treesync::{LeafNode, LeafNodeParameters},
+    versions::ProtocolVersion,
+};
+use openmls_rust_crypto::RustCrypto;
+use openmls_traits::{
+    OpenMlsProvider, crypto::OpenMlsCrypto, storage::StorageProvider, types::Ciphersuite,
+};
+use serde_json::{
+    from_slice as json_decode_bytes, to_string as json_encode, to_vec as json_encode_bytes,
+};
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    sync::mpsc::{self, Receiver, channel},
+};
+use tls_codec::{Deserialize, Serialize};
+
+/// An MLS agent whose groups, key packages and welcomes are exchanged through a
+/// [`StorageAdapter`].
+pub struct Agent {
+    provider: MySgmProvider,
+    adapter: Box<dyn StorageAdapter>,
+    cred_with_key: CredentialWithKey,
+    capabilities: Capabilities,
+    group_config: MlsGroupCreateConfig,
+    reissue: bool,
+    backups: usize,
+    state_key: Option<StateKey>,
+    state_backend: StateBackend,
+    trust_anchors: Option<TrustAnchors>,
+    key_package_lifetime: u64,
+    watches: Option<Watches>,
+    /// Values stored through the adapter so far
+    writes: Cell<u64>,
+}
+
+/// Keys watched through the adapter for the values the next sync would fetch.
+struct Watches {
+    sender: mpsc::Sender<WatchEvent>,
+    events: Receiver<WatchEvent>,
+    /// Keys with a watch running
+    watched: HashSet<String>,
+    /// Keys reported stored and not fetched yet, with the time of the report
+    stored: HashMap<String, u128>,
+}
+
+/// Seconds a key package we advertise is valid for, unless configured otherwise.
+pub const DEFAULT_KEY_PACKAGE_LIFETIME: u64 = 28 * 24 * 60 * 60;
+
+/// How many times a commit that lost to a concurrent one is re-issued before giving up.
+const MAX_REISSUES: usize = 3;
+
+/// A commit of ours, ready to be published.
+struct OutgoingCommit {
+    commit: MlsMessageOut,
+    welcome: Option<MlsMessageOut>,
+    /// pids whose mailboxes receive the welcome
+    invitees: Vec<String>,
+}
+
+/// A commit of ours that made it into the group.
+struct MergedCommit {
+    members_after: usize,
+    commit_bytes: usize,
+    welcome_bytes: Option<usize>,
+}
+
+/// Which of the commits published for an epoch was merged.
+#[derive(PartialEq)]
+enum CommitWinner {
+    Own,
+    Other,
+}
+
+/// The commit that wins an epoch: our own, or another one as staged.
+#[derive(Debug, PartialEq)]
+enum Candidate<T> {
+    Own,
+    Other(T),
+}
+
+/// What became of a welcome under the join policy, with the gid it invited us to.
+enum WelcomeOutcome {
+    Joined(String),
+    Staged(String),
+    Dropped(String),
+}
+
+/// A signed GroupInfo, including the ratchet tree, published for agents joining by
+/// external commit, along with the key the next commit of the group goes to.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct PublishedGroupInfo {
+    epoch: u64,
+    commit_key: String,
+    /// hex-encoded `MlsMessageOut` holding the GroupInfo
+    group_info: String,
+}
+
+impl Agent {
+    /// Creates an agent syncing through `adapter`, confined to the namespace of the state
+    /// if it has one.
+    pub fn new(state: MySgmState, adapter: Box<dyn StorageAdapter>) -> Self {
+        let (cred_with_key, capabilities, group_config) = Self::config(&state);
+        let adapter: Box<dyn StorageAdapter> = match state.namespace() {
+            Some(namespace) => Box::new(NamespacedAdapter::new(adapter, namespace)),
+            None => adapter,
+        };
+        Self {
+            provider: MySgmProvider::new(state, Default::default()),
+            adapter,
+            cred_with_key,
+            capabilities,
+            group_config,
+            reissue: false,
+            backups: persist::DEFAULT_BACKUPS,
+            state_key: None,
+            state_backend: StateBackend::Json,
+            trust_anchors: None,
+            key_package_lifetime: DEFAULT_KEY_PACKAGE_LIFETIME,
+            watches: None,
+            writes: Cell::new(0),
+        }
+    }
+    /// Whether a commit that loses to a concurrent commit of another member is re-issued
+    /// in the new epoch, rather than failing with [`MySgmError::CommitConflict`].
+    pub fn set_reissue(&mut self, reissue: bool) {
+        self.reissue = reissue;
+    }
+    /// How many previous versions of the state file [`Agent::save_state`] keeps.
+    pub fn set_backups(&mut self, backups: usize) {
+        self.backups = backups;
+    }
+    /// Key [`Agent::save_state`] seals the state file with; without one it is written in
+    /// plain JSON.
+    pub fn set_state_key(&mut self, state_key: Option<StateKey>) {
+        self.state_key = state_key;
+    }
+    /// Whether [`Agent::save_state`] writes a JSON file or commits to a SQLite database.
+    pub fn set_state_backend(&mut self, state_backend: StateBackend) {
+        self.state_backend = state_backend;
+    }
+    /// CAs that X.509 credentials of peers are validated against; without them, peers
+    /// with X.509 credentials are refused.
+    pub fn set_trust_anchors(&mut self, trust_anchors: Option<TrustAnchors>) {
+        self.trust_anchors = trust_anchors;
+    }
+    /// Seconds the key packages [`Agent::advertise`] publishes are valid for.
+    pub fn set_key_package_lifetime(&mut self, key_package_lifetime: u64) {
+        self.key_package_lifetime = key_package_lifetime;
+    }
+    /// Credential, capabilities and group configuration derived from the agent's state.
+    fn config(state: &MySgmState) -> (CredentialWithKey, Capabilities, MlsGroupCreateConfig) {
+        // credential
+        let cred_with_key = CredentialWithKey {
+            credential: state
+                .credential()
+                .cloned()
+                .unwrap_or_else(|| BasicCredential::new(state.my_pid().as_bytes().to_vec()).into()),
+            signature_key: state.signature_key_pair().public_key_raw().into(),
+        };
+        // capabilities
+        let capabilities = Capabilities::new(
+            None,
+            Some(&[state.my_ciphersuite()]),
+            Some(&[ExtensionType::LastResort]),
+            None,
+            Some(&[CredentialType::Basic, CredentialType::X509]),
+        );
+        // config
+        let group_config = MlsGroupCreateConfig::builder()
+            .ciphersuite(state.my_ciphersuite())
+            .use_ratchet_tree_extension(true)
+            .capabilities(capabilities.clone())
+            .build();
+        (cred_with_key, capabilities, group_config)
+    }
+    /// Generates a fresh identity for an agent named after `pid`, using `ciphersuite` for
+    /// its groups and key packages.
+    pub fn generate_state(pid: &str, ciphersuite: Ciphersuite) -> Result<MySgmState, MySgmError> {
+        check_key_part("Pid", pid)?;
+        let crypto = RustCrypto::default();
+        // ciphersuite
+        crypto.supports(ciphersuite).map_err(|_| {
+            MySgmError::InvalidArgument(format!("Unsupported ciphersuite: {ciphersuite:?}"))
+        })?;
+        // signature key pair
+        let signature_key_pair =
+            SignatureKeyPair::from_crypto(&crypto, ciphersuite.into()).map_err(MySgmError::mls)?;
+        let pid_transformed = format!(
+            "{}_{}",
+            pid,
+            hex_encode(signature_key_pair.public_key_raw())
+                .chars()
+                .take(3)
+                .collect::<String>()
+        );
+        Ok(MySgmState::new(
+            pid_transformed,
+            signature_key_pair,
+            ciphersuite,
+            ProtocolVersion::Mls10,
+        ))
+    }
+    /// Creates the state of an agent with the X.509 credential of `identity`, using
+    /// `ciphersuite`, whose signature scheme has to be the one of the certificate's key.
+    pub fn generate_x509_state(
+        identity: X509Identity,
+        ciphersuite: Ciphersuite,
+    ) -> Result<MySgmState, MySgmError> {
+        RustCrypto::default().supports(ciphersuite).map_err(|_| {
+            MySgmError::InvalidArgument(format!("Unsupported ciphersuite: {ciphersuite:?}"))
+        })?;
+        let signature_scheme = identity.signature_key_pair.signature_scheme();
+        if ciphersuite.signature_algorithm() != signature_scheme {
+            return Err(MySgmError::InvalidArgument(format!(
+                "The certificate key is for {signature_scheme:?}, but {ciphersuite:?} signs with {:?}",
+                ciphersuite.signature_algorithm()
+            )));
+        }
+        let mut state = MySgmState::new(
+            identity.pid,
+            identity.signature_key_pair,
+            ciphersuite,
+            ProtocolVersion::Mls10,
+        );
+        state.set_credential(x509::credential(&identity.chain)?);
+        Ok(state)
+    }
+    /// Loads the state from `state_path`. A JSON state is decrypted with `state_key` if it
+    /// is sealed, falling back to its newest readable backup.
+    pub fn load_state(
+        state_path: &str,
+        state_backend: StateBackend,
+        state_key: Option<&StateKey>,
+    ) -> Result<MySgmState, MySgmError> {
+        log::debug!("Attempting to load state from file");
+        match state_backend {
+            StateBackend::Json => Ok(persist::load(state_path, state_key)?.state),
+            StateBackend::Sqlite => sqlite::load(state_path),
+        }
+    }
+    /// Atomically writes the state to `state_path`, returning its JSON serialization.
+    pub fn save_state(&self, state_path: &str) -> Result<String, MySgmError> {
+        // the state holds private keys and received messages, so only its identity is logged
+        log::info!(
+            "Saving state of pid: {} at schema version {}",
+            self.state().my_pid(),
+            self.state().schema_version()
+        );
+        let state_json = json_encode(self.state())?;
+        match self.state_backend {
+            StateBackend::Json => persist::save(
+                state_path,
+                &state_json,
+                self.state_key.as_ref(),
+                self.backups,
+            )?,
+            StateBackend::Sqlite => sqlite::save(state_path, self.state())?,
+        }
+        Ok(state_json)
+    }
+    /// Discards the in-memory state in favour of `state`, keeping the adapter.
+    pub fn replace_state(&mut self, state: MySgmState) {
+        self.provider = MySgmProvider::new(state, Default::default());
+        self.reconfigure();
+    }
+    /// Derives the credential, capabilities and group configuration from the state again.
+    fn reconfigure(&mut self) {
+        let (cred_with_key, capabilities, group_config) = Self::config(self.state());
+        self.cred_with_key = cred_with_key;
+        self.capabilities = capabilities;
+        self.group_config = group_config;
+    }
+    pub fn state(&self) -> &MySgmState {
+        self.provider.state()
+    }
+    pub fn state_key(&self) -> Option<&StateKey> {
+        self.state_key.as_ref()
+    }
+    pub fn state_backend(&self) -> StateBackend {
+        self.state_backend
+    }
+    /// Number of values this agent stored through the adapter, so that callers can tell
+    /// whether an operation that failed midway already published something.
+    pub fn writes(&self) -> u64 {
+        self.writes.get()
+    }
+    pub fn provider(&self) -> &MySgmProvider {
+        &self.provider
+    }
+}
+
+// sync
+impl Agent {
+    /// Downloads key packages, welcomes, application messages and commits from the adapter.
+    pub fn sync(&mut self) -> Result<(), MySgmError> {
+        self.download_key_packages()?;
+        self.renew_key_package()?;
+        self.prune_key_packages()?;
+        self.download_welcomes()?;
+        self.replenish_key_packages()?;
+        self.resolve_external_joins()?;
+        for gid in self.state().gids() {
+            self.download_commits(&gid)?;
+            if self.state().gids().contains(&gid) {
+                self.commit_leave_requests(&gid)?;
+                self.renew_leave_request(&gid)?;
+            }
+        }
+        self.arm_watches();
+        Ok(())
+    }
+    /// Watches the keys the next sync would fetch, from the end of every sync on, so that
+    /// [`Agent::take_notifications`] tells when to sync again.
+    pub fn enable_watches(&mut self) {
+        let (sender, events) = channel();
+        self.watches = Some(Watches {
+            sender,
+            events,
+            watched: HashSet::new(),
+            stored: HashMap::new(),
+        });
+        self.arm_watches();
+    }
+    /// Whether a watched key was stored since the last call. Watches that ended are
+    /// renewed by the next sync.
+    pub fn take_notifications(&mut self) -> bool {
+        let Some(watches) = &mut self.watches else {
+            return false;
+        };
+        let mut stored = false;
+        while let Ok(event) = watches.events.try_recv() {
+            match event {
+                WatchEvent::Stored(key) => {
+                    log::info!("Watched key stored: {key}");
+                    watches.watched.remove(&key);
+                    watches.stored.insert(key, now_ms());
+                    stored = true;
+                }
+                WatchEvent::Ended(key) => {
+                    watches.watched.remove(&key);
+                }
+            }
+        }
+        stored
+    }
+    /// Starts watches on the keys the next sync would fetch that have none running. A key
+    /// reported stored that the sync did not get past, e.g. a commit that failed to merge,
+    /// is left to polling, as its watch would report it again right away. If the adapter
+    /// cannot watch, watches are disabled and syncing falls back to polling.
+    fn arm_watches(&mut self) {
+        if self.watches.is_none() {
+            return;
+        }
+        let keys = self.watch_keys();
+        let Some(watches) = &mut self.watches else {
+            return;
+        };
+        watches.stored.retain(|key, _| keys.contains(key));
+        for key in keys {
+            if watches.watched.contains(&key) || watches.stored.contains_key(&key) {
+                continue;
+            }
+            if let Err(e) = self.adapter.watch(&key, watches.sender.clone()) {
+                log::warn!("Falling back to polling, failed to watch {key}: {e}");
+                self.watches = None;
+                return;
+            }
+            watches.watched.insert(key);
+        }
+    }
+    /// Keys of the next key package, welcome, and per group the next commit, application
+    /// message and proposal.
+    fn watch_keys(&self) -> HashSet<String> {
+        let state = self.state();
+        let mut keys = HashSet::from([
+            key_package_key(state.key_package_counter()),
+            welcome_mailbox_key(state.my_pid(), state.mailbox_counter()),
+            welcome_message_key(state.welcome_counter()),
+        ]);
+        for gid in state.gids() {
+            let Ok(group) = self.load_group(&gid) else {
+                continue;
+            };
+            let epoch = group.epoch().as_u64();
+            let group_keys = [
+                commit_key(&group, &self.provider),
+                application_message_key(&group, &self.provider, state.message_cursor(&gid, epoch)),
+                proposal_key(&group, &self.provider, state.proposal_cursor(&gid, epoch)),
+            ];
+            keys.extend(group_keys.into_iter().filter_map(Result::ok));
+        }
+        keys
+    }
+    fn download_key_packages(&mut self) -> Result<(), MySgmError> {
+        loop {
+            let key = key_package_key(self.state().key_package_counter());
+            log::info!("Key package key to get: {key}");
+            let Some(kp_bytes) = self.adapter.get(&key).map_err(MySgmError::adapter)? else {
+                log::info!("No more key packages to download");
+                return Ok(());
+            };
+            self.provider.state_mut().increment_key_package_counter();
+            log::info!("Got key package bytes: {}", hex_encode(&kp_bytes));
+            match self.key_package_from_bytes(kp_bytes) {
+                Ok((pid, kp)) => {
+                    log::info!("pid of key package: {pid}");
+                    self.accept_key_package(&pid, kp)?;
+                }
+                Err(e) => log::warn!("Skipping key package at {key}: {e}"),
+            }
+        }
+    }
+    fn key_package_from_bytes(
+        &self,
+        kp_bytes: Vec<u8>,
+    ) -> Result<(String, KeyPackage), MySgmError> {
+        match MlsMessageIn::tls_deserialize_exact(kp_bytes)?.extract() {
+            MlsMessageBodyIn::KeyPackage(kp_in) => {
+                let kp = kp_in
+                    .validate(self.provider.crypto(), self.state().mls_version())
+                    .map_err(MySgmError::mls)?;
+                log::info!("Processed key package: {kp:?}");
+                let (pid, _) = self.checked_leaf_key(kp.leaf_node())?;
+                if !kp.life_time().is_valid() {
+                    return Err(MySgmError::ExpiredKeyPackage(pid));
+                }
+                Ok((pid, kp))
+            }
+            _ => Err(MySgmError::UnexpectedMessage(
+                "Expected KeyPackage message".to_string(),
+            )),
+        }
+    }
+    /// Advertises a new key package once less than a quarter of the lifetime of ours is
+    /// left, so that peers never hold an expired one.
+    fn renew_key_package(&mut self) -> Result<(), MySgmError> {
+        let Some(kp) = self.state().key_package(self.state().my_pid()) else {
+            return Ok(());
+        };
+        let life_time = kp.life_time();
+        let remaining = life_time.not_after().saturating_sub(now_secs());
+        if remaining > life_time.not_after().saturating_sub(life_time.not_before()) / 4 {
+            return Ok(());
+        }
+        log::info!("Renewing our key package, which expires in {remaining} s");
+        self.publish_key_package(true)
+    }
+    /// Drops the expired single-use key packages of peers, and deletes the private keys of
+    /// the retired key packages of ours that have expired.
+    fn prune_key_packages(&mut self) -> Result<(), MySgmError> {
+        let now = now_secs();
+        let state = self.provider.state_mut();
+        state.retain_all_queued_key_packages(|kp| kp.life_time().not_after() >= now);
+        let expired = state.prune_retired_key_packages(|kp| kp.life_time().not_after() < now);
+        for kp in expired {
+            let hash_ref = kp
+                .hash_ref(self.provider.crypto())
+                .map_err(MySgmError::mls)?;
+            log::info!("Deleting the private keys of expired key package {hash_ref:?}");
+            self.provider
+                .storage()
+                .delete_key_package(&hash_ref)
+                .map_err(MySgmError::mls)?;
+        }
+        Ok(())
+    }
+    /// Drops the single-use key packages of ours that a welcome consumed or that expired,
+    /// and publishes new ones until the pool is back to its size.
+    fn replenish_key_packages(&mut self) -> Result<(), MySgmError> {
+        let now = now_secs();
+        let mut stale = Vec::new();
+        for kp in self.state().key_package_pool() {
+            let hash_ref = kp
+                .hash_ref(self.provider.crypto())
+                .map_err(MySgmError::mls)?;
+            // OpenMLS deletes a single-use key package once a welcome uses it
+            let consumed = self
+                .provider
+                .storage()
+                .key_package::<_, KeyPackageBundle>(&hash_ref)
+                .map_err(MySgmError::mls)?
+                .is_none();
+            if consumed {
+                log::info!("Key package {hash_ref:?} was consumed by a welcome");
+                stale.push(kp.clone());
+            } else if kp.life_time().not_after() < now {
+                log::info!("Deleting the private keys of expired key package {hash_ref:?}");
+                self.provider
+                    .storage()
+                    .delete_key_package(&hash_ref)
+                    .map_err(MySgmError::mls)?;
+                stale.push(kp.clone());
+            }
+        }
+        self.provider
+            .state_mut()
+            .remove_from_key_package_pool(&stale);
+        let missing = self
+            .state()
+            .key_package_pool_size()
+            .saturating_sub(self.state().key_package_pool().len());
+        for _ in 0..missing {
+            self.publish_key_package(false)?;
+        }
+        Ok(())
+    }
+    fn download_welcomes(&mut self) -> Result<(), MySgmError> {
+        // welcome messages addressed to our own mailbox
+        loop {
+            let welcome_index = self.state().mailbox_counter();
+            let key = welcome_mailbox_key(self.state().my_pid(), welcome_index);
+            if !self.download_welcome(&key, welcome_index)? {
+                log::info!("No more welcome messages in mailbox");
+                break;
+            }
+            self.provider.state_mut().increment_mailbox_counter();
+        }
+        // welcome messages from the legacy shared sequence
+        loop {
+            let welcome_index = self.state().welcome_counter();
+            let key = welcome_message_key(welcome_index);
+            if !self.download_welcome(&key, welcome_index)? {
+                log::info!("No more welcome messages to download");
+                break;
+            }
+            self.provider.state_mut().increment_welcome_counter();
+        }
+        Ok(())
+    }
+    /// Fetches the welcome stored at `key` and joins the group it invites us to; returns
+    /// `false` once there is nothing stored at `key`.
+    fn download_welcome(&mut self, key: &str, welcome_index: u64) -> Result<bool, MySgmError> {
+        log::info!("Welcome message key to get: {key}");
+        let started = now_ms();
+        let Some(wm_bytes) = self.adapter.get(key).map_err(MySgmError::adapter)? else {
+            return Ok(false);
+        };
+        let mut download_event = MetricsEvent::new("welcome_download", started, now_ms());
+        download_event.node_id = Some(self.state().my_pid().to_string());
+        download_event.welcome_index = Some(welcome_index);
+        download_event.dht_key = Some(key.to_string());
+        download_event.payload_bytes = Some(wm_bytes.len());
+        log_event(&download_event);
+        log::info!("Got welcome message bytes: {}", hex_encode(&wm_bytes));
+        let mut process_event = MetricsEvent::new("welcome_process", started, now_ms());
+        process_event.node_id = Some(self.state().my_pid().to_string());
+        process_event.welcome_index = Some(welcome_index);
+        process_event.dht_key = Some(key.to_string());
+        match self.receive_welcome(wm_bytes) {
+            Ok(WelcomeOutcome::Joined(gid)) => {
+                log::info!("Group with gid: {gid}");
+                process_event.gid = Some(gid);
+                process_event.welcome_processed = Some(true);
+                process_event.welcome_outcome = Some("joined".to_string());
+            }
+            Ok(WelcomeOutcome::Staged(gid)) => {
+                process_event.gid = Some(gid);
+                process_event.welcome_processed = Some(false);
+                process_event.welcome_outcome = Some("staged".to_string());
+            }
+            Ok(WelcomeOutcome::Dropped(gid)) => {
+                process_event.gid = Some(gid);
+                process_event.welcome_processed = Some(false);
+                process_event.welcome_outcome = Some("dropped".to_string());
+            }
+            Err(e) => {
+                log::warn!("Failed to process welcome: {e}");
+                process_event.welcome_processed = Some(false);
+                process_event.result = "error".to_string();
+                process_event.error = Some(e.to_string());
+            }
+        }
+        process_event.ts_end_ms = now_ms();
+        process_event.duration_ms = process_event.ts_end_ms.saturating_sub(started);
+        log_event(&process_event);
+        Ok(true)
+    }
+    /// Joins the group a welcome invites us to, stages the invitation or drops it,
+    /// depending on the join policy and on who sent the welcome.
+    fn receive_welcome(&mut self, wm_bytes: Vec<u8>) -> Result<WelcomeOutcome, MySgmError> {
+        let (staged_welcome, pins) = self.stage_welcome(&wm_bytes)?;
+        let gid = staged_gid(&staged_welcome)?;
+        let inviter = staged_welcome
+            .welcome_sender()
+            .map_err(MySgmError::mls)
+            .and_then(|sender| pid_of(sender.credential()))?;
+        let policy = self.state().join_policy().clone();
+        if policy.mode == JoinMode::AcceptAll || policy.allowlist.contains(&inviter) {
+            return Ok(WelcomeOutcome::Joined(
+                self.join_staged(staged_welcome, pins)?,
+            ));
+        }
+        if policy.mode == JoinMode::Allowlist {
+            log::warn!("Dropping welcome to gid {gid} from {inviter}, who is not allowlisted");
+            return Ok(WelcomeOutcome::Dropped(gid));
+        }
+        let members = staged_welcome
+            .members()
+            .map(|member| pid_of(&member.credential))
+            .collect::<Result<Vec<_>, MySgmError>>()?;
+        let id = self.provider.state_mut().stage_invite(Invite {
+            id: 0,
+            gid: gid.clone(),
+            inviter,
+            members,
+            welcome: wm_bytes,
+        });
+        log::info!("Staged welcome to gid {gid} as invitation {id}");
+        Ok(WelcomeOutcome::Staged(gid))
+    }
+    /// Processes a serialized welcome, checking the keys of the group's members against
+    /// their pins; returns the pins to record once the group is joined.
+    fn stage_welcome(
+        &self,
+        wm_bytes: &[u8],
+    ) -> Result<(StagedWelcome, Vec<(String, String)>), MySgmError> {
+        let MlsMessageBodyIn::Welcome(welcome) =
+            MlsMessageIn::tls_deserialize_exact(wm_bytes)?.extract()
+        else {
+            return Err(MySgmError::UnexpectedMessage(
+                "Not a welcome message".to_string(),
+            ));
+        };
+        log::info!("Processed welcome message: {welcome:?}");
+        let staged_welcome = StagedWelcome::new_from_welcome(
+            &self.provider,
+            self.group_config.join_config(),
+            welcome,
+            None,
+        )
+        .map_err(MySgmError::mls)?;
+        let leaves = staged_welcome
+            .members()
+            .map(|member| {
+                self.check_credential(&member.credential, &member.signature_key)?;
+                Ok((pid_of(&member.credential)?, member.signature_key))
+            })
+            .collect::<Result<Vec<_>, MySgmError>>()?;
+        let pins = self.check_pins(&leaves)?;
+        Ok((staged_welcome, pins))
+    }
+    fn join_staged(
+        &mut self,
+        staged_welcome: StagedWelcome,
+        pins: Vec<(String, String)>,
+    ) -> Result<String, MySgmError> {
+        let gid = staged_gid(&staged_welcome)?;
+        if self.state().gids().contains(&gid) {
+            return Err(MySgmError::GroupExists(gid));
+        }
+        staged_welcome
+            .into_group(&self.provider)
+            .map_err(MySgmError::mls)?;
+        self.pin_all(pins);
+        self.provider.state_mut().add_gid(gid.clone());
+        Ok(gid)
+    }
+    fn download_commits(&mut self, gid: &str) -> Result<(), MySgmError> {
+        let mut group = self.load_group(gid)?;
+        loop {
+            // an evicted member can derive no more keys, nor process commits
+            if !group.is_active() {
+                log::warn!("Evicted from group, stopping commit download for gid: {gid}");
+                self.forget_group(group, gid)?;
+                break;
+            }
+            let key = match commit_key(&group, &self.provider) {
+                Ok(k) => k,
+                Err(e) => {
+                    log::warn!("Failed to derive commit key: {e}");
+                    break;
+                }
+            };
+            // messages of this epoch become undecryptable once the next commit is merged
+            self.download_application_messages(&mut group, gid)?;
+            // the next commit may cover proposals by reference
+            self.download_proposals(&mut group, gid)?;
+            log::info!("Commit message key to get: {key}");
+            let started = now_ms();
+            let cm_values = self.adapter.get_all(&key).map_err(MySgmError::adapter)?;
+            if cm_values.is_empty() {
+                log::info!("No more commit messages to download for gid: {gid}");
+                break;
+            }
+            let mut download_event = MetricsEvent::new("commit_download", started, now_ms());
+            download_event.node_id = Some(self.state().my_pid().to_string());
+            download_event.gid = Some(gid.to_string());
+            download_event.commit_key = Some(key.clone());
+            download_event.payload_bytes = Some(cm_values.iter().map(Vec::len).sum());
+            log_event(&download_event);
+            for cm_bytes in &cm_values {
+                log::info!("Got commit message bytes: {}", hex_encode(cm_bytes));
+            }
+            let merged = self.merge_winning_commit(&mut group, gid, &key, cm_values, None);
+            let mut merge_event = MetricsEvent::new("commit_merge", started, now_ms());
+            merge_event.node_id = Some(self.state().my_pid().to_string());
+            merge_event.gid = Some(gid.to_string());
+            merge_event.commit_key = Some(key.clone());
+            match merged {
+                Ok(_) => {
+                    log::info!("Merged commit into group state for gid: {gid}");
+                    merge_event.commit_merged = Some(true);
+                    let stored = self
+                        .watches
+                        .as_mut()
+                        .and_then(|watches| watches.stored.remove(&key));
+                    if let Some(stored) = stored {
+                        merge_event.notify_latency_ms = Some(now_ms().saturating_sub(stored));
+                    }
+                    log_event(&merge_event);
+                    if !group.is_active() {
+                        log::warn!("Removed from group, stopping commit download for gid: {gid}");
+                        self.forget_group(group, gid)?;
+                        break;
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Failed to merge commit: {e}");
+                    merge_event.commit_merged = Some(false);
+                    merge_event.result = "error".to_string();
+                    merge_event.error = Some(e.to_string());
+                    log_event(&merge_event);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Merges the commit that wins among `values`, all published under the commit key of
+    /// the group's current epoch. Candidates are ranked by hash and the first one that is
+    /// valid under the protocol wins, so that every member settles on the same one; `own`
+    /// is our pending commit, if we managed to store it. Pins and trust anchors, which
+    /// differ between members, are only checked on the winner: if it does not pass, it is
+    /// not merged and the group stays in its epoch rather than forking.
+    fn merge_winning_commit(
+        &mut self,
+        group: &mut MlsGroup,
+        gid: &str,
+        key: &str,
+        mut values: Vec<Vec<u8>>,
+        own: Option<&[u8]>,
+    ) -> Result<CommitWinner, MySgmError> {
+        let started = now_ms();
+        // the adapter may not return our own write yet
+        if let Some(own) = own.filter(|own| !values.iter().any(|value| value == own)) {
+            values.push(own.to_vec());
+        }
+        let ranked = self.rank_commits(group, values)?;
+        let candidates = ranked.len();
+        let winner = select_winner(ranked, own, key, |value| {
+            self.process_commit_bytes(group, value)
+        });
+        if candidates > 1 || (own.is_some() && matches!(winner, Ok(Candidate::Other(_)))) {
+            log::warn!("{candidates} commits competed for {key} in gid: {gid}");
+            let mut event = MetricsEvent::new("commit_conflict", started, now_ms());
+            event.node_id = Some(self.state().my_pid().to_string());
+            event.gid = Some(gid.to_string());
+            event.commit_key = Some(key.to_string());
+            event.commit_candidates = Some(candidates);
+            event.commit_won = own.map(|_| matches!(winner, Ok(Candidate::Own)));
+            log_event(&event);
+        }
+        let (staged_commit, committer) = match winner? {
+            Candidate::Own => {
+                group
+                    .merge_pending_commit(&self.provider)
+                    .map_err(MySgmError::mls)?;
+                return Ok(CommitWinner::Own);
+            }
+            Candidate::Other(staged) => staged,
+        };
+        // a commit of ours that lost must not be merged later on
+        if group.pending_commit().is_some() {
+            group
+                .clear_pending_commit(self.provider.storage())
+                .map_err(MySgmError::mls)?;
+        }
+        let pins = self
+            .commit_pins(group, &staged_commit, &committer)
+            .inspect_err(|e| log::warn!("Not merging the winning commit at {key}: {e}"))?;
+        group
+            .merge_staged_commit(&self.provider, staged_commit)
+            .map_err(MySgmError::mls)?;
+        self.pin_all(pins);
+        Ok(CommitWinner::Other)
+    }
+    /// Distinct commits ordered by hash, the order in which members try to merge them.
+    fn rank_commits(
+        &self,
+        group: &MlsGroup,
+        values: Vec<Vec<u8>>,
+    ) -> Result<Vec<Vec<u8>>, MySgmError> {
+        let hash_algorithm = group.ciphersuite().hash_algorithm();
+        rank_by_hash(values, |value| {
+            self.provider
+                .crypto()
+                .hash(hash_algorithm, value)
+                .map_err(MySgmError::mls)
+        })
+    }
+    fn process_commit_bytes(
+        &self,
+        group: &mut MlsGroup,
+        cm_bytes: Vec<u8>,
+    ) -> Result<(StagedCommit, Sender), MySgmError> {
+        let proto_msg = MlsMessageIn::tls_deserialize_exact(cm_bytes)?
+            .try_into_protocol_message()
+            .map_err(MySgmError::mls)?;
+        let processed_message = group
+            .process_message(&self.provider, proto_msg)
+            .map_err(MySgmError::mls)?;
+        let sender = processed_message.sender().clone();
+        match processed_message.into_content() {
+            ProcessedMessageContent::StagedCommitMessage(commit_box) => Ok((*commit_box, sender)),
+            _ => Err(MySgmError::UnexpectedMessage(
+                "Not a commit message".to_string(),
+            )),
+        }
+    }
+    /// Fetches and decrypts every application message published in the group's current
+    /// epoch, queueing the plaintexts in the state's inbox.
+    fn download_application_messages(
+        &mut self,
+        group: &mut MlsGroup,
+        gid: &str,
+    ) -> Result<(), MySgmError> {
+        let epoch = group.epoch().as_u64();
+        loop {
+            let index = self.state().message_cursor(gid, epoch);
+            let key = match application_message_key(group, &self.provider, index) {
+                Ok(k) => k,
+                Err(e) => {
+                    log::warn!("Failed to derive application message key: {e}");
+                    return Ok(());
+                }
+            };
+            if self.state().is_sent_message(gid, epoch, index) {
+                log::debug!("Skipping our own application message at {key}");
+                self.provider
+                    .state_mut()
+                    .set_message_cursor(gid, epoch, index + 1);
+                continue;
+            }
+            log::info!("Application message key to get: {key}");
+            let started = now_ms();
+            let Some(am_bytes) = self.adapter.get(&key).map_err(MySgmError::adapter)? else {
+                log::info!("No more application messages to download for gid: {gid}");
+                return Ok(());
+            };
+            self.provider
+                .state_mut()
+                .set_message_cursor(gid, epoch, index + 1);
+            log::info!("Got application message bytes: {}", hex_encode(&am_bytes));
+            let mut event = MetricsEvent::new("message_receive", started, now_ms());
+            event.node_id = Some(self.state().my_pid().to_string());
+            event.gid = Some(gid.to_string());
+            event.dht_key = Some(key.clone());
+            event.payload_bytes = Some(am_bytes.len());
+            match self.decrypt_application_message(group, am_bytes) {
+                Ok((sender, payload)) => {
+                    log::info!("Application message from {sender} in epoch {epoch}");
+                    self.provider.state_mut().push_message(ReceivedMessage {
+                        gid: gid.to_string(),
+                        sender,
+                        epoch,
+                        payload,
+                    });
+                }
+                Err(e) => {
+                    log::warn!("Failed to process application message: {e}");
+                    event.result = "error".to_string();
+                    event.error = Some(e.to_string());
+                }
+            }
+            log_event(&event);
+        }
+    }
+    fn decrypt_application_message(
+        &self,
+        group: &mut MlsGroup,
+        am_bytes: Vec<u8>,
+    ) -> Result<(String, Vec<u8>), MySgmError> {
+        let proto_msg = MlsMessageIn::tls_deserialize_exact(am_bytes)?
+            .try_into_protocol_message()
+            .map_err(MySgmError::mls)?;
+        let processed_message = group
+            .process_message(&self.provider, proto_msg)
+            .map_err(MySgmError::mls)?;
+        let sender = pid_of(processed_message.credential())?;
+        match processed_message.into_content() {
+            ProcessedMessageContent::ApplicationMessage(app_msg) => {
+                Ok((sender, app_msg.into_bytes()))
+            }
+            _ => Err(MySgmError::UnexpectedMessage(
+                "Not an application message".to_string(),
+            )),
+        }
+    }
+    /// Fetches every proposal published in the group's current epoch and queues it for
+    /// the next commit.
+    fn download_proposals(&mut self, group: &mut MlsGroup, gid: &str) -> Result<(), MySgmError> {
+        let epoch = group.epoch().as_u64();
+        loop {
+            let index = self.state().proposal_cursor(gid, epoch);
+            let key = match proposal_key(group, &self.provider, index) {
+                Ok(k) => k,
+                Err(e) => {
+                    log::warn!("Failed to derive proposal key: {e}");
+                    return Ok(());
+                }
+            };
+            log::info!("Proposal key to get: {key}");
+            let started = now_ms();
+            let Some(pr_bytes) = self.adapter.get(&key).map_err(MySgmError::adapter)? else {
+                log::info!("No more proposals to download for gid: {gid}");
+                return Ok(());
+            };
+            self.provider
+                .state_mut()
+                .set_proposal_cursor(gid, epoch, index + 1);
+            log::info!("Got proposal bytes: {}", hex_encode(&pr_bytes));
+            let mut event = MetricsEvent::new("proposal_receive", started, now_ms());
+            event.node_id = Some(self.state().my_pid().to_string());
+            event.gid = Some(gid.to_string());
+            event.dht_key = Some(key.clone());
+            event.payload_bytes = Some(pr_bytes.len());
+            if let Err(e) = self.queue_proposal_bytes(group, pr_bytes) {
+                // our own proposals cannot be processed by us, but are queued already
+                log::warn!("Failed to process proposal: {e}");
+                event.result = "error".to_string();
+                event.error = Some(e.to_string());
+            }
+            log_event(&event);
+        }
+    }
+    fn queue_proposal_bytes(
+        &self,
+        group: &mut MlsGroup,
+        pr_bytes: Vec<u8>,
+    ) -> Result<(), MySgmError> {
+        let proto_msg = MlsMessageIn::tls_deserialize_exact(pr_bytes)?
+            .try_into_protocol_message()
+            .map_err(MySgmError::mls)?;
+        let processed_message = group
+            .process_message(&self.provider, proto_msg)
+            .map_err(MySgmError::mls)?;
+        match processed_message.into_content() {
+            ProcessedMessageContent::ProposalMessage(queued_proposal) => group
+                .store_pending_proposal(self.provider.storage(), *queued_proposal)
+                .map_err(MySgmError::mls),
+            _ => Err(MySgmError::UnexpectedMessage("Not a proposal".to_string())),
+        }
+    }
+    /// Commits the pending proposals if another member asked to leave, since members
+    /// cannot commit their own removal.
+    fn commit_leave_requests(&mut self, gid: &str) -> Result<(), MySgmError> {
+        let group = self.load_group(gid)?;
+        let own_index = group.own_leaf_index();
+        let leaving = group
+            .pending_proposals()
+            .any(|queued| match queued.proposal() {
+                Proposal::Remove(remove) => {
+                    remove.removed() != own_index
+                        && *queued.sender() == Sender::Member(remove.removed())
+                }
+                _ => false,
+            });
+        if !leaving {
+            return Ok(());
+        }
+        log::info!("Committing leave request for gid: {gid}");
+        match self.commit_pending_proposals(gid) {
+            Err(MySgmError::CommitConflict(_)) => {
+                log::info!("Leave request was committed by another member in gid: {gid}");
+                Ok(())
+            }
+            result => result,
+        }
+    }
+    /// Asks to leave again if the epoch changed without removing us, which drops the
+    /// proposal of the previous epoch.
+    fn renew_leave_request(&mut self, gid: &str) -> Result<(), MySgmError> {
+        let Some(epoch) = self.state().pending_leave(gid) else {
+            return Ok(());
+        };
+        let mut group = self.load_group(gid)?;
+        if group.epoch().as_u64() != epoch {
+            log::info!("Renewing leave request for gid: {gid}");
+            self.publish_leave_request(&mut group, gid)?;
+        }
+        Ok(())
+    }
+}
+
+// commands
+impl Agent {
+    /// Creates a group named after `gid` and returns its full gid.
+    pub fn create_group(&mut self, gid: &str, policy: GroupPolicy) -> Result<String, MySgmError> {
+        let started = now_ms();
+        check_key_part("Group name", gid)?;
+        let gid_transformed = format!(
+            "{}_{}",
+            gid,
+            hex_encode(self.state().signature_key_pair().public_key_raw())
+                .chars()
+                .take(3)
+                .collect::<String>()
+        );
+        if self.state().gids().contains(&gid_transformed) {
+            return Err(MySgmError::GroupExists(gid_transformed));
+        }
+        let group = MlsGroup::new_with_group_id(
+            &self.provider,
+            &self.provider,
+            &self.group_config,
+            GroupId::from_slice(gid_transformed.as_bytes()),
+            self.cred_with_key.clone(),
+        )
+        .map_err(MySgmError::mls)?;
+        self.provider.state_mut().add_gid(gid_transformed.clone());
+        self.provider
+            .state_mut()
+            .set_group_policy(&gid_transformed, policy);
+        if policy.external_join {
+            self.publish_group_info(&group, &gid_transformed)?;
+        }
+        let mut event = MetricsEvent::new("group_create", started, now_ms());
+        event.node_id = Some(self.state().my_pid().to_string());
+        event.gid = Some(gid_transformed.clone());
+        event.members_before = Some(0);
+        event.members_after = Some(1);
+        log_event(&event);
+        Ok(gid_transformed)
+    }
+    /// Publishes `count` single-use key packages so that other agents can add us to
+    /// groups, and a last-resort key package they fall back to once those ran out. Syncs
+    /// keep `count` single-use key packages published from then on.
+    pub fn advertise(&mut self, count: usize) -> Result<(), MySgmError> {
+        self.provider.state_mut().set_key_package_pool_size(count);
+        self.publish_key_package(true)?;
+        for _ in 0..count {
+            self.publish_key_package(false)?;
+        }
+        Ok(())
+    }
+    /// Publishes a key package, adding it to the pool unless it is the last-resort one.
+    /// The last-resort key package it replaces is retired, keeping its private keys until
+    /// it expires.
+    fn publish_key_package(&mut self, last_resort: bool) -> Result<(), MySgmError> {
+        let started = now_ms();
+        let mut builder = KeyPackage::builder()
+            .leaf_node_capabilities(self.capabilities.clone())
+            .key_package_lifetime(Lifetime::new(self.key_package_lifetime));
+        if last_resort {
+            builder = builder.mark_as_last_resort();
+        }
+        let key_package_bundle = builder
+            .build(
+                self.state().my_ciphersuite(),
+                &self.provider,
+                &self.provider,
+                self.cred_with_key.clone(),
+            )
+            .map_err(MySgmError::mls)?;
+        let key_package = key_package_bundle.key_package().clone();
+        let my_pid = self.state().my_pid().to_string();
+        let state = self.provider.state_mut();
+        if last_resort {
+            if let Some(retired) = state.remove_key_package(&my_pid) {
+                state.retire_key_package(retired);
+            }
+            state.set_key_package(&my_pid, key_package.clone());
+        } else {
+            state.add_to_key_package_pool(key_package.clone());
+        }
+        let kp_msg = MlsMessageOut::from(key_package).tls_serialize_detached()?;
+        log::info!("Key package to put: {}", hex_encode(&kp_msg));
+        let (_, key) =
+            self.put_first_free(self.state().key_package_counter(), &kp_msg, |index| {
+                Ok(key_package_key(index))
+            })?;
+        log::info!("Key package key: {key}");
+        let mut event = MetricsEvent::new("advertise", started, now_ms());
+        event.node_id = Some(self.state().my_pid().to_string());
+        event.payload_bytes = Some(kp_msg.len());
+        log_event(&event);
+        Ok(())
+    }
+    pub fn export_secret(
+        &self,
+        gid: &str,
+        label: &str,
+        length: usize,
+    ) -> Result<Vec<u8>, MySgmError> {
+        self.load_group(gid)?
+            .export_secret(self.provider.crypto(), label, &[], length)
+            .map_err(MySgmError::mls)
+    }
+    /// pids whose last-resort key package has expired and who have no single-use key
+    /// package left, with the time it expired at in seconds since the Unix epoch.
+    pub fn expired_key_packages(&self) -> Vec<(String, u64)> {
+        let state = self.state();
+        state
+            .pids()
+            .into_iter()
+            .filter(|pid| state.queued_key_packages(pid).is_empty())
+            .filter_map(|pid| {
+                let life_time = state.key_package(&pid)?.life_time();
+                (!life_time.is_valid()).then(|| (pid, life_time.not_after()))
+            })
+            .collect()
+    }
+    /// Leaf index and pid of every member of the group.
+    pub fn members(&self, gid: &str) -> Result<Vec<(LeafNodeIndex, String)>, MySgmError> {
+        self.load_group(gid)?
+            .members()
+            .map(|member| Ok((member.index, pid_of(&member.credential)?)))
+            .collect()
+    }
+    /// Adds the agents to the group; agents that are members already are skipped.
+    pub fn add_members(&mut self, gid: &str, pids: &[String]) -> Result<(), MySgmError> {
+        let started = now_ms();
+        let members_before = self.load_group(gid)?.members().count();
+        let mut added = Vec::new();
+        let merged = self.commit(gid, |agent, group| {
+            let members = member_pids(group)?;
+            let pids: Vec<&String> = pids.iter().filter(|pid| !members.contains(*pid)).collect();
+            if pids.is_empty() {
+                log::info!("All agents are members of gid: {gid}");
+                return Ok(None);
+            }
+            let mut kps = Vec::new();
+            for pid in &pids {
+                log::info!("pid: {pid}");
+                let kp = agent.key_package_for(group, pid)?;
+                log::info!("Key package for pid: {kp:?}");
+                kps.push(kp.clone());
+            }
+            added = pids
+                .iter()
+                .map(|pid| pid.to_string())
+                .zip(kps.clone())
+                .collect();
+            let mut invitees = pending_add_pids(group);
+            invitees.extend(pids.into_iter().cloned());
+            let (commit, welcome, _) = group
+                .add_members_without_update(&agent.provider, &agent.provider, kps.as_slice())
+                .map_err(MySgmError::mls)?;
+            Ok(Some(OutgoingCommit {
+                commit,
+                welcome: Some(welcome),
+                invitees,
+            }))
+        })?;
+        let Some(merged) = merged else {
+            return Ok(());
+        };
+        for (pid, kp) in &added {
+            self.provider.state_mut().consume_key_package(pid, kp);
+        }
+        let mut event = MetricsEvent::new("group_add", started, now_ms());
+        event.node_id = Some(self.state().my_pid().to_string());
+        event.gid = Some(gid.to_string());
+        event.commit_bytes = Some(merged.commit_bytes);
+        event.welcome_bytes = merged.welcome_bytes;
+        event.members_before = Some(members_before);
+        event.members_after = Some(merged.members_after);
+        log_event(&event);
+        Ok(())
+    }
+    /// Removes the members at the given leaf indexes from the group.
+    pub fn remove_members(&mut self, gid: &str, indexes: &[u32]) -> Result<(), MySgmError> {
+        let pids = self.pids_at(gid, indexes)?;
+        self.remove_members_by_pid(gid, &pids)
+    }
+    /// Removes the agents from the group.
+    pub fn remove_members_by_pid(&mut self, gid: &str, pids: &[String]) -> Result<(), MySgmError> {
+        let started = now_ms();
+        let members = member_pids(&self.load_group(gid)?)?;
+        let members_before = members.len();
+        if let Some(pid) = pids.iter().find(|pid| !members.contains(*pid)) {
+            return Err(MySgmError::InvalidArgument(format!(
+                "{pid} is not a member of {gid}"
+            )));
+        }
+        let merged = self.commit(gid, |agent, group| {
+            // leaves are looked up again, since a re-issued commit is staged in a new epoch
+            let indexes: Vec<LeafNodeIndex> = group
+                .members()
+                .filter(|member| pid_of(&member.credential).is_ok_and(|pid| pids.contains(&pid)))
+                .map(|member| member.index)
+                .collect();
+            if indexes.is_empty() {
+                log::info!("No agent to remove is a member of gid: {gid}");
+                return Ok(None);
+            }
+            let invitees = pending_add_pids(group);
+            let (commit, welcome, _) = group
+                .remove_members(&agent.provider, &agent.provider, indexes.as_slice())
+                .map_err(MySgmError::mls)?;
+            Ok(Some(OutgoingCommit {
+                commit,
+                welcome,
+                invitees,
+            }))
+        })?;
+        let Some(merged) = merged else {
+            return Ok(());
+        };
+        let mut event = MetricsEvent::new("group_remove", started, now_ms());
+        event.node_id = Some(self.state().my_pid().to_string());
+        event.gid = Some(gid.to_string());
+        event.commit_bytes = Some(merged.commit_bytes);
+        event.welcome_bytes = merged.welcome_bytes;
+        event.members_before = Some(members_before);
+        event.members_after = Some(merged.members_after);
+        log_event(&event);
+        Ok(())
+    }
+    pub fn self_update(&mut self, gid: &str) -> Result<(), MySgmError> {
+        let started = now_ms();
+        let members_before = self.load_group(gid)?.members().count();
+        let merged = self.commit(gid, |agent, group| {
+            let invitees = pending_add_pids(group);
+            let (commit, welcome, _) = group
+                .self_update(
+                    &agent.provider,
+                    &agent.provider,
+                    LeafNodeParameters::builder()
+                        .with_capabilities(agent.capabilities.clone())
+                        .build(),
+                )
+                .map_err(MySgmError::mls)?
+                .into_messages();
+            Ok(Some(OutgoingCommit {
+                commit,
+                welcome,
+                invitees,
+            }))
+        })?;
+        let Some(merged) = merged else {
+            return Ok(());
+        };
+        let mut event = MetricsEvent::new("group_update", started, now_ms());
+        event.node_id = Some(self.state().my_pid().to_string());
+        event.gid = Some(gid.to_string());
+        event.commit_bytes = Some(merged.commit_bytes);
+        event.welcome_bytes = merged.welcome_bytes;
+        event.members_before = Some(members_before);
+        event.members_after = Some(merged.members_after);
+        event.update_count = Some(1);
+        log_event(&event);
+        Ok(())
+    }
+    /// Asks the other members to remove us from the group. Until a commit removing us is
+    /// seen, the request is renewed in every new epoch; the group is then forgotten.
+    pub fn leave_group(&mut self, gid: &str) -> Result<(), MySgmError> {
+        let mut group = self.load_group(gid)?;
+        self.publish_leave_request(&mut group, gid)
+    }
+    /// Proposes adding the agents to the group, leaving the commit to any member.
+    pub fn propose_add(&mut self, gid: &str, pids: &[String]) -> Result<(), MySgmError> {
+        let mut group = self.load_group(gid)?;
+        for pid in pids {
+            let started = now_ms();
+            let kp = self.key_package_for(&group, pid)?.clone();
+            let (proposal, _) = group
+                .propose_add_member(&self.provider, &self.provider, &kp)
+                .map_err(MySgmError::mls)?;
+            self.publish_proposal(&group, gid, proposal, "proposal_send", started)?;
+            self.provider.state_mut().consume_key_package(pid, &kp);
+        }
+        Ok(())
+    }
+    /// Proposes removing the members at the given leaf indexes from the group.
+    pub fn propose_remove(&mut self, gid: &str, indexes: &[u32]) -> Result<(), MySgmError> {
+        let pids = self.pids_at(gid, indexes)?;
+        self.propose_remove_by_pid(gid, &pids)
+    }
+    /// Proposes removing the agents from the group.
+    pub fn propose_remove_by_pid(&mut self, gid: &str, pids: &[String]) -> Result<(), MySgmError> {
+        let members = self.members(gid)?;
+        let mut group = self.load_group(gid)?;
+        for pid in pids {
+            let started = now_ms();
+            let (index, _) = members.iter().find(|(_, p)| p == pid).ok_or_else(|| {
+                MySgmError::InvalidArgument(format!("{pid} is not a member of {gid}"))
+            })?;
+            let (proposal, _) = group
+                .propose_remove_member(&self.provider, &self.provider, *index)
+                .map_err(MySgmError::mls)?;
+            self.publish_proposal(&group, gid, proposal, "proposal_send", started)?;
+        }
+        Ok(())
+    }
+    /// Proposes refreshing our own leaf in the group.
+    pub fn propose_self_update(&mut self, gid: &str) -> Result<(), MySgmError> {
+        let started = now_ms();
+        let mut group = self.load_group(gid)?;
+        let (proposal, _) = group
+            .propose_self_update(
+                &self.provider,
+                &self.provider,
+                LeafNodeParameters::builder()
+                    .with_capabilities(self.capabilities.clone())
+                    .build(),
+            )
+            .map_err(MySgmError::mls)?;
+        self.publish_proposal(&group, gid, proposal, "proposal_send", started)
+    }
+    /// Commits every proposal queued in the group, our own and those downloaded by
+    /// [`Agent::sync`]; does nothing if there are none.
+    pub fn commit_pending_proposals(&mut self, gid: &str) -> Result<(), MySgmError> {
+        let started = now_ms();
+        let members_before = self.load_group(gid)?.members().count();
+        let merged = self.commit(gid, |agent, group| {
+            if group.pending_proposals().next().is_none() {
+                log::info!("No pending proposals in gid: {gid}");
+                return Ok(None);
+            }
+            let invitees = pending_add_pids(group);
+            let (commit, welcome, _) = group
+                .commit_to_pending_proposals(&agent.provider, &agent.provider)
+                .map_err(MySgmError::mls)?;
+            Ok(Some(OutgoingCommit {
+                commit,
+                welcome,
+                invitees,
+            }))
+        })?;
+        let Some(merged) = merged else {
+            return Ok(());
+        };
+        let mut event = MetricsEvent::new("group_commit", started, now_ms());
+        event.node_id = Some(self.state().my_pid().to_string());
+        event.gid = Some(gid.to_string());
+        event.commit_bytes = Some(merged.commit_bytes);
+        event.welcome_bytes = merged.welcome_bytes;
+        event.members_before = Some(members_before);
+        event.members_after = Some(merged.members_after);
+        log_event(&event);
+        Ok(())
+    }
+    /// Replaces our signature key. The rotation is announced under `kr_{pid}_{index}`,
+    /// signed by the old key, and every group gets a commit, also signed by the old key,
+    /// updating our leaf to the new one. A key package with the new key is published.
+    /// Returns the groups that could not be updated: the old key is kept until there are
+    /// none, and rotating again retries them with the same new key.
+    pub fn rotate_signature_key(&mut self) -> Result<Vec<(String, MySgmError)>, MySgmError> {
+        if self.state().credential().is_some() {
+            return Err(MySgmError::InvalidArgument(
+                "An X.509 credential is rotated by issuing a new certificate".to_string(),
+            ));
+        }
+        if self.state().previous_signature_key_pair().is_none() {
+            let old = self.state().signature_key_pair();
+            let new = SignatureKeyPair::from_crypto(self.provider.crypto(), old.signature_scheme())
+                .map_err(MySgmError::mls)?;
+            let pid = self.state().my_pid().to_string();
+            let rotation = KeyRotation::sign(&pid, old, new.public_key_raw())?;
+            let (_, key) = self.put_first_free(0, &json_encode_bytes(&rotation)?, |index| {
+                Ok(key_rotation_key(&pid, index))
+            })?;
+            log::info!("Announced key rotation at {key}");
+            let state = self.provider.state_mut();
+            state.rotate_signature_key_pair(new);
+            state.retire_key_package_pool();
+            self.reconfigure();
+        } else {
+            log::info!("Resuming key rotation");
+        }
+        let mut failed = Vec::new();
+        for gid in self.state().gids() {
+            if let Err(e) = self.update_signature_key(&gid) {
+                log::warn!("Failed to rotate key in gid {gid}: {e}");
+                failed.push((gid, e));
+            }
+        }
+        self.publish_key_package(true)?;
+        self.replenish_key_packages()?;
+        let retired = if failed.is_empty() {
+            self.provider
+                .state_mut()
+                .retire_previous_signature_key_pair()
+        } else {
+            None
+        };
+        if let Some(old) = retired {
+            self.provider
+                .storage()
+                .delete_signature_key_pair(&old.public_key())
+                .map_err(MySgmError::mls)?;
+        }
+        Ok(failed)
+    }
+    /// Updates our leaf in the group to the current key, signing with the previous one;
+    /// does nothing if the leaf has the current key already.
+    fn update_signature_key(&mut self, gid: &str) -> Result<(), MySgmError> {
+        let started = now_ms();
+        let merged = self.commit(gid, |agent, group| {
+            let new = agent.state().signature_key_pair();
+            if group
+                .own_leaf_node()
+                .is_some_and(|leaf| leaf.signature_key().as_slice() == new.public_key_raw())
+            {
+                return Ok(None);
+            }
+            let old = agent.state().previous_signature_key_pair().ok_or_else(|| {
+                MySgmError::State("No previous signature key to rotate from".to_string())
+            })?;
+            let invitees = pending_add_pids(group);
+            let (commit, welcome, _) = group
+                .self_update_with_new_signer(
+                    &agent.provider,
+                    old,
+                    NewSignerBundle {
+                        signer: new,
+                        credential_with_key: agent.cred_with_key.clone(),
+                    },
+                    LeafNodeParameters::builder()
+                        .with_capabilities(agent.capabilities.clone())
+                        .build(),
+                )
+                .map_err(MySgmError::mls)?
+                .into_messages();
+            Ok(Some(OutgoingCommit {
+                commit,
+                welcome,
+                invitees,
+            }))
+        })?;
+        let Some(merged) = merged else {
+            return Ok(());
+        };
+        let mut event = MetricsEvent::new("key_rotate", started, now_ms());
+        event.node_id = Some(self.state().my_pid().to_string());
+        event.gid = Some(gid.to_string());
+        event.commit_bytes = Some(merged.commit_bytes);
+        event.welcome_bytes = merged.welcome_bytes;
+        event.members_after = Some(merged.members_after);
+        log_event(&event);
+        Ok(())
+    }
+    pub fn group_policy(&self, gid: &str) -> Result<GroupPolicy, MySgmError> {
+        self.load_group(gid)?;
+        Ok(self.state().group_policy(gid))
+    }
+    /// Changes the policy of the group; enabling external joins publishes the GroupInfo
+    /// of the current epoch right away.
+    pub fn set_group_policy(&mut self, gid: &str, policy: GroupPolicy) -> Result<(), MySgmError> {
+        let group = self.load_group(gid)?;
+        self.provider.state_mut().set_group_policy(gid, policy);
+        if policy.external_join {
+            self.publish_group_info(&group, gid)?;
+        }
+        Ok(())
+    }
+    /// Changes which welcomes are acted on as they are downloaded; staged invitations are
+    /// kept whatever the new policy.
+    pub fn set_join_policy(&mut self, policy: JoinPolicy) {
+        self.provider.state_mut().set_join_policy(policy);
+    }
+    /// Joins the group of a staged invitation. The invitation is kept if joining fails.
+    pub fn accept_invite(&mut self, id: u64) -> Result<String, MySgmError> {
+        let invite = self
+            .state()
+            .invite(id)
+            .ok_or_else(|| MySgmError::InvalidArgument(format!("No invitation {id}")))?;
+        let (staged_welcome, pins) = self.stage_welcome(&invite.welcome)?;
+        let gid = self.join_staged(staged_welcome, pins)?;
+        self.provider.state_mut().remove_invite(id);
+        Ok(gid)
+    }
+    /// Forgets a staged invitation without joining its group.
+    pub fn reject_invite(&mut self, id: u64) -> Result<(), MySgmError> {
+        self.provider
+            .state_mut()
+            .remove_invite(id)
+            .map(|_| ())
+            .ok_or_else(|| MySgmError::InvalidArgument(format!("No invitation {id}")))
+    }
+    /// Joins the group by external commit, based on the latest GroupInfo its members
+    /// published. Our commit is merged if it ranks first among the commits for the epoch
+    /// that we can tell are valid. If an external commit of another joiner wins, the join
+    /// is retried from the GroupInfo of the new epoch when re-issuing is enabled. Commits
+    /// of members are encrypted, so if one of them outranks ours, the join is left pending
+    /// until a later GroupInfo tells whether the group merged our commit.
+    pub fn join_external(&mut self, gid: &str) -> Result<(), MySgmError> {
+        let started = now_ms();
+        check_key_part("Gid", gid)?;
+        if self.state().gids().iter().any(|g| g == gid) {
+            return Err(MySgmError::GroupExists(gid.to_string()));
+        }
+        if self.state().external_join(gid).is_some() {
+            return Err(MySgmError::InvalidArgument(format!(
+                "An external join of {gid} is pending"
+            )));
+        }
+        let mut reissues = 0;
+        let mut tried_epoch = None;
+        loop {
+            let Some((index, published, group_info)) = self.latest_group_info(gid, 0)? else {
+                return Err(MySgmError::UnknownGroup(gid.to_string()));
+            };
+            if tried_epoch.is_some_and(|epoch| published.epoch <= epoch) {
+                log::warn!("No GroupInfo after the commit that won for gid: {gid}");
+                return Err(MySgmError::CommitConflict(gid.to_string()));
+            }
+            tried_epoch = Some(published.epoch);
+            log::info!("Joining gid: {gid} in epoch {}", published.epoch);
+            let public_group = self.public_group(group_info.clone())?;
+            // the GroupInfo can be published by anyone, so its members are checked like
+            // those of a welcome
+            let pins = self.check_pins(&self.checked_members(&public_group)?)?;
+            // unlike `external_commit_builder`, this leaves the commit pending, so that it
+            // can be ranked against concurrent commits before it is merged
+            #[allow(deprecated)]
+            let (mut group, commit, _) = MlsGroup::join_by_external_commit(
+                &self.provider,
+                &self.provider,
+                None,
+                group_info,
+                self.group_config.join_config(),
+                Some(self.capabilities.clone()),
+                None,
+                &[],
+                self.cred_with_key.clone(),
+            )
+            .map_err(MySgmError::mls)?;
+            let commit_bytes = commit.tls_serialize_detached()?;
+            let key = &published.commit_key;
+            let lost = match self.adapter.put_checked(key, &commit_bytes) {
+                Ok(()) => {
+                    self.writes.set(self.writes.get() + 1);
+                    // from here on members may merge our commit, so the group is kept
+                    let pending = ExternalJoin {
+                        epoch: published.epoch,
+                        group_info_index: index,
+                    };
+                    self.provider.state_mut().add_external_join(gid, pending);
+                    let mut values = self.adapter.get_all(key).map_err(MySgmError::adapter)?;
+                    values.push(commit_bytes.clone());
+                    let ranked = self.rank_commits(&group, values)?;
+                    let winner =
+                        select_winner(ranked, Some(commit_bytes.as_slice()), key, |value| {
+                            self.check_external_candidate(&public_group, value)
+                        })?;
+                    match winner {
+                        Candidate::Own => false,
+                        Candidate::Other(true) => true,
+                        Candidate::Other(false) => {
+                            log::warn!(
+                                "External commit outranked by a commit we cannot validate, \
+                                 join pending for gid: {gid}"
+                            );
+                            return Ok(());
+                        }
+                    }
+                }
+                Err(e) if e.is::<KeyExists>() => true,
+                Err(e) => {
+                    group
+                        .delete(self.provider.storage())
+                        .map_err(MySgmError::mls)?;
+                    return Err(MySgmError::adapter(e));
+                }
+            };
+            self.provider.state_mut().remove_external_join(gid);
+            if lost {
+                log::warn!("External commit lost to a concurrent commit for gid: {gid}");
+                group
+                    .delete(self.provider.storage())
+                    .map_err(MySgmError::mls)?;
+                if !self.reissue || reissues == MAX_REISSUES {
+                    return Err(MySgmError::CommitConflict(gid.to_string()));
+                }
+                reissues += 1;
+                log::info!("Re-issuing external join of gid: {gid} ({reissues}/{MAX_REISSUES})");
+                continue;
+            }
+            group
+                .merge_pending_commit(&self.provider)
+                .map_err(MySgmError::mls)?;
+            self.joined_externally(gid, pins);
+            self.publish_group_info(&group, gid)?;
+            let mut event = MetricsEvent::new("group_join_external", started, now_ms());
+            event.node_id = Some(self.state().my_pid().to_string());
+            event.gid = Some(gid.to_string());
+            event.commit_key = Some(published.commit_key);
+            event.commit_bytes = Some(commit_bytes.len());
+            event.members_after = Some(group.members().count());
+            log_event(&event);
+            return Ok(());
+        }
+    }
+    /// Records a group joined by external commit, along with the pins of its members.
+    fn joined_externally(&mut self, gid: &str, pins: Vec<(String, String)>) {
+        self.pin_all(pins);
+        self.provider.state_mut().add_gid(gid.to_string());
+        // the group is open to external joins, so keep it open
+        self.provider.state_mut().set_group_policy(
+            gid,
+            GroupPolicy {
+                external_join: true,
+            },
+        );
+    }
+    /// The latest GroupInfo published for `gid` from `index` on, along with its index.
+    fn latest_group_info(
+        &self,
+        gid: &str,
+        mut index: u64,
+    ) -> Result<Option<(u64, PublishedGroupInfo, VerifiableGroupInfo)>, MySgmError> {
+        let mut latest = None;
+        while let Some(bytes) = self
+            .adapter
+            .get(&group_info_key(gid, index))
+            .map_err(MySgmError::adapter)?
+        {
+            latest = Some((index, bytes));
+            index += 1;
+        }
+        let Some((index, bytes)) = latest else {
+            return Ok(None);
+        };
+        let published: PublishedGroupInfo =
+            json_decode_bytes(&bytes).map_err(|e| MySgmError::Codec(e.to_string()))?;
+        let group_info_bytes =
+            hex_decode(&published.group_info).map_err(|e| MySgmError::Codec(e.to_string()))?;
+        let MlsMessageBodyIn::GroupInfo(group_info) =
+            MlsMessageIn::tls_deserialize_exact(group_info_bytes)?.extract()
+        else {
+            return Err(MySgmError::UnexpectedMessage(
+                "Not a group info".to_string(),
+            ));
+        };
+        if group_info.group_id().as_slice() != gid.as_bytes() {
+            return Err(MySgmError::UnexpectedMessage(format!(
+                "Group info at {} is not for {gid}",
+                group_info_key(gid, index)
+            )));
+        }
+        Ok(Some((index, published, group_info)))
+    }
+    /// The public state of the group described by a GroupInfo, once the GroupInfo's
+    /// signature and ratchet tree check out.
+    fn public_group(&self, group_info: VerifiableGroupInfo) -> Result<PublicGroup, MySgmError> {
+        let ratchet_tree = group_info
+            .extensions()
+            .ratchet_tree()
+            .ok_or_else(|| {
+                MySgmError::UnexpectedMessage("Group info without a ratchet tree".to_string())
+            })?
+            .ratchet_tree()
+            .clone();
+        // the public group is only consulted, so it stays out of the agent's storage
+        let (public_group, _) = PublicGroup::from_external(
+            self.provider.crypto(),
+            &OpenMlsKeyValueStore::default(),
+            ratchet_tree,
+            group_info,
+            ProposalStore::default(),
+        )
+        .map_err(MySgmError::mls)?;
+        Ok(public_group)
+    }
+    /// Checks a commit competing with our external commit as far as a joiner can: an
+    /// external commit is staged against the public state of the group, while only the
+    /// header of a member's commit can be read, which `Ok(false)` says.
+    fn check_external_candidate(
+        &self,
+        public_group: &PublicGroup,
+        value: Vec<u8>,
+    ) -> Result<bool, MySgmError> {
+        let message = MlsMessageIn::tls_deserialize_exact(value)?
+            .try_into_protocol_message()
+            .map_err(MySgmError::mls)?;
+        if message.group_id() != public_group.group_id()
+            || message.epoch() != public_group.group_context().epoch()
+            || message.content_type() != ContentType::Commit
+        {
+            return Err(MySgmError::UnexpectedMessage(
+                "Not a commit for this epoch".to_string(),
+            ));
+        }
+        if let ProtocolMessage::PrivateMessage(_) = message {
+            return Ok(false);
+        }
+        let processed = public_group
+            .process_message(self.provider.crypto(), message)
+            .map_err(MySgmError::mls)?;
+        match processed.into_content() {
+            ProcessedMessageContent::StagedCommitMessage(_) => Ok(true),
+            _ => Err(MySgmError::UnexpectedMessage(
+                "Not a commit message".to_string(),
+            )),
+        }
+    }
+    /// Settles the external joins left pending by [`Agent::join_external`] once a member
+    /// published a GroupInfo for a later epoch: our leaf in its tree means that the group
+    /// merged our commit, and its absence that another commit won.
+    fn resolve_external_joins(&mut self) -> Result<(), MySgmError> {
+        for (gid, pending) in self.state().external_joins() {
+            let Some((_, published, group_info)) =
+                self.latest_group_info(&gid, pending.group_info_index + 1)?
+            else {
+                continue;
+            };
+            if published.epoch <= pending.epoch {
+                continue;
+            }
+            let checked = self.public_group(group_info).and_then(|public_group| {
+                let pins = self.check_pins(&self.checked_members(&public_group)?)?;
+                Ok((public_group, pins))
+            });
+            let (public_group, pins) = match checked {
+                Ok(checked) => checked,
+                Err(e) => {
+                    log::warn!("Cannot settle the external join of gid: {gid}: {e}");
+                    continue;
+                }
+            };
+            let own_key = self.state().signature_key_pair().public_key_raw();
+            let merged = public_group
+                .members()
+                .any(|member| member.signature_key.as_slice() == own_key);
+            self.provider.state_mut().remove_external_join(&gid);
+            let mut group = self.load_group(&gid)?;
+            if !merged {
+                log::warn!("External commit lost to a concurrent commit for gid: {gid}");
+                group
+                    .delete(self.provider.storage())
+                    .map_err(MySgmError::mls)?;
+                continue;
+            }
+            log::info!("External commit was merged for gid: {gid}");
+            group
+                .merge_pending_commit(&self.provider)
+                .map_err(MySgmError::mls)?;
+            self.joined_externally(&gid, pins);
+        }
+        Ok(())
+    }
+    /// Encrypts `payload` as an application message for the group and publishes it.
+    pub fn send_message(&mut self, gid: &str, payload: &[u8]) -> Result<(), MySgmError> {
+        let started = now_ms();
+        let mut group = self.load_group(gid)?;
+        let app_msg = group
+            .create_message(&self.provider, &self.provider, payload)
+            .map_err(MySgmError::mls)?;
+        let app_msg_bytes = app_msg.tls_serialize_detached()?;
+        let epoch = group.epoch().as_u64();
+        let (index, key) = self.put_first_free(
+            self.state().message_cursor(gid, epoch),
+            &app_msg_bytes,
+            |index| application_message_key(&group, &self.provider, index).map_err(MySgmError::mls),
+        )?;
+        // we cannot decrypt our own message, so the next sync skips it
+        self.provider
+            .state_mut()
+            .add_sent_message(gid, epoch, index);
+        log::info!("Application message key: {key}");
+        let mut event = MetricsEvent::new("message_send", started, now_ms());
+        event.node_id = Some(self.state().my_pid().to_string());
+        event.gid = Some(gid.to_string());
+        event.dht_key = Some(key);
+        event.payload_bytes = Some(app_msg_bytes.len());
+        log_event(&event);
+        Ok(())
+    }
+    /// Removes and returns the messages received in the group since the last read.
+    pub fn read_messages(&mut self, gid: &str) -> Result<Vec<ReceivedMessage>, MySgmError> {
+        if !self.state().gids().iter().any(|g| g == gid) {
+            return Err(MySgmError::UnknownGroup(gid.to_string()));
+        }
+        Ok(self.provider.state_mut().take_messages(gid))
+    }
+}
+
+// trust
+impl Agent {
+    /// Fingerprint of the signature key trusted for `pid`: our own key for our own pid, so
+    /// that nobody else can get it pinned, and the pinned key for any other pid.
+    pub fn pinned_fingerprint(&self, pid: &str) -> Result<Option<String>, MySgmError> {
+        if pid == self.state().my_pid() {
+            let public_key = self.state().signature_key_pair().public_key_raw();
+            return Ok(Some(fingerprint(public_key)?));
+        }
+        Ok(self.state().pin(pid).map(str::to_string))
+    }
+    /// Checks each pid's signature key against the key pinned for it, returning the pins to
+    /// record for pids that are seen for the first time.
+    fn check_pins(
+        &self,
+        leaves: &[(String, Vec<u8>)],
+    ) -> Result<Vec<(String, String)>, MySgmError> {
+        let mut pins = Vec::new();
+        for (pid, public_key) in leaves {
+            let fingerprint = fingerprint(public_key)?;
+            if self.state().is_revoked(&fingerprint) {
+                return Err(MySgmError::UntrustedKey(format!(
+                    "{pid} uses revoked key {fingerprint}"
+                )));
+            }
+            match self.pinned_fingerprint(pid)? {
+                Some(pinned) if pinned == fingerprint => {}
+                Some(pinned) => {
+                    return Err(MySgmError::UntrustedKey(format!(
+                        "{pid} uses key {fingerprint}, but {pinned} is pinned"
+                    )));
+                }
+                None if pins.iter().any(|(p, f)| p == pid && *f != fingerprint) => {
+                    return Err(MySgmError::UntrustedKey(format!(
+                        "{pid} appears with more than one key"
+                    )));
+                }
+                None => pins.push((pid.clone(), fingerprint)),
+            }
+        }
+        Ok(pins)
+    }
+    /// Validates an X.509 credential against the trust anchors; basic credentials are
+    /// accepted as they are.
+    fn check_credential(
+        &self,
+        credential: &Credential,
+        signature_key: &[u8],
+    ) -> Result<(), MySgmError> {
+        if credential.credential_type() != CredentialType::X509 {
+            return Ok(());
+        }
+        let anchors = self.trust_anchors.as_ref().ok_or_else(|| {
+            MySgmError::InvalidCredential(
+                "X.509 credential, but no trust anchors are configured".to_string(),
+            )
+        })?;
+        x509::validate(credential, signature_key, anchors)
+    }
+    /// pid and signature key of a leaf, once its credential is validated.
+    fn checked_leaf_key(&self, leaf_node: &LeafNode) -> Result<(String, Vec<u8>), MySgmError> {
+        self.check_credential(leaf_node.credential(), leaf_node.signature_key().as_slice())?;
+        leaf_key(leaf_node)
+    }
+    /// pid and signature key of every member of a group described by a GroupInfo, once
+    /// their credentials are validated.
+    fn checked_members(
+        &self,
+        public_group: &PublicGroup,
+    ) -> Result<Vec<(String, Vec<u8>)>, MySgmError> {
+        public_group
+            .members()
+            .map(|member| {
+                self.check_credential(&member.credential, &member.signature_key)?;
+                Ok((pid_of(&member.credential)?, member.signature_key))
+            })
+            .collect()
+    }
+    /// Checks the leaves a commit adds or changes, returning the pins to record. New
+    /// members have to match their pins. A member may replace its own key in an update,
+    /// since its current key signs the update, but not its pid.
+    fn commit_pins(
+        &self,
+        group: &MlsGroup,
+        commit: &StagedCommit,
+        committer: &Sender,
+    ) -> Result<Vec<(String, String)>, MySgmError> {
+        let mut added = Vec::new();
+        let mut updated = Vec::new();
+        for add in commit.add_proposals() {
+            added.push(self.checked_leaf_key(add.add_proposal().key_package().leaf_node())?);
+        }
+        for update in commit.update_proposals() {
+            let leaf = self.checked_leaf_key(update.update_proposal().leaf_node())?;
+            updated.push((update.sender().clone(), leaf));
+        }
+        if let Some(leaf_node) = commit.update_path_leaf_node() {
+            let leaf = self.checked_leaf_key(leaf_node)?;
+            match committer {
+                Sender::Member(_) => updated.push((committer.clone(), leaf)),
+                // an external commit adds the committer
+                _ => added.push(leaf),
+            }
+        }
+        let mut pins = self.check_pins(&added)?;
+        for (sender, (pid, public_key)) in updated {
+            let Sender::Member(index) = sender else {
+                return Err(MySgmError::UnexpectedMessage(
+                    "Update by a non-member".to_string(),
+                ));
+            };
+            let member = group
+                .members()
+                .find(|member| member.index == index)
+                .ok_or_else(|| MySgmError::UnexpectedMessage(format!("No member at {index}")))?;
+            if pid_of(&member.credential)? != pid {
+                return Err(MySgmError::UntrustedKey(format!(
+                    "Member at {index} changed its pid to {pid}"
+                )));
+            }
+            let fingerprint = fingerprint(&public_key)?;
+            if self.state().is_revoked(&fingerprint) {
+                return Err(MySgmError::UntrustedKey(format!(
+                    "{pid} rotated to revoked key {fingerprint}"
+                )));
+            }
+            if member.signature_key != public_key {
+                log::info!("{pid} rotated its key to {fingerprint}");
+                pins.push((pid, fingerprint));
+            }
+        }
+        Ok(pins)
+    }
+    /// Moves the pin of `pid` along the key rotations it announced, starting from the
+    /// pinned key; returns whether the pin moved.
+    fn follow_rotations(&mut self, pid: &str) -> Result<bool, MySgmError> {
+        let Some(mut pinned) = self.state().pin(pid).map(str::to_string) else {
+            return Ok(false);
+        };
+        let mut moved = false;
+        for index in 0.. {
+            let key = key_rotation_key(pid, index);
+            // anyone can put under the key, so a forged value may sit next to the real one
+            let values = self.adapter.get_all(&key).map_err(MySgmError::adapter)?;
+            if values.is_empty() {
+                break;
+            }
+            for bytes in values {
+                let rotation: KeyRotation = match json_decode_bytes(&bytes) {
+                    Ok(rotation) => rotation,
+                    Err(e) => {
+                        log::warn!("Skipping key rotation at {key}: {e}");
+                        continue;
+                    }
+                };
+                if rotation.pid != pid || rotation.old_fingerprint()? != pinned {
+                    continue;
+                }
+                if let Err(e) = rotation.verify() {
+                    log::warn!("Skipping key rotation at {key}: {e}");
+                    continue;
+                }
+                let new = rotation.new_fingerprint()?;
+                if self.state().is_revoked(&new) {
+                    continue;
+                }
+                log::info!("Following key rotation of {pid} to {new}");
+                pinned = new;
+                moved = true;
+                break;
+            }
+        }
+        if moved {
+            self.provider.state_mut().set_pin(pid, &pinned);
+        }
+        Ok(moved)
+    }
+    fn pin_all(&mut self, pins: Vec<(String, String)>) {
+        for (pid, fingerprint) in pins {
+            log::info!("Pinning key {fingerprint} for pid: {pid}");
+            self.provider.state_mut().set_pin(&pid, &fingerprint);
+        }
+    }
+    /// Stores a downloaded key package if its key is trusted for its pid, pinning the key
+    /// if the pid is new; otherwise quarantines it until `Trust set` accepts its key.
+    fn accept_key_package(&mut self, pid: &str, kp: KeyPackage) -> Result<(), MySgmError> {
+        let leaves = [leaf_key(kp.leaf_node())?];
+        let mut checked = self.check_pins(&leaves);
+        if checked.is_err() && self.follow_rotations(pid)? {
+            checked = self.check_pins(&leaves);
+        }
+        match checked {
+            Ok(_) if pid == self.state().my_pid() && !kp.last_resort() => {
+                log::info!("Skipping single-use key package of ours");
+            }
+            Ok(pins) => {
+                self.pin_all(pins);
+                self.provider.state_mut().store_key_package(pid, kp);
+            }
+            Err(_) if pid == self.state().my_pid() => {
+                log::info!("Skipping key package with a former key of ours");
+            }
+            Err(e) => {
+                log::warn!("Quarantining key package of {pid}: {e}");
+                self.provider.state_mut().quarantine(pid, kp);
+            }
+        }
+        Ok(())
+    }
+    /// Pins the key with `fingerprint` for `pid` in place of the key trusted so far. Key
+    /// packages of the pid with another key are dropped, and a quarantined key package
+    /// with that key is released.
+    pub fn trust_set(&mut self, pid: &str, fingerprint: &str) -> Result<(), MySgmError> {
+        if pid == self.state().my_pid() {
+            return Err(MySgmError::InvalidArgument(
+                "Our own key cannot be pinned".to_string(),
+            ));
+        }
+        let fingerprint = parse_fingerprint(fingerprint)?;
+        let state = self.provider.state_mut();
+        state.unrevoke_key(&fingerprint);
+        state.set_pin(pid, &fingerprint);
+        let matches = |kp: Option<&KeyPackage>| -> Result<Option<bool>, MySgmError> {
+            kp.map(|kp| Ok(key_package_fingerprint(kp)? == fingerprint))
+                .transpose()
+        };
+        if matches(state.key_package(pid))? == Some(false) {
+            log::info!("Dropping key package of {pid} with a key that is no longer pinned");
+            state.remove_key_package(pid);
+        }
+        state.retain_queued_key_packages(pid, |kp| {
+            key_package_fingerprint(kp).is_ok_and(|fp| fp == fingerprint)
+        });
+        let release = matches(state.quarantined(pid))? == Some(true);
+        if let Some(kp) = release.then(|| state.release(pid)).flatten() {
+            log::info!("Releasing quarantined key package of {pid}");
+            state.store_key_package(pid, kp);
+        }
+        Ok(())
+    }
+    /// Revokes the key with `fingerprint`, pinned or quarantined for `pid`: its key package
+    /// is dropped, the pin is removed, and the key is refused from then on.
+    pub fn trust_revoke(&mut self, pid: &str, fingerprint: &str) -> Result<(), MySgmError> {
+        let fingerprint = parse_fingerprint(fingerprint)?;
+        let state = self.provider.state_mut();
+        let pinned = state.pin(pid) == Some(fingerprint.as_str());
+        let quarantined = match state.quarantined(pid) {
+            Some(kp) => key_package_fingerprint(kp)? == fingerprint,
+            None => false,
+        };
+        if !pinned && !quarantined {
+            return Err(MySgmError::InvalidArgument(format!(
+                "{fingerprint} is not a known key of {pid}"
+            )));
+        }
+        state.revoke_key(&fingerprint);
+        if pinned {
+            state.remove_pin(pid);
+            state.remove_key_package(pid);
+            state.retain_queued_key_packages(pid, |_| false);
+        }
+        if quarantined {
+            state.release(pid);
+        }
+        Ok(())
+    }
+}
+
+// helpers
+impl Agent {
+    fn load_group(&self, gid: &str) -> Result<MlsGroup, MySgmError> {
+        MlsGroup::load(
+            self.provider.storage(),
+            &GroupId::from_slice(gid.as_bytes()),
+        )
+        .map_err(MySgmError::mls)?
+        .ok_or_else(|| MySgmError::UnknownGroup(gid.to_string()))
+    }
+    /// Key package to add `pid` with, provided it can be added to the group: its oldest
+    /// single-use key package that is still valid, or else its last-resort one.
+    fn key_package_for(&self, group: &MlsGroup, pid: &str) -> Result<&KeyPackage, MySgmError> {
+        let state = self.state();
+        let kp = state
+            .queued_key_packages(pid)
+            .iter()
+            .find(|kp| kp.life_time().is_valid())
+            .or_else(|| state.key_package(pid))
+            .ok_or_else(|| MySgmError::UnknownAgent(pid.to_string()))?;
+        if !kp.life_time().is_valid() {
+            return Err(MySgmError::ExpiredKeyPackage(pid.to_string()));
+        }
+        if kp.ciphersuite() != group.ciphersuite() {
+            return Err(MySgmError::CiphersuiteMismatch(format!(
+                "{pid} advertised {:?}, but the group uses {:?}",
+                kp.ciphersuite(),
+                group.ciphersuite()
+            )));
+        }
+        // the pin may have changed since the key package was accepted
+        self.check_pins(&[leaf_key(kp.leaf_node())?])?;
+        Ok(kp)
+    }
+    /// pids of the members at the given leaf indexes.
+    fn pids_at(&self, gid: &str, indexes: &[u32]) -> Result<Vec<String>, MySgmError> {
+        let members = self.members(gid)?;
+        indexes
+            .iter()
+            .map(|index| {
+                members
+                    .iter()
+                    .find(|(leaf, _)| leaf.u32() == *index)
+                    .map(|(_, pid)| pid.clone())
+                    .ok_or_else(|| {
+                        MySgmError::InvalidArgument(format!("No member at index {index}"))
+                    })
+            })
+            .collect()
+    }
+    /// Deletes a group we are no longer a member of.
+    fn forget_group(&mut self, mut group: MlsGroup, gid: &str) -> Result<(), MySgmError> {
+        group
+            .delete(self.provider.storage())
+            .map_err(MySgmError::mls)?;
+        self.provider.state_mut().remove_gid(gid);
+        Ok(())
+    }
+    fn publish_leave_request(&mut self, group: &mut MlsGroup, gid: &str) -> Result<(), MySgmError> {
+        let started = now_ms();
+        let proposal = group
+            .leave_group(&self.provider, &self.provider)
+            .map_err(MySgmError::mls)?;
+        self.publish_proposal(group, gid, proposal, "group_leave", started)?;
+        self.provider
+            .state_mut()
+            .set_pending_leave(gid, group.epoch().as_u64());
+        Ok(())
+    }
+    /// Stores a proposal at the next free proposal key of the group's current epoch.
+    fn publish_proposal(
+        &self,
+        group: &MlsGroup,
+        gid: &str,
+        proposal: MlsMessageOut,
+        op: &str,
+        started: u128,
+    ) -> Result<(), MySgmError> {
+        let proposal_bytes = proposal.tls_serialize_detached()?;
+        let epoch = group.epoch().as_u64();
+        let (_, key) = self.put_first_free(
+            self.state().proposal_cursor(gid, epoch),
+            &proposal_bytes,
+            |index| proposal_key(group, &self.provider, index).map_err(MySgmError::mls),
+        )?;
+        log::info!("Proposal key: {key}");
+        let mut event = MetricsEvent::new(op, started, now_ms());
+        event.node_id = Some(self.state().my_pid().to_string());
+        event.gid = Some(gid.to_string());
+        event.dht_key = Some(key);
+        event.payload_bytes = Some(proposal_bytes.len());
+        log_event(&event);
+        Ok(())
+    }
+    /// Publishes the GroupInfo of the group's current epoch for external joins.
+    fn publish_group_info(&mut self, group: &MlsGroup, gid: &str) -> Result<(), MySgmError> {
+        let group_info = group
+            .export_group_info(self.provider.crypto(), &self.provider, true)
+            .map_err(MySgmError::mls)?;
+        let published = PublishedGroupInfo {
+            epoch: group.epoch().as_u64(),
+            commit_key: commit_key(group, &self.provider).map_err(MySgmError::mls)?,
+            group_info: hex_encode(group_info.tls_serialize_detached()?),
+        };
+        let (index, key) = self.put_first_free(
+            self.state().group_info_counter(gid),
+            &json_encode_bytes(&published)?,
+            |index| Ok(group_info_key(gid, index)),
+        )?;
+        log::info!("Group info key: {key}");
+        self.provider
+            .state_mut()
+            .set_group_info_counter(gid, index + 1);
+        Ok(())
+    }
+    /// Stores `value` at the first free key of the sequence produced by `key_at`, starting
+    /// at `index`, and returns the index and key used.
+    fn put_first_free(
+        &self,
+        mut index: u64,
+        value: &[u8],
+        key_at: impl Fn(u64) -> Result<String, MySgmError>,
+    ) -> Result<(u64, String), MySgmError> {
+        loop {
+            let key = key_at(index)?;
+            match self.adapter.put_checked(&key, value) {
+                Ok(()) => {
+                    self.writes.set(self.writes.get() + 1);
+                    return Ok((index, key));
+                }
+                Err(e) if e.is::<KeyExists>() => {
+                    log::debug!("Key already taken: {key}");
+                    index += 1;
+                }
+                Err(e) => return Err(MySgmError::adapter(e)),
+            }
+        }
+    }
+    /// Stages a commit with `stage` and publishes it, staging it again in the new epoch
+    /// if it loses to a concurrent commit and re-issuing is enabled. `stage` returns
+    /// `None` when there is nothing (left) to commit.
+    fn commit(
+        &mut self,
+        gid: &str,
+        mut stage: impl FnMut(&Self, &mut MlsGroup) -> Result<Option<OutgoingCommit>, MySgmError>,
+    ) -> Result<Option<MergedCommit>, MySgmError> {
+        let mut reissues = 0;
+        loop {
+            let mut group = self.load_group(gid)?;
+            let Some(outgoing) = stage(self, &mut group)? else {
+                return Ok(None);
+            };
+            if let Some(merged) = self.publish_and_merge(&mut group, gid, outgoing)? {
+                return Ok(Some(merged));
+            }
+            if !self.reissue || reissues == MAX_REISSUES {
+                return Err(MySgmError::CommitConflict(gid.to_string()));
+            }
+            reissues += 1;
+            log::info!("Re-issuing commit for gid: {gid} ({reissues}/{MAX_REISSUES})");
+        }
+    }
+    /// Publishes the pending commit of `group` and merges it, unless a concurrent commit
+    /// for the same epoch wins, in which case ours is dropped, the group catches up with
+    /// the winner and `None` is returned. The welcome, if any, is delivered to the
+    /// mailboxes of the invitees only once our commit is merged.
+    fn publish_and_merge(
+        &mut self,
+        group: &mut MlsGroup,
+        gid: &str,
+        outgoing: OutgoingCommit,
+    ) -> Result<Option<MergedCommit>, MySgmError> {
+        log::info!("Commit message: {:?}", outgoing.commit);
+        let commit_bytes = outgoing.commit.tls_serialize_detached()?;
+        let key = commit_key(group, &self.provider).map_err(MySgmError::mls)?;
+        let stored = match self.adapter.put_checked(&key, &commit_bytes) {
+            Ok(()) => {
+                self.writes.set(self.writes.get() + 1);
+                true
+            }
+            Err(e) if e.is::<KeyExists>() => {
+                log::warn!("Another member already committed at {key}");
+                false
+            }
+            Err(e) => return Err(MySgmError::adapter(e)),
+        };
+        // read back: stores that let writers race may hold other commits next to ours
+        let values = self.adapter.get_all(&key).map_err(MySgmError::adapter)?;
+        let own = stored.then_some(commit_bytes.as_slice());
+        if self.merge_winning_commit(group, gid, &key, values, own)? == CommitWinner::Other {
+            log::warn!("Commit lost to a concurrent commit for gid: {gid}");
+            self.download_commits(gid)?;
+            return Ok(None);
+        }
+        let welcome_bytes = match outgoing.welcome {
+            Some(welcome) => {
+                log::info!("Welcome message: {:?}", welcome);
+                let welcome_bytes = welcome.tls_serialize_detached()?;
+                for pid in &outgoing.invitees {
+                    let (_, key) = self.put_first_free(0, &welcome_bytes, |index| {
+                        Ok(welcome_mailbox_key(pid, index))
+                    })?;
+                    log::info!("Welcome message key: {key}");
+                }
+                Some(welcome_bytes.len())
+            }
+            None => None,
+        };
+        if self.state().group_policy(gid).external_join {
+            self.publish_group_info(group, gid)?;
+        }
+        Ok(Some(MergedCommit {
+            members_after: group.members().count(),
+            commit_bytes: commit_bytes.len(),
+            welcome_bytes,
+        }))
+    }
+}
+
+/// `values` ordered by their `hash`, without duplicates.
+fn rank_by_hash(
+    values: Vec<Vec<u8>>,
+    hash: impl Fn(&[u8]) -> Result<Vec<u8>, MySgmError>,
+) -> Result<Vec<Vec<u8>>, MySgmError> {
+    let mut ranked = values
+        .into_iter()
+        .map(|value| Ok((hash(&value)?, value)))
+        .collect::<Result<Vec<_>, MySgmError>>()?;
+    ranked.sort();
+    ranked.dedup();
+    Ok(ranked.into_iter().map(|(_, value)| value).collect())
+}
+
+/// The first of the `ranked` commits stored at `key` that is `own`, or that `stage`
+/// accepts. `stage` may only check the commit against the protocol, so that every member
+/// picks the same winner; invalid commits are skipped.
+fn select_winner<T>(
+    ranked: Vec<Vec<u8>>,
+    own: Option<&[u8]>,
+    key: &str,
+    mut stage: impl FnMut(Vec<u8>) -> Result<T, MySgmError>,
+) -> Result<Candidate<T>, MySgmError> {
+    let mut last_error = None;
+    for value in ranked {
+        if own == Some(value.as_slice()) {
+            return Ok(Candidate::Own);
+        }
+        match stage(value) {
+            Ok(staged) => return Ok(Candidate::Other(staged)),
+            Err(e) => {
+                log::warn!("Skipping commit at {key}: {e}");
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| MySgmError::UnexpectedMessage(format!("No commit stored at {key}"))))
+}
+
+/// pid carried by a member's credential: the identity of a basic credential, or the
+/// subject of an X.509 certificate.
+pub fn pid_of(credential: &Credential) -> Result<String, MySgmError> {
+    let pid = if credential.credential_type() == CredentialType::X509 {
+        x509::pid_of(credential)?
+    } else {
+        let cred = BasicCredential::try_from(credential.clone()).map_err(MySgmError::mls)?;
+        String::from_utf8_lossy(cred.identity()).to_string()
+    };
+    // the pid goes into the keys of the agent's mailbox and key rotations
+    check_key_part("Pid", &pid).map_err(|e| MySgmError::InvalidCredential(e.to_string()))?;
+    Ok(pid)
+}
+
+/// gid of the group a staged welcome joins, which the inviter chose.
+fn staged_gid(staged_welcome: &StagedWelcome) -> Result<String, MySgmError> {
+    let gid =
+        String::from_utf8_lossy(staged_welcome.group_context().group_id().as_slice()).to_string();
+    check_key_part("Gid", &gid).map_err(|e| MySgmError::UnexpectedMessage(e.to_string()))?;
+    Ok(gid)
+}
+
+fn now_secs() -> u64 {
+    (now_ms() / 1000) as u64
+}
+
+/// Fingerprint of the signature key a key package was issued for.
+pub fn key_package_fingerprint(kp: &KeyPackage) -> Result<String, MySgmError> {
+    fingerprint(kp.leaf_node().signature_key().as_slice())
+}
+
+/// pid and signature public key of a leaf.
+fn leaf_key(leaf_node: &LeafNode) -> Result<(String, Vec<u8>), MySgmError> {
+    Ok((
+        pid_of(leaf_node.credential())?,
+        leaf_node.signature_key().as_slice().to_vec(),
+    ))
+}
+
+/// Normalizes a hex-encoded SHA-256 fingerprint as printed by `Agents --verbose`.
+fn parse_fingerprint(fingerprint: &str) -> Result<String, MySgmError> {
+    let fingerprint = fingerprint.trim().to_lowercase();
+    if fingerprint.len() != 64 || !fingerprint.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(MySgmError::InvalidArgument(format!(
+            "Not a key fingerprint: {fingerprint}"
+        )));
+    }
+    Ok(fingerprint)
+}
+
+/// pids of the members of the group.
+fn member_pids(group: &MlsGroup) -> Result<Vec<String>, MySgmError> {
+    group
+        .members()
+        .map(|member| pid_of(&member.credential))
+        .collect()
+}
+
+/// pids of the agents that pending add proposals would invite, i.e. the recipients of
+/// the welcome produced by the next commit.
+fn pending_add_pids(group: &MlsGroup) -> Vec<String> {
+    group
+        .pending_proposals()
+        .filter_map(|queued| match queued.proposal() {
+            Proposal::Add(add) => pid_of(add.key_package().leaf_node().credential()).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openmls_traits::types::HashType;
+
+    fn values(values: &[&str]) -> Vec<Vec<u8>> {
+        values
+            .iter()
+            .map(|value| value.as_bytes().to_vec())
+            .collect()
+    }
+
+    /// Accepts every commit but the ones starting with `bad`.
+    fn stage(value: Vec<u8>) -> Result<Vec<u8>, MySgmError> {
+        match value.starts_with(b"bad") {
+            true => Err(MySgmError::UnexpectedMessage("invalid".to_string())),
+            false => Ok(value),
+        }
+    }
+
+    #[test]
+    fn rank_orders_by_hash_and_drops_duplicates() {
+        let reversed = |value: &[u8]| -> Result<Vec<u8>, MySgmError> {
+            Ok(value.iter().map(|b| u8::MAX - b).collect())
+        };
+        let ranked = rank_by_hash(values(&["b", "c", "a", "c"]), reversed).unwrap();
+        assert_eq!(ranked, values(&["c", "b", "a"]));
+    }
+
+    #[test]
+    fn rank_is_independent_of_arrival_order() {
+        let crypto = RustCrypto::default();
+        let sha256 = |value: &[u8]| {
+            crypto
+                .hash(HashType::Sha2_256, value)
+                .map_err(MySgmError::mls)
+        };
+        let first = rank_by_hash(values(&["x", "y", "z"]), sha256).unwrap();
+        let second = rank_by_hash(values(&["z", "x", "y", "x"]), sha256).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.len(), 3);
+    }
+
+    #[test]
+    fn invalid_commit_ranked_first_is_skipped() {
+        let winner = select_winner(values(&["bad", "b", "c"]), None, "cm", stage).unwrap();
+        assert_eq!(winner, Candidate::Other(b"b".to_vec()));
+    }
+
+    #[test]
+    fn own_commit_wins_over_invalid_ones_ranked_first() {
+        let ranked = values(&["bad1", "bad2", "own", "c"]);
+        let winner = select_winner(ranked, Some(b"own".as_slice()), "cm", stage).unwrap();
+        assert_eq!(winner, Candidate::Own);
+    }
+
+    #[test]
+    fn valid_commit_ranked_first_beats_own() {
+        let ranked = values(&["a", "own"]);
+        let winner = select_winner(ranked, Some(b"own".as_slice()), "cm", stage).unwrap();
+        assert_eq!(winner, Candidate::Other(b"a".to_vec()));
+    }
+
+    #[test]
+    fn no_valid_commit_is_an_error() {
+        let winner = select_winner(values(&["bad1", "bad2"]), None, "cm", stage);
+        assert!(matches!(winner, Err(MySgmError::UnexpectedMessage(_))));
+        let winner = select_winner(Vec::new(), None, "cm", stage);
+        assert!(matches!(winner, Err(MySgmError::UnexpectedMessage(_))));
+    }
+}
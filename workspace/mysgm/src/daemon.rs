@@ -0,0 +1,123 @@
+//! Long-running agent mode.
+//!
+//! A daemon keeps the agent in memory, syncs with the storage adapter on an interval and
+//! serves commands over a Unix domain socket. Each connection carries exactly one request:
+//! a single line of JSON holding the command, answered by a single line of JSON holding a
+//! [`DaemonResponse`]. Between syncs, the daemon also syncs as soon as its handler is woken
+//! up, e.g. by a watch on the storage adapter reporting a new value.
+
+use super::error::MySgmError;
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{from_str as json_decode, to_string as json_encode};
+use std::{
+    fs::{exists as file_exists, remove_file},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/// How long to wait between polls of the socket when no client is connecting.
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+/// Reply to a single command sent to the daemon.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DaemonResponse {
+    /// Whatever the command printed
+    pub output: String,
+    /// Why the command failed, if it did
+    pub error: Option<String>,
+    /// Exit code the client should terminate with
+    pub exit_code: i32,
+}
+
+impl DaemonResponse {
+    pub fn ok(output: String) -> Self {
+        Self {
+            output,
+            error: None,
+            exit_code: 0,
+        }
+    }
+    pub fn error(error: &MySgmError) -> Self {
+        Self {
+            output: String::new(),
+            error: Some(error.to_string()),
+            exit_code: error.exit_code(),
+        }
+    }
+}
+
+/// The agent behind a daemon.
+pub trait DaemonHandler<C> {
+    /// Called once per sync interval.
+    fn tick(&mut self);
+    /// Called for every command received on the socket.
+    fn request(&mut self, command: C) -> DaemonResponse;
+    /// Polled between connections; returning `true` ticks right away.
+    fn wake(&mut self) -> bool {
+        false
+    }
+}
+
+/// Serves commands on `socket_path` until an I/O error on the listener occurs, calling
+/// [`DaemonHandler::tick`] every `interval` and whenever [`DaemonHandler::wake`] says so.
+pub fn serve<C: DeserializeOwned>(
+    socket_path: &str,
+    interval: Duration,
+    handler: &mut impl DaemonHandler<C>,
+) -> Result<(), MySgmError> {
+    // a socket file left behind by a previous daemon would make bind fail
+    if file_exists(socket_path)? {
+        log::warn!("Removing stale socket: {socket_path}");
+        remove_file(socket_path)?;
+    }
+    let listener = UnixListener::bind(socket_path)?;
+    listener.set_nonblocking(true)?;
+    log::info!("Daemon listening on {socket_path}");
+    let mut last_tick = Instant::now();
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = handle_connection(stream, handler) {
+                    log::warn!("Failed to serve client: {e}");
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => sleep(ACCEPT_POLL),
+            Err(e) => return Err(e.into()),
+        }
+        if handler.wake() || last_tick.elapsed() >= interval {
+            handler.tick();
+            last_tick = Instant::now();
+        }
+    }
+}
+
+fn handle_connection<C: DeserializeOwned>(
+    stream: UnixStream,
+    handler: &mut impl DaemonHandler<C>,
+) -> Result<(), MySgmError> {
+    // some platforms hand out accepted streams in the listener's non-blocking mode
+    stream.set_nonblocking(false)?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    log::info!("Daemon request: {}", line.trim_end());
+    let response = match json_decode::<C>(&line) {
+        Ok(command) => handler.request(command),
+        Err(e) => DaemonResponse::error(&MySgmError::InvalidArgument(format!(
+            "Invalid request: {e}"
+        ))),
+    };
+    writeln!(&stream, "{}", json_encode(&response)?)?;
+    Ok(())
+}
+
+/// Sends `command` to the daemon listening on `socket_path` and waits for its response.
+pub fn request<C: Serialize>(socket_path: &str, command: &C) -> Result<DaemonResponse, MySgmError> {
+    let stream = UnixStream::connect(socket_path)?;
+    writeln!(&stream, "{}", json_encode(command)?)?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    Ok(json_decode(&line)?)
+}
@@ -0,0 +1,298 @@
+//! Optional encryption of the state file at rest.
+//!
+//! A sealed state file is a JSON envelope holding the ChaCha20-Poly1305 encryption of the
+//! state JSON, under a key derived by Argon2id from a passphrase. The header of the
+//! envelope (format version, KDF parameters, salt and nonce) is authenticated as
+//! associated data, so it cannot be altered without failing decryption. Exported
+//! identities (see [`identity`](super::identity)) are sealed the same way.
+//!
+//! Argon2id is slow on purpose, so a [`StateKey`] keeps the last key it derived and keeps
+//! sealing with its salt, deriving a key once per process rather than on every save.
+
+use super::error::MySgmError;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit,
+    aead::{Aead, Payload},
+};
+use hex::{decode as hex_decode, encode as hex_encode};
+use openmls_rust_crypto::RustCrypto;
+use openmls_traits::random::OpenMlsRand;
+use serde_json::{from_str as json_decode, to_string as json_encode, to_vec as json_encode_bytes};
+use std::{
+    fs::read as read_file,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+/// Environment variable holding the passphrase, as an alternative to a key file.
+pub const PASSPHRASE_ENV: &str = "MYSGM_STATE_PASSPHRASE";
+
+const FORMAT: &str = "mysgm-sealed-state";
+const VERSION: u16 = 1;
+const KDF: &str = "argon2id";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Highest Argon2id costs accepted from a header. The header is only authenticated once
+/// the key is derived, so a tampered file could otherwise make the derivation take all
+/// memory or run for hours; `seal` writes the defaults, far below these.
+const MAX_M_COST: u32 = 1 << 20;
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 16;
+
+/// Passphrase a state file or an identity bundle is sealed with.
+#[derive(Clone)]
+pub struct StateKey {
+    passphrase: Vec<u8>,
+    /// Whether a plaintext state file may be opened, to be sealed when next saved
+    seal_plaintext: bool,
+    /// Last key derived from the passphrase, shared by the clones of the key
+    derived: Arc<Mutex<Option<DerivedKey>>>,
+    /// Whether a plaintext state file was opened since backups were last sealed
+    plaintext_opened: Arc<AtomicBool>,
+}
+
+/// AEAD key derived for some KDF parameters and salt.
+struct DerivedKey {
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: String,
+    key: [u8; 32],
+}
+
+impl DerivedKey {
+    fn matches(&self, m_cost: u32, t_cost: u32, p_cost: u32, salt: &str) -> bool {
+        (self.m_cost, self.t_cost, self.p_cost) == (m_cost, t_cost, p_cost) && self.salt == salt
+    }
+}
+
+impl StateKey {
+    pub fn from_passphrase(passphrase: &str) -> Result<Self, MySgmError> {
+        Self::new(passphrase.as_bytes().to_vec())
+    }
+    /// Uses the contents of the file at `path` as the passphrase, without a trailing
+    /// newline, so that both random key files and passphrase files work.
+    pub fn from_file(path: &str) -> Result<Self, MySgmError> {
+        let mut passphrase = read_file(path)?;
+        while passphrase
+            .last()
+            .is_some_and(|b| *b == b'\n' || *b == b'\r')
+        {
+            passphrase.pop();
+        }
+        Self::new(passphrase)
+    }
+    fn new(passphrase: Vec<u8>) -> Result<Self, MySgmError> {
+        if passphrase.is_empty() {
+            return Err(MySgmError::InvalidArgument(
+                "State passphrase is empty".to_string(),
+            ));
+        }
+        Ok(Self {
+            passphrase,
+            seal_plaintext: false,
+            derived: Arc::default(),
+            plaintext_opened: Arc::default(),
+        })
+    }
+    /// Lets [`open`] accept a plaintext state file, which gets sealed when next saved.
+    /// Without it, a plaintext state file is refused, so that a state that should be sealed
+    /// is not silently replaced by one that is not.
+    pub fn sealing_plaintext(mut self, seal_plaintext: bool) -> Self {
+        self.seal_plaintext = seal_plaintext;
+        self
+    }
+    /// Whether a plaintext state file was opened with this key since the last call, in
+    /// which case plaintext backups may be left to seal.
+    pub(crate) fn take_plaintext_opened(&self) -> bool {
+        self.plaintext_opened.swap(false, Ordering::SeqCst)
+    }
+    /// Salt of the key derived last, if it was derived with the `params` used for sealing.
+    fn derived_salt(&self, params: &Params) -> Result<Option<String>, MySgmError> {
+        let derived = self
+            .derived
+            .lock()
+            .map_err(|e| MySgmError::StateKey(e.to_string()))?;
+        Ok(derived
+            .as_ref()
+            .filter(|derived| {
+                (derived.m_cost, derived.t_cost, derived.p_cost)
+                    == (params.m_cost(), params.t_cost(), params.p_cost())
+            })
+            .map(|derived| derived.salt.clone()))
+    }
+    /// AEAD key for the KDF parameters and salt in `header`.
+    fn cipher(&self, header: &Header) -> Result<ChaCha20Poly1305, MySgmError> {
+        let mut derived = self
+            .derived
+            .lock()
+            .map_err(|e| MySgmError::StateKey(e.to_string()))?;
+        let cached = derived.as_ref().filter(|derived| {
+            derived.matches(header.m_cost, header.t_cost, header.p_cost, &header.salt)
+        });
+        if let Some(cached) = cached {
+            return Ok(ChaCha20Poly1305::new(&cached.key.into()));
+        }
+        if header.m_cost > MAX_M_COST || header.t_cost > MAX_T_COST || header.p_cost > MAX_P_COST {
+            return Err(MySgmError::StateKey(format!(
+                "Argon2id costs m={} t={} p={} exceed the limits m={MAX_M_COST} t={MAX_T_COST} p={MAX_P_COST}",
+                header.m_cost, header.t_cost, header.p_cost
+            )));
+        }
+        if header.salt.len() != 2 * SALT_LEN {
+            return Err(MySgmError::StateKey("Invalid salt".to_string()));
+        }
+        let params = Params::new(header.m_cost, header.t_cost, header.p_cost, Some(32))
+            .map_err(|e| MySgmError::StateKey(e.to_string()))?;
+        let salt = hex_decode(&header.salt).map_err(|e| MySgmError::StateKey(e.to_string()))?;
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(&self.passphrase, &salt, &mut key)
+            .map_err(|e| MySgmError::StateKey(e.to_string()))?;
+        *derived = Some(DerivedKey {
+            m_cost: header.m_cost,
+            t_cost: header.t_cost,
+            p_cost: header.p_cost,
+            salt: header.salt.clone(),
+            key,
+        });
+        Ok(ChaCha20Poly1305::new(&key.into()))
+    }
+}
+
+/// Authenticated, unencrypted part of a sealed state file.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Header {
+    format: String,
+    version: u16,
+    kdf: String,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: String,
+    nonce: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Envelope {
+    #[serde(flatten)]
+    header: Header,
+    ciphertext: String,
+}
+
+fn envelope(contents: &str) -> Option<Envelope> {
+    json_decode::<Envelope>(contents)
+        .ok()
+        .filter(|envelope| envelope.header.format == FORMAT)
+}
+
+/// Encrypts the state JSON `plaintext` into a sealed state file.
+pub fn seal(key: &StateKey, plaintext: &str) -> Result<String, MySgmError> {
+    let rand = RustCrypto::default();
+    let random = |len| {
+        rand.random_vec(len)
+            .map_err(|e| MySgmError::StateKey(format!("{e:?}")))
+    };
+    let params = Params::default();
+    let salt = match key.derived_salt(&params)? {
+        Some(salt) => salt,
+        None => hex_encode(random(SALT_LEN)?),
+    };
+    let header = Header {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        kdf: KDF.to_string(),
+        m_cost: params.m_cost(),
+        t_cost: params.t_cost(),
+        p_cost: params.p_cost(),
+        salt,
+        nonce: hex_encode(random(NONCE_LEN)?),
+    };
+    let aad = json_encode_bytes(&header)?;
+    let nonce = hex_decode(&header.nonce).map_err(|e| MySgmError::StateKey(e.to_string()))?;
+    let ciphertext = key
+        .cipher(&header)?
+        .encrypt(
+            nonce.as_slice().into(),
+            Payload {
+                msg: plaintext.as_bytes(),
+                aad: &aad,
+            },
+        )
+        .map_err(|_| MySgmError::StateKey("Encryption failed".to_string()))?;
+    Ok(json_encode(&Envelope {
+        header,
+        ciphertext: hex_encode(ciphertext),
+    })?)
+}
+
+/// Returns the state JSON held by a state file, decrypting it if it is sealed.
+pub fn open(key: Option<&StateKey>, contents: &str) -> Result<String, MySgmError> {
+    let Some(envelope) = envelope(contents) else {
+        match key {
+            Some(key) if !key.seal_plaintext => {
+                return Err(MySgmError::StateKey(
+                    "State file is not encrypted; pass --seal-plaintext-state to seal it"
+                        .to_string(),
+                ));
+            }
+            Some(key) => {
+                log::warn!("State file is not encrypted; it will be sealed when next saved");
+                key.plaintext_opened.store(true, Ordering::SeqCst);
+            }
+            None => {}
+        }
+        return Ok(contents.to_string());
+    };
+    let key = key.ok_or_else(|| {
+        MySgmError::StateKey(format!(
+            "State file is encrypted; pass --state-key-file or set {PASSPHRASE_ENV}"
+        ))
+    })?;
+    decrypt(key, &envelope)
+}
+
+/// Whether `contents` is a sealed file.
+pub fn is_sealed(contents: &str) -> bool {
+    envelope(contents).is_some()
+}
+
+/// Decrypts a sealed file, failing if it is not sealed.
+pub fn open_sealed(key: &StateKey, contents: &str) -> Result<String, MySgmError> {
+    let envelope =
+        envelope(contents).ok_or_else(|| MySgmError::StateKey("File is not sealed".to_string()))?;
+    decrypt(key, &envelope)
+}
+
+fn decrypt(key: &StateKey, envelope: &Envelope) -> Result<String, MySgmError> {
+    let header = &envelope.header;
+    if header.version != VERSION || header.kdf != KDF {
+        return Err(MySgmError::StateKey(format!(
+            "Unsupported sealed file version {} with KDF {}",
+            header.version, header.kdf
+        )));
+    }
+    let aad = json_encode_bytes(header)?;
+    let nonce = hex_decode(&header.nonce).map_err(|e| MySgmError::StateKey(e.to_string()))?;
+    if nonce.len() != NONCE_LEN {
+        return Err(MySgmError::StateKey("Invalid nonce".to_string()));
+    }
+    let ciphertext =
+        hex_decode(&envelope.ciphertext).map_err(|e| MySgmError::StateKey(e.to_string()))?;
+    let plaintext = key
+        .cipher(header)?
+        .decrypt(
+            nonce.as_slice().into(),
+            Payload {
+                msg: &ciphertext,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| MySgmError::StateKey("Wrong key or corrupted file".to_string()))?;
+    String::from_utf8(plaintext).map_err(|e| MySgmError::State(e.to_string()))
+}
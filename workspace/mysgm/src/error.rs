@@ -0,0 +1,121 @@
+//! Error type shared by the agent library and the CLI.
+
+/// Everything that can go wrong while running an agent.
+///
+/// Each variant maps to a distinct process exit code, see [`MySgmError::exit_code`].
+#[derive(Debug)]
+pub enum MySgmError {
+    /// Reading or writing a local file or socket failed
+    Io(String),
+    /// The agent state could not be encoded or decoded
+    State(String),
+    /// The storage adapter failed to read or write a key
+    Adapter(String),
+    /// Bytes from the adapter could not be decoded as an MLS message
+    Codec(String),
+    /// OpenMLS rejected an operation
+    Mls(String),
+    /// No group with this gid is known locally
+    UnknownGroup(String),
+    /// A group with this gid already exists locally
+    GroupExists(String),
+    /// No key package is known for this pid
+    UnknownAgent(String),
+    /// A message of a different kind was expected
+    UnexpectedMessage(String),
+    /// A command argument is invalid
+    InvalidArgument(String),
+    /// Our commit lost to a concurrent commit for the same epoch
+    CommitConflict(String),
+    /// A key package uses a different ciphersuite than the group
+    CiphersuiteMismatch(String),
+    /// The state file is encrypted and could not be decrypted
+    StateKey(String),
+    /// A signature key differs from the one pinned for its pid, or was revoked
+    UntrustedKey(String),
+    /// An X.509 credential does not validate against the trust anchors
+    InvalidCredential(String),
+    /// The key package known for this pid is past its lifetime
+    ExpiredKeyPackage(String),
+}
+
+impl MySgmError {
+    /// Process exit code for this error; 1 is left to clap and 101 to panics.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Io(_) => 2,
+            Self::State(_) => 3,
+            Self::Adapter(_) => 4,
+            Self::Codec(_) => 5,
+            Self::Mls(_) => 6,
+            Self::UnknownGroup(_) => 7,
+            Self::GroupExists(_) => 8,
+            Self::UnknownAgent(_) => 9,
+            Self::UnexpectedMessage(_) => 10,
+            Self::InvalidArgument(_) => 11,
+            Self::CommitConflict(_) => 12,
+            Self::CiphersuiteMismatch(_) => 13,
+            Self::StateKey(_) => 14,
+            Self::UntrustedKey(_) => 15,
+            Self::InvalidCredential(_) => 16,
+            Self::ExpiredKeyPackage(_) => 17,
+        }
+    }
+    pub fn adapter(e: impl core::fmt::Display) -> Self {
+        Self::Adapter(e.to_string())
+    }
+    pub fn mls(e: impl core::fmt::Display) -> Self {
+        Self::Mls(e.to_string())
+    }
+}
+
+impl core::fmt::Display for MySgmError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error: {e}"),
+            Self::State(e) => write!(f, "State error: {e}"),
+            Self::Adapter(e) => write!(f, "Storage adapter error: {e}"),
+            Self::Codec(e) => write!(f, "Decoding error: {e}"),
+            Self::Mls(e) => write!(f, "MLS error: {e}"),
+            Self::UnknownGroup(gid) => write!(f, "Unknown group: {gid}"),
+            Self::GroupExists(gid) => write!(f, "Group already exists: {gid}"),
+            Self::UnknownAgent(pid) => write!(f, "No key package for pid: {pid}"),
+            Self::UnexpectedMessage(e) => write!(f, "Unexpected message: {e}"),
+            Self::InvalidArgument(e) => write!(f, "Invalid argument: {e}"),
+            Self::CommitConflict(gid) => {
+                write!(f, "Commit lost to a concurrent commit in group: {gid}")
+            }
+            Self::CiphersuiteMismatch(e) => write!(f, "Ciphersuite mismatch: {e}"),
+            Self::StateKey(e) => write!(f, "State encryption error: {e}"),
+            Self::UntrustedKey(e) => write!(f, "Untrusted signature key: {e}"),
+            Self::InvalidCredential(e) => write!(f, "Invalid credential: {e}"),
+            Self::ExpiredKeyPackage(pid) => write!(f, "Key package of {pid} has expired"),
+        }
+    }
+}
+
+impl core::error::Error for MySgmError {}
+
+impl From<std::io::Error> for MySgmError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for MySgmError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::State(e.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for MySgmError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::State(e.to_string())
+    }
+}
+
+impl From<tls_codec::Error> for MySgmError {
+    fn from(e: tls_codec::Error) -> Self {
+        Self::Codec(e.to_string())
+    }
+}
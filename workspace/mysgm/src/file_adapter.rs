@@ -1,20 +1,58 @@
+use crate::adapter::{KeyExists, WatchEvent};
 use core::error::Error;
 use hex::{decode as hex_decode, encode as hex_encode};
-use std::fs::{
-    exists as file_exists, read_to_string as read_file_to_string, write as write_string_to_file,
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher, recommended_watcher};
+use std::{
+    ffi::OsStr,
+    fs::{
+        OpenOptions, exists as file_exists, hard_link, read_to_string as read_file_to_string,
+        remove_file,
+    },
+    io::{ErrorKind, Write},
+    path::Path,
+    process,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{Receiver, RecvTimeoutError, Sender, channel},
+    },
+    thread::spawn,
+    time::{Duration, Instant},
 };
 
+/// How long a watch waits for its file before it ends and has to be renewed.
+const WATCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often watches are checked for their timeout while the directory is quiet.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tells apart the temporary files of concurrent writes within a process.
+static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Debug, Clone)]
 pub struct FileAdapter {
     path: String,
+    /// Started by the first watch, and shared by the clones of the adapter
+    watcher: Arc<Mutex<Option<DirectoryWatcher>>>,
 }
 
 impl FileAdapter {
     pub fn new(path: &str) -> Self {
-        Self { path: path.into() }
+        Self {
+            path: path.into(),
+            watcher: Arc::default(),
+        }
+    }
+    /// Path of the file of `key`, which has to be a plain file name, so that no key reads
+    /// or writes outside the directory.
+    fn file(&self, key: &str) -> Result<String, Box<dyn Error>> {
+        if key.is_empty() || key.starts_with('.') || key.contains(['/', '\0']) {
+            return Err(format!("Key {key:?} is not a file name").into());
+        }
+        Ok(format!("{}/{}", self.path, key))
     }
     pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
-        let file = format!("{}/{}", self.path, key);
+        let file = self.file(key)?;
         match file_exists(&file)? {
             true => {
                 let decoded_value = hex_decode(read_file_to_string(&file)?.trim())?;
@@ -23,14 +61,141 @@ impl FileAdapter {
             false => Ok(None),
         }
     }
+    /// Writes the value to a temporary file and links it in place under `key`. Linking
+    /// fails if the key exists, so that of two agents racing for the same key exactly one
+    /// wins, and readers never see the file before it holds the whole value.
     pub fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
-        let file = format!("{}/{}", self.path, key);
-        match file_exists(&file)? {
-            true => Err("Key already exists".into()),
-            false => {
-                write_string_to_file(&file, hex_encode(value))?;
-                Ok(())
+        let file = self.file(key)?;
+        let temp_file = format!(
+            "{}/.{key}.{}.{}.tmp",
+            self.path,
+            process::id(),
+            TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+        );
+        let linked = write_file(&temp_file, hex_encode(value).as_bytes())
+            .and_then(|()| hard_link(&temp_file, &file));
+        let _ = remove_file(&temp_file);
+        match linked {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Err(Box::new(KeyExists)),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    /// Watches the directory for the file of `key` to be written, through inotify on
+    /// Linux. The watches of an adapter share a single watcher of the directory, so that
+    /// watching many keys does not use up inotify instances.
+    pub fn watch(&self, key: &str, events: Sender<WatchEvent>) -> Result<(), Box<dyn Error>> {
+        let file = self.file(key)?;
+        let mut watcher = lock(&self.watcher)?;
+        let watcher = match watcher.as_mut() {
+            Some(watcher) => watcher,
+            None => watcher.insert(DirectoryWatcher::new(&self.path)?),
+        };
+        let mut watches = lock(&watcher.watches)?;
+        watches.push(KeyWatch {
+            key: key.to_string(),
+            events,
+            until: Instant::now() + WATCH_TIMEOUT,
+        });
+        // the file may have been written before the watch was set up
+        if file_exists(&file)? {
+            settle(&mut watches, |watched| watched == key);
+        }
+        Ok(())
+    }
+}
+
+/// The watcher of an adapter's directory, and the watches it serves.
+struct DirectoryWatcher {
+    /// Dropping the watcher stops the notifications, and with them the dispatching thread
+    _watcher: RecommendedWatcher,
+    watches: Arc<Mutex<Vec<KeyWatch>>>,
+}
+
+impl core::fmt::Debug for DirectoryWatcher {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DirectoryWatcher").finish_non_exhaustive()
+    }
+}
+
+impl DirectoryWatcher {
+    fn new(path: &str) -> Result<Self, Box<dyn Error>> {
+        let (notifications, received) = channel();
+        let mut watcher = recommended_watcher(notifications)?;
+        watcher.watch(Path::new(path), RecursiveMode::NonRecursive)?;
+        let watches = Arc::<Mutex<Vec<KeyWatch>>>::default();
+        let dispatched = watches.clone();
+        spawn(move || dispatch(&received, &dispatched));
+        Ok(Self {
+            _watcher: watcher,
+            watches,
+        })
+    }
+}
+
+/// A watch of a key, waiting for its file until `until`.
+struct KeyWatch {
+    key: String,
+    events: Sender<WatchEvent>,
+    until: Instant,
+}
+
+/// Reports the files written in the directory to the watches of their keys, and ends the
+/// watches that time out, until the watcher is dropped.
+fn dispatch(received: &Receiver<notify::Result<Event>>, watches: &Mutex<Vec<KeyWatch>>) {
+    loop {
+        let notification = received.recv_timeout(EXPIRY_INTERVAL);
+        let Ok(mut watches) = watches.lock() else {
+            return;
+        };
+        match notification {
+            Ok(Ok(event)) if written(&event.kind) => settle(&mut watches, |key| {
+                event
+                    .paths
+                    .iter()
+                    .any(|path| path.file_name() == Some(OsStr::new(key)))
+            }),
+            Ok(_) | Err(RecvTimeoutError::Timeout) => settle(&mut watches, |_| false),
+            Err(RecvTimeoutError::Disconnected) => {
+                for watch in watches.drain(..) {
+                    let _ = watch.events.send(WatchEvent::Ended(watch.key));
+                }
+                return;
             }
         }
     }
 }
+
+/// Ends the watches of the keys `stored` says were written, and those that timed out.
+fn settle(watches: &mut Vec<KeyWatch>, stored: impl Fn(&str) -> bool) {
+    let now = Instant::now();
+    watches.retain(|watch| {
+        let event = if stored(&watch.key) {
+            WatchEvent::Stored(watch.key.clone())
+        } else if watch.until <= now {
+            WatchEvent::Ended(watch.key.clone())
+        } else {
+            return true;
+        };
+        let _ = watch.events.send(event);
+        false
+    });
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>, Box<dyn Error>> {
+    mutex.lock().map_err(|e| e.to_string().into())
+}
+
+/// Writes `contents` to a new file at `path` and syncs it to disk.
+fn write_file(path: &str, contents: &[u8]) -> std::io::Result<()> {
+    let mut f = OpenOptions::new().write(true).create_new(true).open(path)?;
+    f.write_all(contents)?;
+    f.sync_all()
+}
+
+/// Whether a file event means the file holds its value. `put_checked` links the complete
+/// file in place, so only its creation counts; keys are written once, and changes to
+/// existing files, e.g. of their metadata, are not new values.
+fn written(kind: &EventKind) -> bool {
+    matches!(kind, EventKind::Create(_))
+}
@@ -0,0 +1,116 @@
+//! Moving an agent's identity between machines.
+//!
+//! An identity bundle holds the pid, ciphersuite and signature key pair of an agent, and
+//! optionally the rest of its state including its groups. It is sealed with a passphrase
+//! like an encrypted state file. The public identity, the pid and the public key with its
+//! fingerprint, can be handed out freely, e.g. to administrators pinning agents.
+
+use super::{
+    adapter::check_key_part,
+    envelope::{self, StateKey},
+    error::MySgmError,
+    keys::SignatureKeyPair,
+    migrate,
+    state::MySgmState,
+};
+
+use hex::encode as hex_encode;
+use openmls::{credentials::Credential, versions::ProtocolVersion};
+use openmls_rust_crypto::RustCrypto;
+use openmls_traits::{
+    crypto::OpenMlsCrypto,
+    types::{Ciphersuite, HashType, SignatureScheme},
+};
+use serde_json::{Value, from_str as json_decode, to_string as json_encode, to_value};
+
+const FORMAT: &str = "mysgm-identity";
+const VERSION: u16 = 1;
+
+/// Contents of a sealed identity bundle.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct IdentityBundle {
+    format: String,
+    version: u16,
+    pid: String,
+    ciphersuite: Ciphersuite,
+    mls_version: ProtocolVersion,
+    signature_key_pair: SignatureKeyPair,
+    /// X.509 credential, if the agent does not use a basic one
+    #[serde(default)]
+    credential: Option<Credential>,
+    /// The complete agent state, if exported with groups
+    state: Option<Value>,
+}
+
+/// The part of an identity that can be shared.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct PublicIdentity {
+    pub pid: String,
+    pub ciphersuite: Ciphersuite,
+    pub signature_scheme: SignatureScheme,
+    /// hex-encoded signature public key
+    pub public_key: String,
+    pub fingerprint: String,
+}
+
+/// Hex-encoded SHA-256 hash of a signature public key.
+pub fn fingerprint(public_key: &[u8]) -> Result<String, MySgmError> {
+    let hash = RustCrypto::default()
+        .hash(HashType::Sha2_256, public_key)
+        .map_err(|e| MySgmError::Mls(format!("{e:?}")))?;
+    Ok(hex_encode(hash))
+}
+
+pub fn public_identity(state: &MySgmState) -> Result<PublicIdentity, MySgmError> {
+    let signature_key_pair = state.signature_key_pair();
+    Ok(PublicIdentity {
+        pid: state.my_pid().to_string(),
+        ciphersuite: state.my_ciphersuite(),
+        signature_scheme: signature_key_pair.signature_scheme(),
+        public_key: hex_encode(signature_key_pair.public_key_raw()),
+        fingerprint: fingerprint(signature_key_pair.public_key_raw())?,
+    })
+}
+
+/// Seals the identity of `state` with `key`, along with the whole state if `with_groups`.
+pub fn export(state: &MySgmState, key: &StateKey, with_groups: bool) -> Result<String, MySgmError> {
+    let bundle = IdentityBundle {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        pid: state.my_pid().to_string(),
+        ciphersuite: state.my_ciphersuite(),
+        mls_version: state.mls_version(),
+        signature_key_pair: state.signature_key_pair().clone(),
+        credential: state.credential().cloned(),
+        state: with_groups.then(|| to_value(state)).transpose()?,
+    };
+    envelope::seal(key, &json_encode(&bundle)?)
+}
+
+/// Reconstructs an agent state from a bundle sealed by [`export`]. Without groups, the
+/// state starts out like a new agent's with the exported identity.
+pub fn import(contents: &str, key: &StateKey) -> Result<MySgmState, MySgmError> {
+    let bundle: IdentityBundle = json_decode(&envelope::open_sealed(key, contents)?)?;
+    if bundle.format != FORMAT || bundle.version != VERSION {
+        return Err(MySgmError::State(format!(
+            "Unsupported identity bundle {} version {}",
+            bundle.format, bundle.version
+        )));
+    }
+    check_key_part("Pid", &bundle.pid)?;
+    match bundle.state {
+        Some(state) => migrate::decode_value(state),
+        None => {
+            let mut state = MySgmState::new(
+                bundle.pid,
+                bundle.signature_key_pair,
+                bundle.ciphersuite,
+                bundle.mls_version,
+            );
+            if let Some(credential) = bundle.credential {
+                state.set_credential(credential);
+            }
+            Ok(state)
+        }
+    }
+}
@@ -6,8 +6,10 @@
 //! associated methods and traits.
 
 use hex::encode as hex_encode;
+use openmls_rust_crypto::RustCrypto;
 use openmls_traits::{
     crypto::OpenMlsCrypto,
+    signatures::{Signer, SignerError},
     storage::{CURRENT_VERSION, Entity, Key, traits},
     types::{CryptoError, SignatureScheme},
 };
@@ -177,3 +179,16 @@ impl SignatureKeyPair {
         }
     }
 }
+
+impl Signer for SignatureKeyPair {
+    /// Signs `payload` with the private key, for signing with a key other than the one in
+    /// the agent state, e.g. while rotating it.
+    fn sign(&self, payload: &[u8]) -> Result<Vec<u8>, SignerError> {
+        RustCrypto::default()
+            .sign(self.signature_scheme, payload, &self.private)
+            .map_err(SignerError::CryptoError)
+    }
+    fn signature_scheme(&self) -> SignatureScheme {
+        self.signature_scheme
+    }
+}
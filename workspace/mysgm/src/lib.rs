@@ -0,0 +1,22 @@
+//! Secure group messaging agents built on OpenMLS, sharing state through a key/value store.
+
+pub mod adapter;
+pub mod agent;
+pub mod daemon;
+pub mod envelope;
+pub mod error;
+pub mod file_adapter;
+pub mod identity;
+pub mod keys;
+pub mod metrics;
+pub mod migrate;
+pub mod opendht;
+pub mod persist;
+pub mod provider;
+pub mod rotation;
+pub mod sqlite;
+pub mod state;
+pub mod x509;
+
+pub use agent::Agent;
+pub use error::MySgmError;
@@ -1,47 +1,34 @@
-pub mod file_adapter;
-pub mod keys;
-pub mod metrics;
-pub mod opendht;
-pub mod provider;
-pub mod state;
-
-use file_adapter::FileAdapter;
-use keys::SignatureKeyPair;
-use metrics::{MetricsEvent, log_event, now_ms};
-use opendht::OpenDhtRestAdapter;
-use provider::MySgmProvider;
-use state::MySgmState;
+use mysgm::{
+    Agent, MySgmError,
+    adapter::{self, StorageAdapter},
+    agent::{self, key_package_fingerprint},
+    daemon::{self, DaemonHandler, DaemonResponse},
+    envelope::{self, StateKey},
+    file_adapter::FileAdapter,
+    identity, metrics, migrate,
+    opendht::{self, OpenDhtConfig, OpenDhtRestAdapter},
+    persist::{self, StateBackend},
+    sqlite,
+    state::{GroupPolicy, JoinMode},
+    x509::{self, TrustAnchors},
+};
 
 use clap::{Parser, Subcommand, ValueEnum};
-use core::error::Error;
 use hex::encode as hex_encode;
-use openmls::{
-    credentials::{BasicCredential, Credential, CredentialType, CredentialWithKey},
-    extensions::ExtensionType,
-    framing::{MlsMessageBodyIn, MlsMessageIn, MlsMessageOut, ProcessedMessageContent},
-    group::{
-        GroupId, MergeCommitError, MlsGroup, MlsGroupCreateConfig, MlsGroupJoinConfig,
-        MlsGroupStateError, ProcessMessageError, StagedWelcome,
-    },
-    key_packages::{KeyPackage, key_package_in::KeyPackageIn},
-    prelude::{Capabilities, LeafNodeIndex},
-    treesync::LeafNodeParameters,
-    versions::ProtocolVersion,
-};
-use openmls_rust_crypto::RustCrypto;
-use openmls_traits::{OpenMlsProvider, types::Ciphersuite};
-use serde_json::{from_str as json_decode, to_string as json_encode};
+use openmls_traits::types::Ciphersuite;
+use serde_json::{Value, from_str as json_decode, to_string as json_encode};
 use std::{
-    fs::{read_to_string as read_file_to_string, write as write_string_to_file},
-    io::{BufRead, stdin},
+    fs::{exists as file_exists, read_to_string as read_file_to_string},
+    io::{BufRead, Read, Write, stdin, stdout},
+    process::exit,
+    time::Duration,
 };
-use tls_codec::{Deserialize, Serialize};
 
 /// CLI for secure group messsaging agent
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct CliArgs {
-    /// Path to a JSON file to read (required)
+    /// Path to the agent state: a JSON file, or a SQLite database with --state-backend sqlite
     state_path: String,
     /// Option to reset state
     #[arg(long)]
@@ -49,6 +36,19 @@ struct CliArgs {
     /// Optional identifier to use in generating pid
     #[arg(long, default_value = "agent")]
     pid: String,
+    /// Ciphersuite of a new agent's groups and key packages
+    #[arg(long, value_enum, requires = "reset")]
+    ciphersuite: Option<CiphersuiteArg>,
+    /// PEM certificate chain, leaf first, giving a new agent an X.509 credential; the pid
+    /// is the common name of the certificate subject
+    #[arg(long, requires_all = ["reset", "x509_key"])]
+    x509_cert: Option<String>,
+    /// PKCS#8 PEM private key of the --x509-cert certificate
+    #[arg(long, requires = "x509_cert")]
+    x509_key: Option<String>,
+    /// PEM bundle of the CAs that X.509 credentials of peers are validated against
+    #[arg(long, env = "MYSGM_X509_TRUST_ANCHORS")]
+    x509_trust_anchors: Option<String>,
     /// Command to execute
     #[command(subcommand)]
     main_command: MainCommands,
@@ -58,27 +58,117 @@ struct CliArgs {
     /// Directory to use with the file adapter
     #[arg(long, default_value = "/tmp")]
     file_path: String,
+    /// Namespace prefixed to every adapter key, so that deployments sharing a store do not
+    /// collide; it is stored in the state at --reset, and other namespaces are refused later
+    #[arg(long, env = "MYSGM_NAMESPACE")]
+    namespace: Option<String>,
     /// DHT REST proxy host
     #[arg(long, default_value = "localhost")]
     dht_host: String,
     /// DHT REST proxy port
     #[arg(long, default_value_t = 8000)]
     dht_port: u16,
+    /// URL of the DHT REST proxy, http:// or https://, in place of --dht-host and --dht-port
+    #[arg(long, env = "MYSGM_DHT_URL", conflicts_with_all = ["dht_host", "dht_port"])]
+    dht_url: Option<String>,
+    /// PEM bundle of the CAs the https DHT proxy's certificate is validated against,
+    /// instead of the built-in roots
+    #[arg(long)]
+    dht_ca_bundle: Option<String>,
+    /// PEM file with the client certificate and private key presented to the https DHT proxy
+    #[arg(long)]
+    dht_client_cert: Option<String>,
+    /// File holding the bearer token sent to the DHT proxy; alternatively, set MYSGM_DHT_TOKEN
+    #[arg(long, env = "MYSGM_DHT_TOKEN_FILE")]
+    dht_token_file: Option<String>,
+    /// Milliseconds allowed to connect to the DHT proxy
+    #[arg(long, default_value_t = 2000)]
+    dht_connect_timeout_ms: u64,
+    /// Milliseconds allowed for a whole request to the DHT proxy
+    #[arg(long, default_value_t = 10000)]
+    dht_timeout_ms: u64,
+    /// Attempts per DHT request before giving up; server errors, failed connections and
+    /// timeouts are retried
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    dht_max_attempts: u32,
+    /// Longest delay in milliseconds before the first DHT retry, doubled for each further one
+    #[arg(long, default_value_t = 100)]
+    dht_backoff_ms: u64,
     /// File path for structured JSON metrics logs (JSONL)
     #[arg(long, default_value = "mysgm-metrics.log")]
     log_file: String,
+    /// Re-issue a commit that lost to a concurrent commit in the new epoch instead of failing
+    #[arg(long)]
+    reissue: bool,
+    /// How the agent state is stored
+    #[arg(long, value_enum, default_value = "json")]
+    state_backend: StateBackendKind,
+    /// File holding the passphrase the state file is encrypted with; alternatively, set
+    /// MYSGM_STATE_PASSPHRASE
+    #[arg(long, env = "MYSGM_STATE_KEY_FILE")]
+    state_key_file: Option<String>,
+    /// Accept a plaintext state file although a state key is given, sealing it on the next save
+    #[arg(long)]
+    seal_plaintext_state: bool,
+    /// Number of previous versions of the state file to keep as `<state_path>.<n>`
+    #[arg(long, default_value_t = persist::DEFAULT_BACKUPS)]
+    backups: usize,
+    /// Seconds the key packages published by Advertise are valid for
+    #[arg(long, default_value_t = agent::DEFAULT_KEY_PACKAGE_LIFETIME)]
+    key_package_lifetime: u64,
+    /// Unix domain socket of a running daemon; commands are sent to it instead of being run locally
+    #[arg(long)]
+    socket: Option<String>,
 }
 
-#[derive(Debug, Subcommand)]
+#[derive(Debug, Subcommand, serde::Serialize, serde::Deserialize)]
 enum MainCommands {
+    /// Keep running, syncing on an interval and as soon as a watched key is stored, and
+    /// serving commands on --socket
+    Daemon {
+        /// Milliseconds between syncs with the storage adapter, also renewing the watches
+        #[arg(long, default_value_t = 5000)]
+        interval_ms: u64,
+    },
     Me {},
-    Agents {},
+    Agents {
+        /// Also print the pinned key fingerprint of each pid and any quarantined key
+        #[arg(long)]
+        verbose: bool,
+        /// Only print the pids whose key package has expired, with its expiry time
+        #[arg(long, conflicts_with = "verbose")]
+        stale: bool,
+    },
     Groups {},
-    Advertise {},
+    Advertise {
+        /// Number of single-use key packages to publish besides the last-resort one; syncs
+        /// keep that many published
+        #[arg(long, default_value_t = 0)]
+        count: usize,
+    },
     CreateGroup {
         /// Optional gid for the new group
         #[arg(long, default_value = "group")]
         gid: String,
+        /// Publish the GroupInfo so that agents can join by external commit
+        #[arg(long)]
+        external_join: bool,
+    },
+    /// Join a group that publishes its GroupInfo by external commit
+    JoinExternal {
+        /// Full gid of the group to join
+        #[arg(long)]
+        gid: String,
+    },
+    /// List the invitations staged by the join policy, or act on one
+    Invites {
+        #[command(subcommand)]
+        invites_command: Option<InvitesCommands>,
+    },
+    /// Change which signature key is trusted for a pid
+    Trust {
+        #[command(subcommand)]
+        trust_command: TrustCommands,
     },
     Group {
         /// gid for group commands
@@ -87,9 +177,110 @@ enum MainCommands {
         #[command(subcommand)]
         group_command: GroupCommands,
     },
+    /// Inspect the state file; runs locally, without syncing
+    State {
+        #[command(subcommand)]
+        state_command: StateCommands,
+    },
+    /// Export, import or rotate the agent's identity; only rotating syncs
+    Identity {
+        #[command(subcommand)]
+        identity_command: IdentityCommands,
+    },
+}
+
+#[derive(Debug, Subcommand, serde::Serialize, serde::Deserialize)]
+enum InvitesCommands {
+    /// Join the group of a staged invitation
+    Accept { id: u64 },
+    /// Forget a staged invitation
+    Reject { id: u64 },
+    /// Show the join policy, or change it
+    Policy {
+        /// What to do with welcomes from inviters that are not allowlisted
+        #[arg(long, value_enum)]
+        mode: Option<JoinModeArg>,
+        /// Add inviters whose welcomes are always accepted
+        #[arg(long)]
+        allow: Vec<String>,
+        /// Remove inviters from the allowlist
+        #[arg(long)]
+        disallow: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand, serde::Serialize, serde::Deserialize)]
+enum TrustCommands {
+    /// Pin a key for the pid, replacing the key pinned so far
+    Set {
+        pid: String,
+        /// Fingerprint of the key, as printed by `Agents --verbose` or `Identity Export --public`
+        fingerprint: String,
+    },
+    /// Unpin a key of the pid and refuse it from now on
+    Revoke {
+        pid: String,
+        /// Fingerprint of the pinned or quarantined key
+        fingerprint: String,
+    },
 }
 
-#[derive(Debug, Subcommand)]
+#[derive(Debug, Subcommand, serde::Serialize, serde::Deserialize)]
+enum IdentityCommands {
+    /// Export the identity in a bundle sealed with a passphrase, or only its public part
+    Export {
+        /// File to write to; standard output if absent
+        #[arg(long)]
+        out: Option<String>,
+        /// File holding the passphrase the bundle is sealed with
+        #[arg(long, required_unless_present = "public")]
+        key_file: Option<String>,
+        /// Include the groups and the rest of the agent state
+        #[arg(long, conflicts_with = "public")]
+        with_groups: bool,
+        /// Only export the pid, public key and its fingerprint, unencrypted
+        #[arg(long)]
+        public: bool,
+    },
+    /// Create the agent state at the state path from an exported identity
+    Import {
+        /// Bundle written by Identity Export
+        bundle: String,
+        /// File holding the passphrase the bundle is sealed with
+        #[arg(long)]
+        key_file: String,
+    },
+    /// Replace the signature key, updating our leaf in every group
+    Rotate {},
+}
+
+#[derive(Debug, Subcommand, serde::Serialize, serde::Deserialize)]
+enum StateCommands {
+    /// Check the state file and its backups, and report which one would be loaded
+    Verify {},
+    /// Re-encrypt the state file and its backups with a new passphrase, or decrypt them
+    Rekey {
+        /// File holding the new passphrase
+        #[arg(long, required_unless_present = "decrypt")]
+        new_key_file: Option<String>,
+        /// Write the state in plain JSON instead
+        #[arg(long, conflicts_with = "new_key_file")]
+        decrypt: bool,
+    },
+    /// Migrate the state to the schema version of this build
+    Migrate {
+        /// Only report what would change
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Create the SQLite state database at the state path from a JSON state file
+    ImportJson {
+        /// JSON state file to import, decrypted with the state key if it is sealed
+        json_path: String,
+    },
+}
+
+#[derive(Debug, Subcommand, serde::Serialize, serde::Deserialize)]
 enum GroupCommands {
     ExportSecret {
         /// Label for the exported secret
@@ -104,644 +295,808 @@ enum GroupCommands {
         pids: Vec<String>,
     },
     Remove {
-        /// Leaf indexes to remove; if empty, read from stdin one per line.
+        /// Leaf indexes to remove; if empty and no --pid is given, read from stdin one per line.
         indexes: Vec<u32>,
+        /// Agent IDs (pids) to remove instead of leaf indexes
+        #[arg(long = "pid", conflicts_with = "indexes")]
+        pids: Vec<String>,
+    },
+    /// Ask the other members to remove us from the group
+    Leave {},
+    /// Publish a proposal for any member to commit later
+    Propose {
+        #[command(subcommand)]
+        proposal: ProposalCommands,
+    },
+    /// Commit all pending proposals
+    Commit {},
+    /// Show the local policy of the group, or change it
+    Policy {
+        /// Publish the GroupInfo after our commits so that agents can join by external commit
+        #[arg(long)]
+        external_join: Option<bool>,
     },
     Members {},
     Update {},
+    Send {
+        /// Message to encrypt and send; if absent, read from stdin.
+        message: Option<String>,
+        /// Message read from stdin, which need not be UTF-8
+        #[arg(skip)]
+        #[serde(default)]
+        stdin_message: Option<Vec<u8>>,
+    },
+    Read {},
 }
 
-#[derive(Clone, Debug, ValueEnum)]
-enum AdapterKind {
-    File,
-    Dht,
+#[derive(Debug, Subcommand, serde::Serialize, serde::Deserialize)]
+enum ProposalCommands {
+    Add {
+        /// Agent IDs (pids) to propose adding
+        #[arg(required = true)]
+        pids: Vec<String>,
+    },
+    Remove {
+        /// Leaf indexes to propose removing
+        #[arg(required_unless_present = "pids")]
+        indexes: Vec<u32>,
+        /// Agent IDs (pids) to propose removing instead of leaf indexes
+        #[arg(long = "pid", conflicts_with = "indexes")]
+        pids: Vec<String>,
+    },
+    Update {},
 }
 
-trait StorageAdapter {
-    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>>;
-    fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>>;
+/// Ciphersuites supported by the RustCrypto provider
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum CiphersuiteArg {
+    /// MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519
+    X25519Aes128gcmEd25519,
+    /// MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519
+    X25519Chacha20poly1305Ed25519,
+    /// MLS_128_DHKEMP256_AES128GCM_SHA256_P256
+    P256Aes128gcmP256,
 }
 
-impl StorageAdapter for FileAdapter {
-    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
-        FileAdapter::get(self, key)
-    }
-
-    fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
-        FileAdapter::put_checked(self, key, value)
+impl From<CiphersuiteArg> for Ciphersuite {
+    fn from(arg: CiphersuiteArg) -> Self {
+        match arg {
+            CiphersuiteArg::X25519Aes128gcmEd25519 => {
+                Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519
+            }
+            CiphersuiteArg::X25519Chacha20poly1305Ed25519 => {
+                Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519
+            }
+            CiphersuiteArg::P256Aes128gcmP256 => {
+                Ciphersuite::MLS_128_DHKEMP256_AES128GCM_SHA256_P256
+            }
+        }
     }
 }
 
-impl StorageAdapter for OpenDhtRestAdapter {
-    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
-        OpenDhtRestAdapter::get(self, key)
-    }
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum StateBackendKind {
+    Json,
+    Sqlite,
+}
 
-    fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
-        OpenDhtRestAdapter::put_checked(self, key, value)
+impl From<StateBackendKind> for StateBackend {
+    fn from(kind: StateBackendKind) -> Self {
+        match kind {
+            StateBackendKind::Json => StateBackend::Json,
+            StateBackendKind::Sqlite => StateBackend::Sqlite,
+        }
     }
 }
 
-
-pub fn key_package_key(index: u64) -> String {
-    format!("kp{index}")
-}
-pub fn welcome_message_key(index: u64) -> String {
-    format!("wm{index}")
+#[derive(Clone, Copy, Debug, ValueEnum, serde::Serialize, serde::Deserialize)]
+enum JoinModeArg {
+    /// Join every group we are invited to
+    AcceptAll,
+    /// Drop welcomes from inviters that are not allowlisted
+    Allowlist,
+    /// Stage welcomes from inviters that are not allowlisted until `Invites accept`
+    Stage,
 }
 
-pub fn commit_key(group: &MlsGroup, provider: &MySgmProvider) -> Result<String, Box<dyn Error>> {
-    Ok(format!(
-        "cm{}",
-        hex_encode(group.export_secret(provider, "post_commit", &[], 32)?)
-    ))
+impl From<JoinModeArg> for JoinMode {
+    fn from(mode: JoinModeArg) -> Self {
+        match mode {
+            JoinModeArg::AcceptAll => JoinMode::AcceptAll,
+            JoinModeArg::Allowlist => JoinMode::Allowlist,
+            JoinModeArg::Stage => JoinMode::Stage,
+        }
+    }
 }
 
-fn main() {
-    pretty_env_logger::init();
-    // CLI args
-    let args = CliArgs::parse();
-    metrics::init_metrics_logger(&args.log_file).unwrap();
-    log::info!("Command-line arguments: {args:?}");
-
-    let adapter: Box<dyn StorageAdapter> = match args.adapter {
-    AdapterKind::File => Box::new(FileAdapter::new(&args.file_path)),
-    AdapterKind::Dht => Box::new(OpenDhtRestAdapter::new(&args.dht_host, args.dht_port)),
-    };
-    log::info!("Storage adapter: {}", match args.adapter {
-        AdapterKind::File => "file",
-        AdapterKind::Dht => "dht",
-    });
+#[derive(Clone, Debug, ValueEnum)]
+enum AdapterKind {
+    File,
+    Dht,
+}
 
-    // crypto
-    let crypto: RustCrypto = Default::default();
-    // state
-    log::info!("Path to agent state: {}", args.state_path);
-    log::info!("Reset state? {}", args.reset);
-    let state = if args.reset {
-        log::warn!("Resetting state");
-        // ciphersuite
-        let ciphersuite = Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519;
-        // signature key pair
-        let signature_key_pair =
-            SignatureKeyPair::from_crypto(&crypto, ciphersuite.into()).unwrap();
-        // new provider; done
-        let pid_transformed = format!(
-            "{}_{}",
-            &args.pid,
-            hex_encode(signature_key_pair.public_key_raw())
-                .chars()
-                .take(3)
-                .collect::<String>()
-        );
-        MySgmState::new(
-            pid_transformed,
-            signature_key_pair,
-            ciphersuite,
-            ProtocolVersion::Mls10,
-        )
-    } else {
-        log::debug!("Attempting to load state from file");
-        json_decode(&read_file_to_string(&args.state_path).unwrap()).unwrap()
-    };
-    log::info!("State: {state:?}");
-    // credential
-    let cred_with_key = CredentialWithKey {
-        credential: BasicCredential::new(state.my_pid().as_bytes().to_vec()).into(),
-        signature_key: state.signature_key_pair().public_key_raw().into(),
+/// Fills in arguments that the command line left for stdin, so that commands can be
+/// executed without a terminal (e.g. by a daemon on behalf of a client).
+fn read_stdin_arguments(command: &mut MainCommands) -> Result<(), MySgmError> {
+    let MainCommands::Group { group_command, .. } = command else {
+        return Ok(());
     };
-    // capabilities
-    let capabilities = Capabilities::new(
-        None,
-        None,
-        Some(&[ExtensionType::LastResort]),
-        None,
-        Some(&[CredentialType::Basic]),
-    );
-    // config
-    let group_config = MlsGroupCreateConfig::builder()
-        .ciphersuite(state.my_ciphersuite())
-        .use_ratchet_tree_extension(true)
-        .capabilities(capabilities.clone())
-        .build();
-    // provider
-    let mut provider = MySgmProvider::new(state, crypto);
-    // download key packages
-    loop {
-        let key = key_package_key(provider.state().key_package_counter());
-        log::info!("Key package key to get: {key}");
-        match adapter.get(&key) {
-            Ok(Some(kp_bytes)) => {
-                provider.state_mut().increment_key_package_counter();
-                log::info!("Got key package bytes: {}", hex_encode(&kp_bytes));
-                match MlsMessageIn::tls_deserialize_exact(kp_bytes)
-                    .unwrap()
-                    .extract()
-                {
-                    MlsMessageBodyIn::KeyPackage(kp_in) => {
-                        let kp = kp_in
-                            .validate(provider.crypto(), provider.state().mls_version())
-                            .unwrap();
-                        log::info!("Processed key package: {kp:?}");
-                        let cred =
-                            BasicCredential::try_from(kp.leaf_node().credential().clone()).unwrap();
-                        let pid = String::from_utf8_lossy(cred.identity()).to_string();
-                        log::info!("pid of key package: {pid}");
-                        provider.state_mut().set_key_package(&pid, kp);
+    match group_command {
+        GroupCommands::Add { pids } if pids.is_empty() => {
+            let handle = stdin().lock();
+            log::debug!("Reading lines from stdin as agents to add");
+            for line in handle.lines() {
+                match line {
+                    Ok(l) => {
+                        pids.push(l);
                     }
-                    _ => {
-                        panic!("Expected KeyPackage message");
+                    Err(e) => {
+                        log::error!("Error reading line: {e}");
+                        break;
                     }
                 }
             }
-            Ok(None) => {
-                log::info!("No more key packages to download");
-                break;
-            }
-            Err(e) => {
-                panic!("Failed to get key package: {e}");
-            }
         }
-    }
-    // download welcome messages
-    loop {
-        let welcome_index = provider.state().welcome_counter();
-        let key = welcome_message_key(welcome_index);
-        log::info!("Welcome message key to get: {key}");
-        let started = now_ms();
-        match adapter.get(&key) {
-            Ok(Some(wm_bytes)) => {
-                let mut download_event = MetricsEvent::new("welcome_download", started, now_ms());
-                download_event.node_id = Some(provider.state().my_pid().to_string());
-                download_event.welcome_index = Some(welcome_index);
-                download_event.dht_key = Some(key.clone());
-                download_event.payload_bytes = Some(wm_bytes.len());
-                log_event(&download_event);
-                provider.state_mut().increment_welcome_counter();
-                log::info!("Got welcome message bytes: {}", hex_encode(&wm_bytes));
-                match MlsMessageIn::tls_deserialize_exact(wm_bytes)
-                    .unwrap()
-                    .extract()
-                {
-                    MlsMessageBodyIn::Welcome(welcome) => {
-                        log::info!("Processed welcome message: {welcome:?}");
-                        match StagedWelcome::new_from_welcome(
-                            &provider,
-                            group_config.join_config(),
-                            welcome,
-                            None,
-                        ) {
-                            Ok(staged_welcome) => {
-                                let group = staged_welcome.into_group(&provider).unwrap();
-                                let gid = String::from_utf8_lossy(group.group_id().as_slice())
-                                    .to_string();
-                                log::info!("Group with gid: {gid}");
-                                provider.state_mut().add_gid(gid.clone());
-                                let mut process_event =
-                                    MetricsEvent::new("welcome_process", started, now_ms());
-                                process_event.node_id = Some(provider.state().my_pid().to_string());
-                                process_event.gid = Some(gid);
-                                process_event.welcome_index = Some(welcome_index);
-                                process_event.welcome_processed = Some(true);
-                                log_event(&process_event);
-                            }
-                            Err(e) => {
-                                log::warn!("Failed to process welcome: {e}");
-                                let mut process_event =
-                                    MetricsEvent::new("welcome_process", started, now_ms());
-                                process_event.node_id = Some(provider.state().my_pid().to_string());
-                                process_event.welcome_index = Some(welcome_index);
-                                process_event.welcome_processed = Some(false);
-                                process_event.result = "error".to_string();
-                                process_event.error = Some(e.to_string());
-                                log_event(&process_event);
-                            }
-                        }
+        GroupCommands::Remove { indexes, pids } if indexes.is_empty() && pids.is_empty() => {
+            let handle = stdin().lock();
+            log::debug!("Reading lines from stdin as indexes to remove");
+            for line in handle.lines() {
+                match line {
+                    Ok(l) => {
+                        log::info!("index: {l}");
+                        indexes.push(l.parse::<u32>().map_err(|e| {
+                            MySgmError::InvalidArgument(format!("Invalid index {l}: {e}"))
+                        })?);
                     }
-                    _ => {
-                        panic!("Not a welcome message");
+                    Err(e) => {
+                        log::error!("Error reading line: {e}");
+                        break;
                     }
                 }
             }
-            Ok(None) => {
-                log::info!("No more welcome messages to download");
-                break;
+        }
+        GroupCommands::Send {
+            message: None,
+            stdin_message,
+        } if stdin_message.is_none() => {
+            log::debug!("Reading message from stdin");
+            let mut buf = Vec::new();
+            stdin().lock().read_to_end(&mut buf)?;
+            *stdin_message = Some(buf);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Executes a single command, writing its output to `out`.
+fn execute(
+    agent: &mut Agent,
+    command: &MainCommands,
+    out: &mut dyn Write,
+) -> Result<(), MySgmError> {
+    log::info!("Command to process: {command:?}");
+    match command {
+        MainCommands::Daemon { .. } => {
+            return Err(MySgmError::InvalidArgument(
+                "Already running as a daemon".to_string(),
+            ));
+        }
+        MainCommands::Identity {
+            identity_command: IdentityCommands::Rotate {},
+        } => {
+            for (gid, e) in agent.rotate_signature_key()? {
+                writeln!(out, "failed {gid}: {e}")?;
             }
-            Err(e) => {
-                panic!("Failed to get welcome message: {e}");
+            let public_key = agent.state().signature_key_pair().public_key_raw();
+            writeln!(out, "{}", identity::fingerprint(public_key)?)?;
+        }
+        MainCommands::State { .. } | MainCommands::Identity { .. } => {
+            return Err(MySgmError::InvalidArgument(
+                "State and Identity commands are not run by the agent".to_string(),
+            ));
+        }
+        MainCommands::Me {} => {
+            writeln!(out, "{}", agent.state().my_pid())?;
+        }
+        MainCommands::Agents { stale: true, .. } => {
+            for (pid, not_after) in agent.expired_key_packages() {
+                writeln!(out, "{pid} {not_after}")?;
             }
         }
-    }
-    // download commits
-    for gid in provider.state().gids() {
-        let mut group = MlsGroup::load(provider.storage(), &GroupId::from_slice(gid.as_bytes()))
-            .unwrap()
-            .unwrap();
-        loop {
-            let key = match commit_key(&group, &provider) {
-                Ok(k) => k,
-                Err(e) if e.to_string().contains("evict") => {
-                    log::warn!("Evicted from group, stopping commit download for gid: {gid}");
-                    group.delete(provider.storage()).unwrap();
-                    provider.state_mut().remove_gid(&gid);
-                    break;
-                }
-                Err(e) => {
-                    log::warn!("Failed to merge commit: {e}");
-                    break;
+        MainCommands::Agents { verbose: false, .. } => {
+            for pid in agent.state().pids() {
+                writeln!(out, "{pid}")?;
+            }
+        }
+        MainCommands::Agents { verbose: true, .. } => {
+            let state = agent.state();
+            let mut pids = state.pids();
+            pids.extend(state.pinned_pids());
+            pids.extend(state.quarantined_pids());
+            pids.sort();
+            pids.dedup();
+            for pid in pids {
+                let pinned = agent.pinned_fingerprint(&pid)?;
+                write!(out, "{pid} {}", pinned.as_deref().unwrap_or("-"))?;
+                if let Some(kp) = state.quarantined(&pid) {
+                    write!(out, " quarantined {}", key_package_fingerprint(kp)?)?;
                 }
+                writeln!(out)?;
+            }
+        }
+        MainCommands::Groups {} => {
+            for gid in agent.state().gids() {
+                writeln!(out, "{gid}")?;
+            }
+        }
+        MainCommands::CreateGroup { gid, external_join } => {
+            let policy = GroupPolicy {
+                external_join: *external_join,
             };
-            log::info!("Commit message key to get: {key}");
-            let started = now_ms();
-            match adapter.get(&key) {
-                Ok(Some(cm_bytes)) => {
-                    let mut download_event = MetricsEvent::new("commit_download", started, now_ms());
-                    download_event.node_id = Some(provider.state().my_pid().to_string());
-                    download_event.gid = Some(gid.clone());
-                    download_event.commit_key = Some(key.clone());
-                    download_event.payload_bytes = Some(cm_bytes.len());
-                    log_event(&download_event);
-                    log::info!("Got commit message bytes: {}", hex_encode(&cm_bytes));
-                    let proto_msg = MlsMessageIn::tls_deserialize_exact(cm_bytes)
-                        .unwrap()
-                        .try_into_protocol_message()
-                        .unwrap();
-                    match group.process_message(&provider, proto_msg) {
-                        Ok(processed_message) => match processed_message.into_content() {
-                            ProcessedMessageContent::StagedCommitMessage(commit_box) => {
-                                match group.merge_staged_commit(&provider, *commit_box) {
-                                    Ok(_) => {
-                                        log::info!("Merged commit into group state for gid: {gid}");
-                                        let mut merge_event =
-                                            MetricsEvent::new("commit_merge", started, now_ms());
-                                        merge_event.node_id =
-                                            Some(provider.state().my_pid().to_string());
-                                        merge_event.gid = Some(gid.clone());
-                                        merge_event.commit_key = Some(key.clone());
-                                        merge_event.commit_merged = Some(true);
-                                        log_event(&merge_event);
-                                    }
-                                    Err(e) if e.to_string().contains("UseAfterEviction") => {
-                                        log::warn!(
-                                            "Evicted from group, stopping commit download for gid: {gid}"
-                                        );
-                                        provider.state_mut().remove_gid(&gid);
-                                        break;
-                                    }
-                                    Err(e) => {
-                                        log::warn!("Failed to merge commit: {e}");
-                                        let mut merge_event =
-                                            MetricsEvent::new("commit_merge", started, now_ms());
-                                        merge_event.node_id =
-                                            Some(provider.state().my_pid().to_string());
-                                        merge_event.gid = Some(gid.clone());
-                                        merge_event.commit_key = Some(key.clone());
-                                        merge_event.commit_merged = Some(false);
-                                        merge_event.result = "error".to_string();
-                                        merge_event.error = Some(e.to_string());
-                                        log_event(&merge_event);
-                                        break;
-                                    }
-                                }
-                            }
-                            _ => panic!("Not a commit message"),
-                        },
-                        Err(e) => {
-                            log::warn!("Failed to process commit message: {e}");
-                            let mut merge_event =
-                                MetricsEvent::new("commit_merge", started, now_ms());
-                            merge_event.node_id = Some(provider.state().my_pid().to_string());
-                            merge_event.gid = Some(gid.clone());
-                            merge_event.commit_key = Some(key.clone());
-                            merge_event.commit_merged = Some(false);
-                            merge_event.result = "error".to_string();
-                            merge_event.error = Some(e.to_string());
-                            log_event(&merge_event);
-                            break;
+            writeln!(out, "{}", agent.create_group(gid, policy)?)?;
+        }
+        MainCommands::JoinExternal { gid } => {
+            agent.join_external(gid)?;
+        }
+        MainCommands::Advertise { count } => {
+            agent.advertise(*count)?;
+        }
+        MainCommands::Invites {
+            invites_command: None,
+        } => {
+            for invite in agent.state().invites() {
+                writeln!(
+                    out,
+                    "{} {} {} {}",
+                    invite.id,
+                    invite.gid,
+                    invite.inviter,
+                    invite.members.join(",")
+                )?;
+            }
+        }
+        MainCommands::Invites {
+            invites_command: Some(invites_command),
+        } => match invites_command {
+            InvitesCommands::Accept { id } => {
+                writeln!(out, "{}", agent.accept_invite(*id)?)?;
+            }
+            InvitesCommands::Reject { id } => {
+                agent.reject_invite(*id)?;
+            }
+            InvitesCommands::Policy {
+                mode,
+                allow,
+                disallow,
+            } => {
+                let mut policy = agent.state().join_policy().clone();
+                if mode.is_none() && allow.is_empty() && disallow.is_empty() {
+                    let mode = match policy.mode {
+                        JoinMode::AcceptAll => "accept-all",
+                        JoinMode::Allowlist => "allowlist",
+                        JoinMode::Stage => "stage",
+                    };
+                    writeln!(out, "mode {mode}")?;
+                    writeln!(out, "allowlist {}", policy.allowlist.join(","))?;
+                } else {
+                    if let Some(mode) = mode {
+                        policy.mode = (*mode).into();
+                    }
+                    policy.allowlist.retain(|pid| !disallow.contains(pid));
+                    for pid in allow {
+                        if !policy.allowlist.contains(pid) {
+                            policy.allowlist.push(pid.clone());
                         }
                     }
+                    agent.set_join_policy(policy);
                 }
-                Ok(None) => {
-                    log::info!("No more commit messages to download for gid: {gid}");
-                    break;
+            }
+        },
+        MainCommands::Trust { trust_command } => match trust_command {
+            TrustCommands::Set { pid, fingerprint } => {
+                agent.trust_set(pid, fingerprint)?;
+            }
+            TrustCommands::Revoke { pid, fingerprint } => {
+                agent.trust_revoke(pid, fingerprint)?;
+            }
+        },
+        MainCommands::Group { gid, group_command } => match group_command {
+            GroupCommands::ExportSecret { label, length } => {
+                writeln!(
+                    out,
+                    "{}",
+                    hex_encode(agent.export_secret(gid, label, *length)?)
+                )?;
+            }
+            GroupCommands::Members {} => {
+                for (index, pid) in agent.members(gid)? {
+                    writeln!(out, "{index} {pid}")?;
                 }
-                Err(e) => {
-                    panic!("Failed to get commit message: {e}");
+            }
+            GroupCommands::Remove { pids, .. } if !pids.is_empty() => {
+                agent.remove_members_by_pid(gid, pids)?;
+            }
+            GroupCommands::Remove { indexes, .. } => {
+                agent.remove_members(gid, indexes)?;
+            }
+            GroupCommands::Leave {} => {
+                agent.leave_group(gid)?;
+            }
+            GroupCommands::Propose { proposal } => match proposal {
+                ProposalCommands::Add { pids } => {
+                    agent.propose_add(gid, pids)?;
+                }
+                ProposalCommands::Remove { pids, .. } if !pids.is_empty() => {
+                    agent.propose_remove_by_pid(gid, pids)?;
+                }
+                ProposalCommands::Remove { indexes, .. } => {
+                    agent.propose_remove(gid, indexes)?;
+                }
+                ProposalCommands::Update {} => {
+                    agent.propose_self_update(gid)?;
+                }
+            },
+            GroupCommands::Commit {} => {
+                agent.commit_pending_proposals(gid)?;
+            }
+            GroupCommands::Policy { external_join } => {
+                let mut policy = agent.group_policy(gid)?;
+                match external_join {
+                    Some(external_join) => {
+                        policy.external_join = *external_join;
+                        agent.set_group_policy(gid, policy)?;
+                    }
+                    None => writeln!(out, "external_join {}", policy.external_join)?,
+                }
+            }
+            GroupCommands::Add { pids } => {
+                agent.add_members(gid, pids)?;
+            }
+            GroupCommands::Update {} => {
+                agent.self_update(gid)?;
+            }
+            GroupCommands::Send {
+                message,
+                stdin_message,
+            } => {
+                let payload = match (message, stdin_message) {
+                    (Some(message), _) => message.as_bytes(),
+                    (None, Some(stdin_message)) => stdin_message.as_slice(),
+                    (None, None) => &[],
+                };
+                agent.send_message(gid, payload)?;
+            }
+            GroupCommands::Read {} => {
+                for message in agent.read_messages(gid)? {
+                    writeln!(
+                        out,
+                        "{} {} {}",
+                        message.epoch,
+                        message.sender,
+                        String::from_utf8_lossy(&message.payload)
+                    )?;
                 }
             }
+        },
+    }
+    Ok(())
+}
+
+/// In-memory agent served by `mysgm daemon`.
+struct DaemonAgent {
+    agent: Agent,
+    state_path: String,
+    saved_state: String,
+}
+
+impl DaemonAgent {
+    /// Writes the state to disk if it changed since the last write.
+    fn persist(&mut self) -> Result<(), MySgmError> {
+        let state_json = json_encode(self.agent.state())?;
+        if state_json != self.saved_state {
+            self.saved_state = self.agent.save_state(&self.state_path)?;
         }
+        Ok(())
     }
-    // execute command
-    log::info!("Command to process: {:?}", args.main_command);
-    match &args.main_command {
-        MainCommands::Me {} => {
-            println!("{}", provider.state().my_pid());
+    /// Recovers from an operation that failed midway. If it stored nothing through the
+    /// adapter since `writes`, its in-memory changes are dropped by going back to the last
+    /// state written to disk. Otherwise the state is kept and written, as going back
+    /// would, e.g., leave the agent behind a commit it published and merged.
+    fn recover(&mut self, writes: u64) {
+        if self.agent.writes() == writes {
+            self.restore();
+            return;
         }
-        MainCommands::Agents {} => {
-            for pid in provider.state().pids() {
-                println!("{pid}");
-            }
+        log::warn!("Keeping the state of a failed operation that already published");
+        if let Err(e) = self.persist() {
+            log::error!("Failed to write state: {e}");
         }
-        MainCommands::Groups {} => {
-            for gid in provider.state().gids() {
-                println!("{gid}");
-            }
-        }
-        MainCommands::CreateGroup { gid } => {
-            let started = now_ms();
-            let gid_transformed = format!(
-                "{}_{}",
-                gid,
-                hex_encode(provider.state().signature_key_pair().public_key_raw())
-                    .chars()
-                    .take(3)
-                    .collect::<String>()
-            );
-            match provider.state().gids().contains(&gid_transformed) {
-                true => {
-                    panic!("Group already exists");
-                }
-                false => {
-                    let _ = MlsGroup::new_with_group_id(
-                        &provider,
-                        &provider,
-                        &group_config,
-                        GroupId::from_slice(gid_transformed.as_bytes()),
-                        cred_with_key.clone(),
-                    )
-                    .unwrap();
-                    provider.state_mut().add_gid(gid_transformed.clone());
-                    let mut event = MetricsEvent::new("group_create", started, now_ms());
-                    event.node_id = Some(provider.state().my_pid().to_string());
-                    event.gid = Some(gid_transformed.clone());
-                    event.members_before = Some(0);
-                    event.members_after = Some(1);
-                    log_event(&event);
-                    println!("{gid_transformed}");
-                }
+    }
+    /// Drops in-memory changes by going back to the last state written to disk.
+    fn restore(&mut self) {
+        log::warn!("Restoring state from {}", self.state_path);
+        match Agent::load_state(
+            &self.state_path,
+            self.agent.state_backend(),
+            self.agent.state_key(),
+        ) {
+            Ok(state) => self.agent.replace_state(state),
+            Err(e) => log::error!("Failed to restore state: {e}"),
+        }
+    }
+}
+
+impl DaemonHandler<MainCommands> for DaemonAgent {
+    fn tick(&mut self) {
+        let writes = self.agent.writes();
+        if let Err(e) = self.agent.sync().and_then(|()| self.persist()) {
+            log::error!("Sync failed: {e}");
+            self.recover(writes);
+        }
+    }
+    fn wake(&mut self) -> bool {
+        self.agent.take_notifications()
+    }
+    fn request(&mut self, command: MainCommands) -> DaemonResponse {
+        let mut out = Vec::new();
+        let writes = self.agent.writes();
+        match execute(&mut self.agent, &command, &mut out).and_then(|()| self.persist()) {
+            Ok(()) => DaemonResponse::ok(String::from_utf8_lossy(&out).to_string()),
+            Err(e) => {
+                log::error!("Command failed: {e}");
+                self.recover(writes);
+                DaemonResponse::error(&e)
             }
         }
-        MainCommands::Advertise {} => {
-            let started = now_ms();
-            let key_package_bundle = KeyPackage::builder()
-                .leaf_node_capabilities(capabilities.clone())
-                .mark_as_last_resort()
-                .build(
-                    provider.state().my_ciphersuite(),
-                    &provider,
-                    &provider,
-                    cred_with_key.clone(),
-                )
-                .unwrap();
-            let key_package = key_package_bundle.key_package().clone();
-            let my_pid = provider.state().my_pid().to_string();
-            provider
-                .state_mut()
-                .set_key_package(&my_pid, key_package.clone());
-            let kp_msg = MlsMessageOut::from(key_package)
-                .tls_serialize_detached()
-                .unwrap();
-            log::info!("Key package to put: {}", hex_encode(&kp_msg));
-            let mut index = provider.state().key_package_counter();
-            loop {
-                let key = key_package_key(index);
-                log::info!("Key package key: {key}");
-                match adapter.put_checked(&key, &kp_msg) {
-                    Ok(()) => {
-                        break;
-                    }
-                    Err(e) if e.to_string() == "Key already exists" => {
-                        log::warn!("Failed to put key package: {e}");
-                        index += 1;
+    }
+}
+
+/// Key of the state file, from --state-key-file or the passphrase environment variable.
+fn state_key(args: &CliArgs) -> Result<Option<StateKey>, MySgmError> {
+    let key = match &args.state_key_file {
+        Some(path) => StateKey::from_file(path)?,
+        None => match std::env::var(envelope::PASSPHRASE_ENV) {
+            Ok(passphrase) => StateKey::from_passphrase(&passphrase)?,
+            Err(_) => return Ok(None),
+        },
+    };
+    Ok(Some(key.sealing_plaintext(args.seal_plaintext_state)))
+}
+
+/// Bearer token of the DHT proxy, from --dht-token-file or the token environment variable.
+fn dht_token(args: &CliArgs) -> Result<Option<String>, MySgmError> {
+    let token = match &args.dht_token_file {
+        Some(path) => read_file_to_string(path)?.trim_end().to_string(),
+        None => match std::env::var(opendht::TOKEN_ENV) {
+            Ok(token) => token,
+            Err(_) => return Ok(None),
+        },
+    };
+    if token.is_empty() {
+        return Err(MySgmError::InvalidArgument(
+            "DHT bearer token is empty".to_string(),
+        ));
+    }
+    Ok(Some(token))
+}
+
+/// Executes a state command against the state files, without an agent.
+fn execute_state(
+    state_path: &str,
+    state_backend: StateBackend,
+    state_key: Option<&StateKey>,
+    backups: usize,
+    command: &StateCommands,
+    out: &mut dyn Write,
+) -> Result<(), MySgmError> {
+    match command {
+        StateCommands::Migrate { dry_run } => {
+            let state_json = match state_backend {
+                StateBackend::Json => persist::read_json(state_path, state_key)?,
+                StateBackend::Sqlite => sqlite::read_json(state_path)?,
+            };
+            let mut state: Value = json_decode(&state_json)?;
+            let changes = migrate::upgrade(&mut state)?;
+            if changes.is_empty() {
+                writeln!(
+                    out,
+                    "{state_path} is at schema version {}",
+                    migrate::SCHEMA_VERSION
+                )?;
+                return Ok(());
+            }
+            for change in &changes {
+                writeln!(out, "{change}")?;
+            }
+            if !dry_run {
+                match state_backend {
+                    StateBackend::Json => {
+                        let state = migrate::decode_value(state)?;
+                        persist::save(state_path, &json_encode(&state)?, state_key, backups)?;
                     }
-                    Err(e) => {
-                        panic!("{e}");
+                    StateBackend::Sqlite => {
+                        let state = sqlite::load(state_path)?;
+                        sqlite::save(state_path, &state)?;
                     }
                 }
+                writeln!(out, "migrated {state_path}")?;
             }
-            let mut event = MetricsEvent::new("advertise", started, now_ms());
-            event.node_id = Some(provider.state().my_pid().to_string());
-            event.payload_bytes = Some(kp_msg.len());
-            log_event(&event);
-        }
-        MainCommands::Group { gid, group_command } => {
-            let mut group =
-                MlsGroup::load(provider.storage(), &GroupId::from_slice(gid.as_bytes()))
-                    .unwrap()
-                    .unwrap();
-            match group_command {
-                GroupCommands::ExportSecret { label, length } => {
-                    println!(
-                        "{}",
-                        hex_encode(group.export_secret(&provider, label, &[], *length).unwrap())
-                    );
-                }
-                GroupCommands::Members {} => {
-                    let mut pids: Vec<String> = Vec::new();
-                    for member in group.members() {
-                        let cred = BasicCredential::try_from(member.credential.clone()).unwrap();
-                        println!(
-                            "{} {}",
-                            member.index,
-                            String::from_utf8_lossy(cred.identity())
-                        );
-                    }
+        }
+        StateCommands::ImportJson { json_path } => {
+            if file_exists(state_path)? {
+                return Err(MySgmError::InvalidArgument(format!(
+                    "{state_path} already exists"
+                )));
+            }
+            let loaded = persist::load(json_path, state_key)?;
+            sqlite::save(state_path, &loaded.state)?;
+            writeln!(out, "imported {} into {state_path}", loaded.path)?;
+        }
+        StateCommands::Verify {} if state_backend == StateBackend::Sqlite => {
+            let state = sqlite::load(state_path)?;
+            writeln!(out, "{state_path} ok {}", state.my_pid())?;
+            writeln!(out, "loaded {state_path}")?;
+        }
+        StateCommands::Rekey { .. } if state_backend == StateBackend::Sqlite => {
+            return Err(MySgmError::InvalidArgument(
+                "The SQLite state backend is not encrypted".to_string(),
+            ));
+        }
+        StateCommands::Verify {} => {
+            for path in persist::candidates(state_path)? {
+                match persist::read(&path, state_key) {
+                    Ok(state) => writeln!(out, "{path} ok {}", state.my_pid())?,
+                    Err(e) => writeln!(out, "{path} invalid: {e}")?,
                 }
-                GroupCommands::Remove { indexes } => {
-                    let started = now_ms();
-                    let members_before = group.members().count();
-                    let mut indexes: Vec<LeafNodeIndex> = indexes
-                        .iter()
-                        .map(|index| LeafNodeIndex::new(*index))
-                        .collect();
-                    if indexes.is_empty() {
-                        let handle = stdin().lock();
-                        log::debug!("Reading lines from stdin as indexes to remove");
-                        for line in handle.lines() {
-                            match line {
-                                Ok(l) => {
-                                    log::info!("index: {l}");
-                                    indexes.push(LeafNodeIndex::new(l.parse::<u32>().unwrap()));
-                                }
-                                Err(e) => {
-                                    log::error!("Error reading line: {e}");
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                    let (commit, welcome_opt, _) = group
-                        .remove_members(&provider, &provider, indexes.as_slice())
-                        .unwrap();
-                    log::info!("Commit message: {:?}", commit);
-                    let commit_bytes = commit.tls_serialize_detached().unwrap();
-                    let key = commit_key(&group, &provider).unwrap();
-                    adapter
-                        .put_checked(&key, &commit_bytes)
-                        .unwrap();
-                    group.merge_pending_commit(&provider).unwrap();
-                    let members_after = group.members().count();
-                    let mut event = MetricsEvent::new("group_remove", started, now_ms());
-                    event.node_id = Some(provider.state().my_pid().to_string());
-                    event.gid = Some(gid.clone());
-                    event.members_before = Some(members_before);
-                    event.members_after = Some(members_after);
-                    event.commit_bytes = Some(commit_bytes.len());
-                    if let Some(welcome) = welcome_opt {
-                        log::info!("Welcome message: {:?}", welcome);
-                        event.welcome_bytes = Some(welcome.tls_serialize_detached().unwrap().len());
-                        let mut index = provider.state().welcome_counter();
-                        loop {
-                            let key = welcome_message_key(index);
-                            log::info!("Welcome message key: {key}");
-                            match adapter
-                                .put_checked(&key, &welcome.tls_serialize_detached().unwrap())
-                            {
-                                Ok(()) => {
-                                    break;
-                                }
-                                Err(e) if e.to_string() == "Key already exists" => {
-                                    log::warn!("Failed to put key package: {e}");
-                                    index += 1;
-                                }
-                                Err(e) => {
-                                    panic!("{e}");
-                                }
-                            }
-                        }
+            }
+            let loaded = persist::load(state_path, state_key)?;
+            writeln!(out, "loaded {}", loaded.path)?;
+        }
+        StateCommands::Rekey {
+            new_key_file,
+            decrypt: _,
+        } => {
+            let new_key = new_key_file
+                .as_deref()
+                .map(StateKey::from_file)
+                .transpose()?;
+            // fails before anything is rewritten if the state file cannot be read
+            persist::read(state_path, state_key)?;
+            for path in persist::candidates(state_path)? {
+                match persist::read(&path, state_key) {
+                    Ok(state) => {
+                        persist::save(&path, &json_encode(&state)?, new_key.as_ref(), 0)?;
+                        writeln!(out, "{path} rekeyed")?;
                     }
-                    log_event(&event);
+                    Err(e) => writeln!(out, "{path} skipped: {e}")?,
                 }
-                GroupCommands::Add { pids } => {
-                    let started = now_ms();
-                    let members_before = group.members().count();
-                    let mut input_pids = pids.clone();
-                    let mut kps = Vec::new();
-                    if input_pids.is_empty() {
-                        let handle = stdin().lock();
-                        log::debug!("Reading lines from stdin as agents to add");
-                        for line in handle.lines() {
-                            match line {
-                                Ok(l) => {
-                                    input_pids.push(l);
-                                }
-                                Err(e) => {
-                                    log::error!("Error reading line: {e}");
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                    for pid in input_pids {
-                        log::info!("pid: {pid}");
-                        match provider.state().key_package(&pid) {
-                            Some(kp) => {
-                                log::info!("Key package for pid: {kp:?}");
-                                kps.push(kp.clone());
-                            }
-                            None => {
-                                panic!("No key package for pid: {pid}");
-                            }
-                        }
-                    }
-                    let (commit, welcome, _) = group
-                        .add_members_without_update(&provider, &provider, kps.as_slice())
-                        .unwrap();
-                    log::info!("Commit message: {:?}", commit);
-                    let commit_bytes = commit.tls_serialize_detached().unwrap();
-                    let key = commit_key(&group, &provider).unwrap();
-                    adapter
-                        .put_checked(&key, &commit_bytes)
-                        .unwrap();
-                    group.merge_pending_commit(&provider).unwrap();
-                    log::info!("Welcome message: {:?}", welcome);
-                    let welcome_bytes = welcome.tls_serialize_detached().unwrap();
-                    let mut index = provider.state().welcome_counter();
-                    loop {
-                        let key = welcome_message_key(index);
-                        log::info!("Welcome message key: {key}");
-                        match adapter.put_checked(&key, &welcome_bytes)
-                        {
-                            Ok(()) => {
-                                break;
-                            }
-                            Err(e) if e.to_string() == "Key already exists" => {
-                                log::warn!("Failed to put key package: {e}");
-                                index += 1;
-                            }
-                            Err(e) => {
-                                panic!("{e}");
-                            }
-                        }
-                    }
-                    let members_after = group.members().count();
-                    let mut event = MetricsEvent::new("group_add", started, now_ms());
-                    event.node_id = Some(provider.state().my_pid().to_string());
-                    event.gid = Some(gid.clone());
-                    event.members_before = Some(members_before);
-                    event.members_after = Some(members_after);
-                    event.commit_bytes = Some(commit_bytes.len());
-                    event.welcome_bytes = Some(welcome_bytes.len());
-                    log_event(&event);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Executes an identity command against the state files, without an agent.
+fn execute_identity(
+    state_path: &str,
+    state_backend: StateBackend,
+    state_key: Option<&StateKey>,
+    backups: usize,
+    command: &IdentityCommands,
+    out: &mut dyn Write,
+) -> Result<(), MySgmError> {
+    match command {
+        IdentityCommands::Export {
+            out: path,
+            key_file,
+            with_groups,
+            public,
+        } => {
+            let state = Agent::load_state(state_path, state_backend, state_key)?;
+            let exported = match key_file {
+                Some(key_file) if !public => {
+                    identity::export(&state, &StateKey::from_file(key_file)?, *with_groups)?
                 }
-                GroupCommands::Update {} => {
-                    let started = now_ms();
-                    let members_before = group.members().count();
-                    let (commit, welcome_opt, _) = group
-                        .self_update(
-                            &provider,
-                            &provider,
-                            LeafNodeParameters::builder()
-                                .with_capabilities(capabilities.clone())
-                                .build(),
-                        )
-                        .unwrap()
-                        .into_messages();
-                    log::info!("Commit message: {:?}", commit);
-                    let commit_bytes = commit.tls_serialize_detached().unwrap();
-                    let key = commit_key(&group, &provider).unwrap();
-                    adapter
-                        .put_checked(&key, &commit_bytes)
-                        .unwrap();
-                    group.merge_pending_commit(&provider).unwrap();
-                    let members_after = group.members().count();
-                    let mut event = MetricsEvent::new("group_update", started, now_ms());
-                    event.node_id = Some(provider.state().my_pid().to_string());
-                    event.gid = Some(gid.clone());
-                    event.members_before = Some(members_before);
-                    event.members_after = Some(members_after);
-                    event.commit_bytes = Some(commit_bytes.len());
-                    if let Some(welcome) = welcome_opt {
-                        log::info!("Welcome message: {:?}", welcome);
-                        event.welcome_bytes = Some(welcome.tls_serialize_detached().unwrap().len());
-                        let mut index = provider.state().welcome_counter();
-                        loop {
-                            let key = welcome_message_key(index);
-                            log::info!("Welcome message key: {key}");
-                            match adapter
-                                .put_checked(&key, &welcome.tls_serialize_detached().unwrap())
-                            {
-                                Ok(()) => {
-                                    break;
-                                }
-                                Err(e) if e.to_string() == "Key already exists" => {
-                                    log::warn!("Failed to put key package: {e}");
-                                    index += 1;
-                                }
-                                Err(e) => {
-                                    panic!("{e}");
-                                }
-                            }
-                        }
-                    }
-                    event.update_count = Some(1);
-                    log_event(&event);
+                _ => json_encode(&identity::public_identity(&state)?)?,
+            };
+            match path {
+                Some(path) => persist::save(path, &exported, None, 0)?,
+                None => writeln!(out, "{exported}")?,
+            }
+        }
+        IdentityCommands::Import { bundle, key_file } => {
+            if file_exists(state_path)? {
+                return Err(MySgmError::InvalidArgument(format!(
+                    "{state_path} already exists"
+                )));
+            }
+            let state = identity::import(
+                &read_file_to_string(bundle)?,
+                &StateKey::from_file(key_file)?,
+            )?;
+            match state_backend {
+                StateBackend::Json => {
+                    persist::save(state_path, &json_encode(&state)?, state_key, backups)?
                 }
+                StateBackend::Sqlite => sqlite::save(state_path, &state)?,
             }
+            writeln!(out, "imported {} into {state_path}", state.my_pid())?;
+        }
+        IdentityCommands::Rotate {} => {
+            return Err(MySgmError::InvalidArgument(
+                "Identity Rotate is run by the agent".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn run(mut args: CliArgs) -> Result<(), MySgmError> {
+    // state commands work on the files directly, even while a daemon holds the state
+    let state_key = state_key(&args)?;
+    let state_backend = args.state_backend.into();
+    if let MainCommands::State { state_command } = &args.main_command {
+        return execute_state(
+            &args.state_path,
+            state_backend,
+            state_key.as_ref(),
+            args.backups,
+            state_command,
+            &mut stdout(),
+        );
+    }
+    match &args.main_command {
+        MainCommands::Identity { identity_command }
+            if !matches!(identity_command, IdentityCommands::Rotate {}) =>
+        {
+            return execute_identity(
+                &args.state_path,
+                state_backend,
+                state_key.as_ref(),
+                args.backups,
+                identity_command,
+                &mut stdout(),
+            );
+        }
+        _ => {}
+    }
+    if state_backend == StateBackend::Sqlite && state_key.is_some() {
+        return Err(MySgmError::InvalidArgument(
+            "The SQLite state backend does not support a state key".to_string(),
+        ));
+    }
+    // thin client of a running daemon
+    let client = !matches!(args.main_command, MainCommands::Daemon { .. });
+    if let Some(socket) = args.socket.as_ref().filter(|_| client) {
+        read_stdin_arguments(&mut args.main_command)?;
+        let response = daemon::request(socket, &args.main_command)?;
+        print!("{}", response.output);
+        if let Some(e) = response.error {
+            eprintln!("{e}");
+            exit(response.exit_code);
+        }
+        return Ok(());
+    }
+
+    let adapter: Box<dyn StorageAdapter> = match args.adapter {
+        AdapterKind::File => Box::new(FileAdapter::new(&args.file_path)),
+        AdapterKind::Dht => {
+            let config = OpenDhtConfig {
+                connect_timeout: Duration::from_millis(args.dht_connect_timeout_ms),
+                timeout: Duration::from_millis(args.dht_timeout_ms),
+                max_attempts: args.dht_max_attempts,
+                backoff: Duration::from_millis(args.dht_backoff_ms),
+                ca_bundle: args.dht_ca_bundle.clone(),
+                client_identity: args.dht_client_cert.clone(),
+                bearer_token: dht_token(&args)?,
+                ..Default::default()
+            };
+            let url = args
+                .dht_url
+                .clone()
+                .unwrap_or_else(|| format!("http://{}:{}", args.dht_host, args.dht_port));
+            Box::new(OpenDhtRestAdapter::new(&url, config).map_err(MySgmError::adapter)?)
+        }
+    };
+    log::info!(
+        "Storage adapter: {}",
+        match args.adapter {
+            AdapterKind::File => "file",
+            AdapterKind::Dht => "dht",
+        }
+    );
+
+    // state
+    log::info!("Path to agent state: {}", args.state_path);
+    log::info!("Reset state? {}", args.reset);
+    let mut state = if args.reset {
+        log::warn!("Resetting state");
+        let ciphersuite = args
+            .ciphersuite
+            .unwrap_or(CiphersuiteArg::X25519Chacha20poly1305Ed25519);
+        match (&args.x509_cert, &args.x509_key) {
+            (Some(cert), Some(key)) => {
+                Agent::generate_x509_state(x509::load_identity(cert, key)?, ciphersuite.into())?
+            }
+            _ => Agent::generate_state(&args.pid, ciphersuite.into())?,
+        }
+    } else {
+        Agent::load_state(&args.state_path, state_backend, state_key.as_ref())?
+    };
+    match (&args.namespace, state.namespace()) {
+        (Some(namespace), None) if args.reset => {
+            adapter::check_namespace(namespace)?;
+            state.set_namespace(namespace.clone());
+        }
+        (Some(namespace), stored) if stored != Some(namespace.as_str()) => {
+            return Err(MySgmError::InvalidArgument(format!(
+                "The state was created for namespace {}, not {namespace}",
+                stored.unwrap_or("(none)")
+            )));
+        }
+        _ => {}
+    }
+    log::info!("Namespace: {}", state.namespace().unwrap_or("(none)"));
+    log::info!(
+        "State of pid: {} at schema version {}",
+        state.my_pid(),
+        state.schema_version()
+    );
+    read_stdin_arguments(&mut args.main_command)?;
+    // agent
+    let mut agent = Agent::new(state, adapter);
+    agent.set_reissue(args.reissue);
+    agent.set_backups(args.backups);
+    agent.set_key_package_lifetime(args.key_package_lifetime);
+    agent.set_state_key(state_key);
+    agent.set_state_backend(state_backend);
+    agent.set_trust_anchors(
+        args.x509_trust_anchors
+            .as_deref()
+            .map(TrustAnchors::from_pem_file)
+            .transpose()?,
+    );
+    agent.sync()?;
+    match &args.main_command {
+        MainCommands::Daemon { interval_ms } => {
+            let socket = args.socket.as_deref().ok_or_else(|| {
+                MySgmError::InvalidArgument("Daemon requires --socket".to_string())
+            })?;
+            let saved_state = agent.save_state(&args.state_path)?;
+            agent.enable_watches();
+            let mut daemon_agent = DaemonAgent {
+                agent,
+                state_path: args.state_path.clone(),
+                saved_state,
+            };
+            daemon::serve(
+                socket,
+                Duration::from_millis(*interval_ms),
+                &mut daemon_agent,
+            )?;
+        }
+        command => {
+            execute(&mut agent, command, &mut stdout())?;
+            // save state
+            agent.save_state(&args.state_path)?;
         }
     }
-    // save state
-    log::info!("State before saving: {:?}", provider.state());
-    write_string_to_file(&args.state_path, json_encode(provider.state()).unwrap()).unwrap();
     // done
+    Ok(())
+}
+
+fn main() {
+    pretty_env_logger::init();
+    // CLI args
+    let args = CliArgs::parse();
+    if let Err(e) = metrics::init_metrics_logger(&args.log_file) {
+        eprintln!("Failed to open metrics log: {e}");
+        exit(MySgmError::Io(e.to_string()).exit_code());
+    }
+    log::info!("Command-line arguments: {args:?}");
+    if let Err(e) = run(args) {
+        eprintln!("{e}");
+        exit(e.exit_code());
+    }
 }
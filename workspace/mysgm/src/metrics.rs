@@ -28,8 +28,15 @@ pub struct MetricsEvent {
     pub dht_key: Option<String>,
     pub payload_bytes: Option<usize>,
     pub http_status: Option<u16>,
+    /// Requests made for a DHT operation, retries included
+    pub attempts: Option<u32>,
     pub welcome_processed: Option<bool>,
+    pub welcome_outcome: Option<String>,
     pub commit_merged: Option<bool>,
+    pub commit_candidates: Option<usize>,
+    pub commit_won: Option<bool>,
+    /// Time from a watch reporting the commit key stored to the merge
+    pub notify_latency_ms: Option<u128>,
 }
 
 impl MetricsEvent {
@@ -53,8 +60,13 @@ impl MetricsEvent {
             dht_key: None,
             payload_bytes: None,
             http_status: None,
+            attempts: None,
             welcome_processed: None,
+            welcome_outcome: None,
             commit_merged: None,
+            commit_candidates: None,
+            commit_won: None,
+            notify_latency_ms: None,
         }
     }
 }
@@ -78,9 +90,10 @@ pub fn log_event(event: &MetricsEvent) {
         return;
     };
 
-    if let Ok(mut file) = file_mutex.lock() {
-        if let Ok(json_line) = serde_json::to_string(event) {
-            let _ = writeln!(file, "{json_line}");
-        }
+    let Ok(mut file) = file_mutex.lock() else {
+        return;
+    };
+    if let Ok(json_line) = serde_json::to_string(event) {
+        let _ = writeln!(file, "{json_line}");
     }
 }
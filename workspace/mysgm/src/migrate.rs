@@ -0,0 +1,172 @@
+//! Versioning of the agent state schema.
+//!
+//! The state records the schema version it was written with in `schema_version`; state
+//! written before versioning has none and counts as version 0. On load, the raw JSON is
+//! passed through [`MIGRATIONS`] until it reaches [`SCHEMA_VERSION`], and only then
+//! deserialized into a [`MySgmState`]. State written by a newer version of mysgm is
+//! refused, since saving it again would drop the fields this version does not know about.
+
+use super::{agent::key_package_fingerprint, error::MySgmError, state::MySgmState};
+
+use openmls::key_packages::KeyPackage;
+use serde_json::{Map, Value, from_str as json_decode, from_value, json};
+
+/// Schema version of the state written by this version of mysgm.
+pub const SCHEMA_VERSION: u64 = 8;
+
+const SCHEMA_VERSION_FIELD: &str = "schema_version";
+
+type Migration = fn(&mut Map<String, Value>) -> Result<(), MySgmError>;
+
+/// `MIGRATIONS[n]` migrates a state from schema version `n` to `n + 1`.
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [
+    v0_to_v1, v1_to_v2, v2_to_v3, v3_to_v4, v4_to_v5, v5_to_v6, v6_to_v7, v7_to_v8,
+];
+
+/// Spells out the defaults of the fields added before the schema was versioned.
+fn v0_to_v1(state: &mut Map<String, Value>) -> Result<(), MySgmError> {
+    let defaults = [
+        ("mailbox_counter", json!(0)),
+        ("message_cursors", json!({})),
+        ("sent_messages", json!({})),
+        ("inbox", json!([])),
+        ("proposal_cursors", json!({})),
+        ("pending_leaves", json!({})),
+        ("group_policies", json!({})),
+        ("group_info_counters", json!({})),
+        ("external_joins", json!({})),
+    ];
+    for (field, default) in defaults {
+        state.entry(field).or_insert(default);
+    }
+    Ok(())
+}
+
+/// Pins the signature keys of the key packages stored before keys were pinned, as if
+/// they had been seen for the first time.
+fn v1_to_v2(state: &mut Map<String, Value>) -> Result<(), MySgmError> {
+    let mut pins = Map::new();
+    if let Some(key_packages) = state.get("key_packages").and_then(Value::as_object) {
+        for (pid, kp) in key_packages {
+            let kp: KeyPackage = from_value(kp.clone())?;
+            pins.insert(pid.clone(), key_package_fingerprint(&kp)?.into());
+        }
+    }
+    state.insert("pins".to_string(), Value::Object(pins));
+    state.entry("quarantine").or_insert(json!({}));
+    state.entry("revoked_keys").or_insert(json!([]));
+    Ok(())
+}
+
+/// Adds the join policy, accepting every welcome as before, and the staged invitations.
+fn v2_to_v3(state: &mut Map<String, Value>) -> Result<(), MySgmError> {
+    let defaults = [
+        (
+            "join_policy",
+            json!({ "mode": "accept-all", "allowlist": [] }),
+        ),
+        ("invites", json!([])),
+        ("invite_counter", json!(0)),
+    ];
+    for (field, default) in defaults {
+        state.entry(field).or_insert(default);
+    }
+    Ok(())
+}
+
+/// Adds the credential of agents that do not use a basic credential, none so far.
+fn v3_to_v4(state: &mut Map<String, Value>) -> Result<(), MySgmError> {
+    state.entry("credential").or_insert(Value::Null);
+    Ok(())
+}
+
+/// Adds the key pair kept while a signature key rotation is under way.
+fn v4_to_v5(state: &mut Map<String, Value>) -> Result<(), MySgmError> {
+    state
+        .entry("previous_signature_key_pair")
+        .or_insert(Value::Null);
+    Ok(())
+}
+
+/// Adds the key packages of ours kept until they expire, none so far.
+fn v5_to_v6(state: &mut Map<String, Value>) -> Result<(), MySgmError> {
+    state.entry("retired_key_packages").or_insert(json!([]));
+    Ok(())
+}
+
+/// Adds the queues of single-use key packages of peers and the pool of ours, all empty,
+/// so that only last-resort key packages are used as before.
+fn v6_to_v7(state: &mut Map<String, Value>) -> Result<(), MySgmError> {
+    let defaults = [
+        ("key_package_queues", json!({})),
+        ("key_package_pool", json!([])),
+        ("key_package_pool_size", json!(0)),
+    ];
+    for (field, default) in defaults {
+        state.entry(field).or_insert(default);
+    }
+    Ok(())
+}
+
+/// Adds the adapter namespace, none so far, so that keys stay unprefixed.
+fn v7_to_v8(state: &mut Map<String, Value>) -> Result<(), MySgmError> {
+    state.entry("namespace").or_insert(Value::Null);
+    Ok(())
+}
+
+/// Schema version of a raw state.
+pub fn schema_version(state: &Map<String, Value>) -> Result<u64, MySgmError> {
+    match state.get(SCHEMA_VERSION_FIELD) {
+        None => Ok(0),
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| MySgmError::State(format!("Invalid schema version: {version}"))),
+    }
+}
+
+/// Migrates a raw state to [`SCHEMA_VERSION`] in place, returning a description of every
+/// change made; none if the state is up to date.
+pub fn upgrade(state: &mut Value) -> Result<Vec<String>, MySgmError> {
+    let fields = state
+        .as_object_mut()
+        .ok_or_else(|| MySgmError::State("Agent state is not an object".to_string()))?;
+    let version = schema_version(fields)?;
+    if version > SCHEMA_VERSION {
+        return Err(MySgmError::State(format!(
+            "State has schema version {version}, but this version of mysgm only supports up to {SCHEMA_VERSION}; upgrade mysgm"
+        )));
+    }
+    let mut changes = Vec::new();
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let to = from as u64 + 1;
+        let before = fields.clone();
+        migration(fields)?;
+        fields.insert(SCHEMA_VERSION_FIELD.to_string(), to.into());
+        for (field, value) in fields.iter() {
+            match before.get(field) {
+                None => changes.push(format!("v{from} -> v{to}: add {field} = {value}")),
+                Some(old) if old != value => {
+                    changes.push(format!("v{from} -> v{to}: change {field}"))
+                }
+                Some(_) => {}
+            }
+        }
+        for field in before.keys().filter(|field| !fields.contains_key(*field)) {
+            changes.push(format!("v{from} -> v{to}: remove {field}"));
+        }
+    }
+    Ok(changes)
+}
+
+/// Deserializes a state from its JSON, migrating it first if it is outdated.
+pub fn decode(state_json: &str) -> Result<MySgmState, MySgmError> {
+    decode_value(json_decode(state_json)?)
+}
+
+/// Deserializes a raw state, migrating it first if it is outdated.
+pub fn decode_value(mut state: Value) -> Result<MySgmState, MySgmError> {
+    for change in upgrade(&mut state)? {
+        log::info!("Migrated state: {change}");
+    }
+    Ok(from_value(state)?)
+}
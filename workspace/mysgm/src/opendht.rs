@@ -1,104 +1,212 @@
 use base64::{Engine, engine::general_purpose::STANDARD};
 use core::error::Error;
 
-use crate::metrics::{MetricsEvent, log_event, now_ms};
-use reqwest::blocking::Client as ReqwestClient;
+use crate::{
+    adapter::{KeyExists, WatchEvent},
+    metrics::{MetricsEvent, log_event, now_ms},
+};
+use rand::{Rng, thread_rng};
+use reqwest::{
+    Certificate, Identity, Method, StatusCode, Url,
+    blocking::{Client as ReqwestClient, RequestBuilder},
+};
 use serde_json::{Value, from_str as json_decode, json, to_string as json_encode};
+use std::{
+    fs::read as read_file,
+    io::{BufRead, BufReader},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{Receiver, Sender, channel},
+    },
+    thread::{sleep, spawn},
+    time::Duration,
+};
+
+/// How long a watch listens before it ends and has to be renewed.
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(60);
+/// Most `LISTEN` streams an adapter keeps open at once.
+const MAX_LISTENERS: usize = 16;
+
+/// Environment variable holding the bearer token, as an alternative to a token file.
+pub const TOKEN_ENV: &str = "MYSGM_DHT_TOKEN";
+
+/// Timeouts, retry policy and authentication of the requests to the proxy.
+#[derive(Clone)]
+pub struct OpenDhtConfig {
+    /// Time allowed to connect to the proxy
+    pub connect_timeout: Duration,
+    /// Time allowed for a whole request, reading the response included
+    pub timeout: Duration,
+    /// Attempts per operation, the first one included
+    pub max_attempts: u32,
+    /// Longest delay before the first retry, doubled for every further one
+    pub backoff: Duration,
+    /// Longest delay before any retry
+    pub max_backoff: Duration,
+    /// PEM bundle of the CAs an https proxy's certificate has to chain to, in place of the
+    /// built-in roots
+    pub ca_bundle: Option<String>,
+    /// PEM file holding the client certificate, and its private key, presented to an https
+    /// proxy
+    pub client_identity: Option<String>,
+    /// Token sent as `Authorization: Bearer` with every request
+    pub bearer_token: Option<String>,
+}
+
+impl core::fmt::Debug for OpenDhtConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("OpenDhtConfig")
+            .field("connect_timeout", &self.connect_timeout)
+            .field("timeout", &self.timeout)
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff", &self.backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("ca_bundle", &self.ca_bundle)
+            .field("client_identity", &self.client_identity)
+            .field(
+                "bearer_token",
+                &self.bearer_token.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+impl Default for OpenDhtConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(2),
+            timeout: Duration::from_secs(10),
+            max_attempts: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            ca_bundle: None,
+            client_identity: None,
+            bearer_token: None,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct OpenDhtRestAdapter {
-    proxy_address: String,
-    proxy_port: u16,
+    base_url: String,
+    /// Shared by every request, so that connections to the proxy are reused
+    client: ReqwestClient,
+    config: OpenDhtConfig,
+    listeners: Arc<Mutex<Listeners>>,
 }
 
 impl OpenDhtRestAdapter {
-    pub fn new(proxy_address: &str, proxy_port: u16) -> Self {
-        Self {
-            proxy_address: proxy_address.into(),
-            proxy_port,
+    /// Adapter for the proxy at `url`, `http://` or `https://`. With https, the proxy's
+    /// certificate is always validated, against the CA bundle of `config` if it has one,
+    /// and requests fail if it does not validate.
+    pub fn new(url: &str, config: OpenDhtConfig) -> Result<Self, Box<dyn Error>> {
+        let url = Url::parse(url)?;
+        let https = match url.scheme() {
+            "https" => true,
+            "http" => false,
+            scheme => return Err(format!("Unsupported DHT proxy scheme {scheme}").into()),
+        };
+        if !https && (config.ca_bundle.is_some() || config.client_identity.is_some()) {
+            return Err("A CA bundle or client certificate needs an https DHT proxy URL".into());
         }
-    }
-    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
-        // Implementation for getting a value from OpenDHT via REST API using reqwest
-        let request_url = format!(
-            "http://{}:{}/key/{}",
-            self.proxy_address, self.proxy_port, key
-        );
-        let started = now_ms();
-        let response = ReqwestClient::new().get(&request_url).send().map_err(Box::new)?;
-        let status = response.status();
-        let response_body = response.text()?;
-        let mut event = MetricsEvent::new("dht_get", started, now_ms());
-        event.dht_key = Some(key.to_string());
-        event.http_status = Some(status.as_u16());
-        event.payload_bytes = Some(response_body.len());
-        if !status.is_success() {
-            event.result = "error".to_string();
-            event.error = Some(format!("HTTP status {status}"));
+        if !https && config.bearer_token.is_some() {
+            log::warn!("Sending the DHT bearer token over plain HTTP to {url}");
         }
-        log_event(&event);
-        if !status.is_success() {
-            return Err(format!("HTTP status {status}").into());
+        let mut builder = ReqwestClient::builder()
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.timeout)
+            .https_only(https);
+        if let Some(path) = &config.ca_bundle {
+            let certs = Certificate::from_pem_bundle(&read_file(path)?)?;
+            if certs.is_empty() {
+                return Err(format!("No certificates in {path}").into());
+            }
+            builder = builder.tls_built_in_root_certs(false);
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
         }
+        if let Some(path) = &config.client_identity {
+            builder = builder.identity(Identity::from_pem(&read_file(path)?)?);
+        }
+        Ok(Self {
+            base_url: url.as_str().trim_end_matches('/').to_string(),
+            client: builder.build()?,
+            config,
+            listeners: Arc::new(Mutex::new(Listeners::new())),
+        })
+    }
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Ok(self.get_all(key)?.into_iter().next())
+    }
+    /// All distinct values stored under `key`, in the order the proxy returns them.
+    pub fn get_all(&self, key: &str) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        let response_body = self.execute(
+            "dht_get",
+            key,
+            None,
+            || self.client.get(self.url(key)),
+            || true,
+        )?;
         if response_body.is_empty() {
-            Ok(None)
-        } else {
-            let json_value: Value = json_decode(&response_body).map_err(Box::new)?;
-            let data_str = match &json_value {
-                Value::Array(values) => values
-                    .iter()
-                    .find_map(|value| value.get("data").and_then(|data| data.as_str())),
-                Value::Object(_) => json_value.get("data").and_then(|data| data.as_str()),
-                _ => None,
-            };
-            match data_str {
-                Some(data) if !data.is_empty() => {
-                    Ok(Some(STANDARD.decode(data).map_err(Box::new)?))
-                }
-                _ => Ok(None),
+            return Ok(Vec::new());
+        }
+        let json_value: Value = json_decode(&response_body).map_err(Box::new)?;
+        let data_strs: Vec<&str> = match &json_value {
+            Value::Array(values) => values
+                .iter()
+                .filter_map(|value| value.get("data").and_then(|data| data.as_str()))
+                .collect(),
+            Value::Object(_) => json_value
+                .get("data")
+                .and_then(|data| data.as_str())
+                .into_iter()
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut values: Vec<Vec<u8>> = Vec::new();
+        for data in data_strs.into_iter().filter(|data| !data.is_empty()) {
+            let value = STANDARD.decode(data).map_err(Box::new)?;
+            if !values.contains(&value) {
+                values.push(value);
             }
         }
+        Ok(values)
     }
+    /// Stores `value` under `key`. A failed put may still have stored the value, so it is
+    /// only sent again once a get shows that the value is not there, as every put adds a
+    /// value.
     pub fn put(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
-        // Implementation for putting a value into OpenDHT via REST API using reqwest
-        let request_url = format!(
-            "http://{}:{}/key/{}",
-            self.proxy_address, self.proxy_port, key
-        );
         let request_payload = json_encode(&json!({
             "data": STANDARD.encode(value),
             "permanent": true
-        }))
-        .unwrap();
-        let started = now_ms();
-        let response = ReqwestClient::new()
-            .post(&request_url)
-            .header("Content-Type", "application/json")
-            .body(request_payload)
-            .send()
-            .map_err(Box::new)?;
-        let status = response.status();
-        let mut event = MetricsEvent::new("dht_put", started, now_ms());
-        event.dht_key = Some(key.to_string());
-        event.http_status = Some(status.as_u16());
-        event.payload_bytes = Some(value.len());
-        if !status.is_success() {
-            event.result = "error".to_string();
-            event.error = Some(format!("HTTP status {status}"));
-        }
-        log_event(&event);
-        if !status.is_success() {
-            return Err(format!("HTTP status {status}").into());
-        }
+        }))?;
+        self.execute(
+            "dht_put",
+            key,
+            Some(value.len()),
+            || {
+                self.client
+                    .post(self.url(key))
+                    .header("Content-Type", "application/json")
+                    .body(request_payload.clone())
+            },
+            || {
+                self.get_all(key)
+                    .is_ok_and(|values| !values.iter().any(|stored| stored == value))
+            },
+        )?;
         Ok(())
     }
     pub fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
         if let Ok(Some(_)) = self.get(key) {
-            Err("Key already exists".into())
+            Err(Box::new(KeyExists))
         } else {
             match self.put(key, value) {
                 Ok(()) => Ok(()),
                 Err(err) => {
+                    // the put may have been stored even though no response made it back
                     if let Ok(Some(_)) = self.get(key) {
                         Ok(())
                     } else {
@@ -108,4 +216,204 @@ impl OpenDhtRestAdapter {
             }
         }
     }
+    /// Watches `key` through the proxy's `LISTEN` stream, which sends every value stored
+    /// under the key as a line of JSON. The stream is opened once one of the adapter's
+    /// listeners is free.
+    pub fn watch(&self, key: &str, events: Sender<WatchEvent>) -> Result<(), Box<dyn Error>> {
+        let mut request = self
+            .client
+            .request(Method::from_bytes(b"LISTEN")?, self.url(key))
+            .timeout(LISTEN_TIMEOUT);
+        if let Some(token) = &self.config.bearer_token {
+            request = request.bearer_auth(token);
+        }
+        let job = ListenJob {
+            key: key.to_string(),
+            request,
+            events,
+        };
+        self.listeners
+            .lock()
+            .map_err(|e| e.to_string())?
+            .submit(job)
+    }
+    fn url(&self, key: &str) -> String {
+        format!("{}/key/{key}", self.base_url)
+    }
+    /// Sends the request built by `request` with retries, logs the operation as `op`, and
+    /// returns the body of a successful response. `payload_bytes` defaults to the size of
+    /// the response body.
+    fn execute(
+        &self,
+        op: &str,
+        key: &str,
+        payload_bytes: Option<usize>,
+        request: impl Fn() -> RequestBuilder,
+        resend: impl Fn() -> bool,
+    ) -> Result<String, Box<dyn Error>> {
+        let started = now_ms();
+        let (response, attempts) = self.send(request, resend);
+        let mut event = MetricsEvent::new(op, started, now_ms());
+        event.dht_key = Some(key.to_string());
+        event.attempts = Some(attempts);
+        let (status, response_body) = match response {
+            Ok(response) => response,
+            Err(e) => {
+                event.result = "error".to_string();
+                event.error = Some(e.to_string());
+                log_event(&event);
+                return Err(Box::new(e));
+            }
+        };
+        event.http_status = Some(status.as_u16());
+        event.payload_bytes = Some(payload_bytes.unwrap_or(response_body.len()));
+        if !status.is_success() {
+            event.result = "error".to_string();
+            event.error = Some(format!("HTTP status {status}"));
+        }
+        log_event(&event);
+        if !status.is_success() {
+            return Err(format!("HTTP status {status}").into());
+        }
+        Ok(response_body)
+    }
+    /// Sends the request built by `request` until the proxy answers with anything but a
+    /// server error, or the attempts run out. Server errors, failed connections and
+    /// timeouts are retried after a backoff, as long as `resend` agrees: a request that
+    /// may have taken effect without its response making it back is only sent again once
+    /// it is known not to have. Returns the last response or error, along with the number
+    /// of attempts made.
+    fn send(
+        &self,
+        request: impl Fn() -> RequestBuilder,
+        resend: impl Fn() -> bool,
+    ) -> (Result<(StatusCode, String), reqwest::Error>, u32) {
+        let mut attempt = 1;
+        loop {
+            let mut request = request();
+            if let Some(token) = &self.config.bearer_token {
+                request = request.bearer_auth(token);
+            }
+            let response = request.send().and_then(|response| {
+                let status = response.status();
+                response.text().map(|body| (status, body))
+            });
+            let retry = match &response {
+                Ok((status, _)) => status.is_server_error(),
+                Err(e) => e.is_connect() || e.is_timeout(),
+            };
+            if !retry || attempt >= self.config.max_attempts {
+                return (response, attempt);
+            }
+            let delay = self.backoff(attempt);
+            log::warn!("DHT request failed on attempt {attempt}, retrying in {delay:?}");
+            sleep(delay);
+            if !resend() {
+                log::info!("Not resending a DHT request that may have taken effect");
+                return (response, attempt);
+            }
+            attempt += 1;
+        }
+    }
+    /// Delay before the retry following `attempt`: random up to an exponential ceiling, so
+    /// that agents failing together do not retry together.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .config
+            .backoff
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.config.max_backoff);
+        ceiling.mul_f64(thread_rng().gen_range(0.0..1.0))
+    }
+}
+
+/// A watch waiting for a listener.
+struct ListenJob {
+    key: String,
+    request: RequestBuilder,
+    events: Sender<WatchEvent>,
+}
+
+/// Threads reading the `LISTEN` streams of watches, spawned as watches need them up to
+/// [`MAX_LISTENERS`]. Further watches wait for a stream to end. The threads exit once the
+/// adapter and its clones are dropped.
+#[derive(Debug)]
+struct Listeners {
+    jobs: Sender<ListenJob>,
+    queue: Arc<Mutex<Receiver<ListenJob>>>,
+    /// Listeners waiting for a watch
+    idle: Arc<AtomicUsize>,
+    spawned: usize,
+}
+
+impl Listeners {
+    fn new() -> Self {
+        let (jobs, queue) = channel();
+        Self {
+            jobs,
+            queue: Arc::new(Mutex::new(queue)),
+            idle: Arc::default(),
+            spawned: 0,
+        }
+    }
+    fn submit(&mut self, job: ListenJob) -> Result<(), Box<dyn Error>> {
+        if self.idle.load(Ordering::SeqCst) == 0 && self.spawned < MAX_LISTENERS {
+            let queue = self.queue.clone();
+            let idle = self.idle.clone();
+            spawn(move || run_listener(&queue, &idle));
+            self.spawned += 1;
+        }
+        self.jobs.send(job).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+/// Runs the watches of the queue one after the other, until the adapter is dropped.
+fn run_listener(queue: &Mutex<Receiver<ListenJob>>, idle: &AtomicUsize) {
+    loop {
+        idle.fetch_add(1, Ordering::SeqCst);
+        let job = match queue.lock() {
+            Ok(queue) => queue.recv(),
+            Err(_) => return,
+        };
+        idle.fetch_sub(1, Ordering::SeqCst);
+        let Ok(ListenJob {
+            key,
+            request,
+            events,
+        }) = job
+        else {
+            return;
+        };
+        let event = match listen(request) {
+            Ok(true) => WatchEvent::Stored(key),
+            Ok(false) => WatchEvent::Ended(key),
+            Err(e) => {
+                log::info!("Watch of DHT key {key} ended: {e}");
+                WatchEvent::Ended(key)
+            }
+        };
+        let _ = events.send(event);
+    }
+}
+
+/// Reads a `LISTEN` stream until it carries a value, returning whether one came before the
+/// stream ended.
+fn listen(request: RequestBuilder) -> Result<bool, Box<dyn Error>> {
+    let response = request.send()?.error_for_status()?;
+    for line in BufReader::new(response).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: Value = json_decode(&line)?;
+        if value
+            .get("data")
+            .and_then(Value::as_str)
+            .is_some_and(|data| !data.is_empty())
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
 }
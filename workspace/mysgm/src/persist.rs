@@ -0,0 +1,165 @@
+//! Crash-safe persistence of the agent state file.
+//!
+//! The state is written to a temporary file next to the state file, synced to disk and
+//! renamed over the state file, so that the state file holds either the old or the new
+//! state in full. The state it replaces is kept as a numbered backup (`<state>.1` being
+//! the newest), and [`load`] falls back to the newest readable backup when the state file
+//! itself cannot be read. With a [`StateKey`], state files are sealed by
+//! [`envelope`](super::envelope) before they are written, and once a plaintext state was
+//! opened with the key, the next save seals the backups left in plaintext as well.
+
+use super::{
+    envelope::{self, StateKey},
+    error::MySgmError,
+    migrate,
+    state::MySgmState,
+};
+
+use std::{
+    fs::{OpenOptions, copy, exists as file_exists, read_to_string as read_file_to_string, rename},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+/// Where the agent state is stored.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StateBackend {
+    /// A JSON file, rewritten in full on every save
+    #[default]
+    Json,
+    /// A SQLite database, see [`sqlite`](super::sqlite)
+    Sqlite,
+}
+
+/// Number of backups kept unless configured otherwise.
+pub const DEFAULT_BACKUPS: usize = 3;
+
+/// A state read from disk, along with the file it was read from.
+pub struct LoadedState {
+    pub state: MySgmState,
+    pub path: String,
+}
+
+/// Path of the `n`th newest backup of the state file at `path`, counting from 1.
+pub fn backup_path(path: &str, n: usize) -> String {
+    format!("{path}.{n}")
+}
+
+/// The state file at `path` followed by its existing backups, newest first.
+pub fn candidates(path: &str) -> Result<Vec<String>, MySgmError> {
+    let mut paths = vec![path.to_string()];
+    for n in 1.. {
+        let backup = backup_path(path, n);
+        if !file_exists(&backup)? {
+            break;
+        }
+        paths.push(backup);
+    }
+    Ok(paths)
+}
+
+/// Reads a single state file, decrypting it if it is sealed, and returns its JSON.
+pub fn read_json(path: &str, key: Option<&StateKey>) -> Result<String, MySgmError> {
+    envelope::open(key, &read_file_to_string(path)?)
+}
+
+/// Reads a single state file, migrating it to the current schema version.
+pub fn read(path: &str, key: Option<&StateKey>) -> Result<MySgmState, MySgmError> {
+    migrate::decode(&read_json(path, key)?)
+}
+
+/// Loads the state file at `path`, or its newest backup that can be read if the state
+/// file cannot. Fails with the error of the state file if none can be read.
+pub fn load(path: &str, key: Option<&StateKey>) -> Result<LoadedState, MySgmError> {
+    let mut paths = candidates(path)?.into_iter();
+    let main_error = match read(path, key) {
+        Ok(state) => {
+            return Ok(LoadedState {
+                state,
+                path: path.to_string(),
+            });
+        }
+        Err(e) => e,
+    };
+    log::warn!("Failed to load state from {path}: {main_error}");
+    paths.next();
+    for backup in paths {
+        match read(&backup, key) {
+            Ok(state) => {
+                log::warn!("Loaded state from backup {backup}");
+                return Ok(LoadedState {
+                    state,
+                    path: backup,
+                });
+            }
+            Err(e) => log::warn!("Failed to load state from {backup}: {e}"),
+        }
+    }
+    Err(main_error)
+}
+
+/// Atomically replaces the state file at `path` with the state JSON `contents`, sealed
+/// with `key` if given, keeping up to `backups` previous versions.
+pub fn save(
+    path: &str,
+    contents: &str,
+    key: Option<&StateKey>,
+    backups: usize,
+) -> Result<(), MySgmError> {
+    let sealed;
+    let contents = match key {
+        Some(key) => {
+            sealed = envelope::seal(key, contents)?;
+            &sealed
+        }
+        None => contents,
+    };
+    let tmp = format!("{path}.tmp");
+    // the state holds private keys, so only the owner may read it
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+    if backups > 0 && file_exists(path)? {
+        for n in (1..backups).rev() {
+            let backup = backup_path(path, n);
+            if file_exists(&backup)? {
+                rename(&backup, backup_path(path, n + 1))?;
+            }
+        }
+        // copied rather than renamed, so that there is no moment without a state file
+        let backup = backup_path(path, 1);
+        copy(path, &backup)?;
+        // synced before the state file is replaced, or a crash could leave neither on disk
+        OpenOptions::new().read(true).open(&backup)?.sync_all()?;
+    }
+    if let Some(key) = key.filter(|key| key.take_plaintext_opened()) {
+        seal_backups(path, key)?;
+    }
+    rename(&tmp, path)?;
+    // the rename itself is only durable once the directory is synced
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    OpenOptions::new().read(true).open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Seals the backups of the state file at `path` that still hold a plaintext state.
+fn seal_backups(path: &str, key: &StateKey) -> Result<(), MySgmError> {
+    for backup in candidates(path)?.into_iter().skip(1) {
+        let contents = read_file_to_string(&backup)?;
+        if !envelope::is_sealed(&contents) {
+            log::info!("Sealing plaintext backup {backup}");
+            save(&backup, &contents, Some(key), 0)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,89 @@
+//! Announcements of signature key rotations.
+//!
+//! An agent rotating its signature key publishes a [`KeyRotation`] under
+//! [`key_rotation_key`](super::adapter::key_rotation_key), naming its old and new public
+//! keys and signed with the old key. A peer that pinned the old key (see
+//! [`Agent::pinned_fingerprint`](super::Agent::pinned_fingerprint)) follows the
+//! announcements of the pid from the pinned key, and moves the pin to the new key once
+//! the signature checks out. Members of a group the agent is in also see the rotation in
+//! the commit that updates its leaf, which the old key signs as well.
+
+use super::{error::MySgmError, identity::fingerprint, keys::SignatureKeyPair};
+
+use hex::{decode as hex_decode, encode as hex_encode};
+use openmls_rust_crypto::RustCrypto;
+use openmls_traits::{crypto::OpenMlsCrypto, types::SignatureScheme};
+
+const LABEL: &str = "mysgm-key-rotation";
+
+/// An agent's statement that it replaced its signature key.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct KeyRotation {
+    pub pid: String,
+    pub signature_scheme: SignatureScheme,
+    /// hex-encoded public key being retired
+    pub old_public_key: String,
+    /// hex-encoded public key replacing it
+    pub new_public_key: String,
+    /// hex-encoded signature by the old key
+    signature: String,
+}
+
+impl KeyRotation {
+    /// Announces that `pid` replaces `old` with the key `new_public_key`.
+    pub fn sign(
+        pid: &str,
+        old: &SignatureKeyPair,
+        new_public_key: &[u8],
+    ) -> Result<Self, MySgmError> {
+        let mut rotation = Self {
+            pid: pid.to_string(),
+            signature_scheme: old.signature_scheme(),
+            old_public_key: hex_encode(old.public_key_raw()),
+            new_public_key: hex_encode(new_public_key),
+            signature: String::new(),
+        };
+        let signature = RustCrypto::default()
+            .sign(
+                old.signature_scheme(),
+                &rotation.content(),
+                old.private_key_raw(),
+            )
+            .map_err(|e| MySgmError::Mls(format!("{e:?}")))?;
+        rotation.signature = hex_encode(signature);
+        Ok(rotation)
+    }
+    /// Checks that the old key signed the announcement.
+    pub fn verify(&self) -> Result<(), MySgmError> {
+        let invalid = || MySgmError::UntrustedKey(format!("Invalid key rotation of {}", self.pid));
+        let old_public_key = hex_decode(&self.old_public_key).map_err(|_| invalid())?;
+        let signature = hex_decode(&self.signature).map_err(|_| invalid())?;
+        RustCrypto::default()
+            .verify_signature(
+                self.signature_scheme,
+                &self.content(),
+                &old_public_key,
+                &signature,
+            )
+            .map_err(|_| invalid())
+    }
+    pub fn old_fingerprint(&self) -> Result<String, MySgmError> {
+        key_fingerprint(&self.old_public_key)
+    }
+    pub fn new_fingerprint(&self) -> Result<String, MySgmError> {
+        key_fingerprint(&self.new_public_key)
+    }
+    /// What the old key signs.
+    fn content(&self) -> Vec<u8> {
+        format!(
+            "{LABEL}:{}:{}:{}",
+            self.pid, self.old_public_key, self.new_public_key
+        )
+        .into_bytes()
+    }
+}
+
+fn key_fingerprint(public_key: &str) -> Result<String, MySgmError> {
+    let public_key = hex_decode(public_key).map_err(|e| MySgmError::Codec(e.to_string()))?;
+    fingerprint(&public_key)
+}
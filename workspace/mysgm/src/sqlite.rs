@@ -0,0 +1,159 @@
+//! SQLite backend for the agent state, selected with `--state-backend sqlite`.
+//!
+//! The OpenMLS values live in an `openmls_values` table, one row per key, and the rest of
+//! the agent state is kept as a single JSON row in `agent_state`. Once a database is
+//! attached to the state's [`OpenMlsKeyValueStore`](super::state::OpenMlsKeyValueStore),
+//! every value OpenMLS writes or deletes becomes a row write in an open transaction, which
+//! [`save`] commits together with the agent state. Changes that are never saved are rolled
+//! back when the connection is dropped, like changes to a JSON state that is never written.
+//!
+//! The transaction starts with the first value OpenMLS writes, taking the write lock right
+//! away, and holds it until the state is saved. Another process writing the database, e.g.
+//! `State Migrate` while a daemon runs, waits up to [`BUSY_TIMEOUT`] for the daemon to
+//! finish its request before failing, and so does the daemon for it.
+
+use super::{error::MySgmError, migrate, state::MySgmState};
+
+use rusqlite::{Connection, OptionalExtension, params};
+use serde_json::{Map, Value, from_str as json_decode, to_string as json_encode};
+use std::{fs::exists as file_exists, time::Duration};
+
+/// How long to wait for another connection to release the write lock.
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS agent_state (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        state TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS openmls_values (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+";
+
+/// Field of [`MySgmState`] that is stored in its own table.
+const OPENMLS_VALUES: &str = "openmls_values";
+
+/// Connection to a state database, with a transaction open from the first write until the
+/// state is saved.
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl core::fmt::Debug for SqliteStore {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SqliteStore")
+            .field("path", &self.conn.path())
+            .finish()
+    }
+}
+
+impl SqliteStore {
+    fn open(path: &str) -> Result<Self, MySgmError> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = FULL;")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+    /// Starts a transaction unless one is open. It takes the write lock at once: a
+    /// transaction that starts reading cannot wait for the lock once it wants to write.
+    fn begin(&self) -> Result<(), rusqlite::Error> {
+        if self.conn.is_autocommit() {
+            self.conn.execute_batch("BEGIN IMMEDIATE")?;
+        }
+        Ok(())
+    }
+    pub fn put(&self, key: &str, value: &str) -> Result<(), rusqlite::Error> {
+        self.begin()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO openmls_values (key, value) VALUES (?1, ?2)",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+    pub fn delete(&self, key: &str) -> Result<(), rusqlite::Error> {
+        self.begin()?;
+        self.conn
+            .execute("DELETE FROM openmls_values WHERE key = ?1", params![key])?;
+        Ok(())
+    }
+    /// Commits the open transaction along with `state_json`.
+    fn commit(&self, state_json: &str) -> Result<(), MySgmError> {
+        self.begin()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO agent_state (id, state) VALUES (0, ?1)",
+            params![state_json],
+        )?;
+        self.conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+    /// JSON of the agent state, without the OpenMLS values.
+    fn agent_state(&self, path: &str) -> Result<String, MySgmError> {
+        self.conn
+            .query_row("SELECT state FROM agent_state WHERE id = 0", [], |row| {
+                row.get(0)
+            })
+            .optional()?
+            .ok_or_else(|| MySgmError::State(format!("No agent state in {path}")))
+    }
+}
+
+fn open_existing(path: &str) -> Result<SqliteStore, MySgmError> {
+    if !file_exists(path)? {
+        return Err(MySgmError::Io(format!("No state database at {path}")));
+    }
+    SqliteStore::open(path)
+}
+
+/// Reads the JSON of the agent state in the database at `path`, without the OpenMLS
+/// values and without migrating it.
+pub fn read_json(path: &str) -> Result<String, MySgmError> {
+    open_existing(path)?.agent_state(path)
+}
+
+/// Loads the state from the database at `path`, migrating it to the current schema
+/// version, and attaches the database to it.
+pub fn load(path: &str) -> Result<MySgmState, MySgmError> {
+    let store = open_existing(path)?;
+    let state_json = store.agent_state(path)?;
+    let mut values = Map::new();
+    {
+        let mut statement = store
+            .conn
+            .prepare("SELECT key, value FROM openmls_values")?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        for row in rows {
+            let (key, value): (String, String) = row?;
+            values.insert(key, Value::String(value));
+        }
+    }
+    let mut state: Value = json_decode(&state_json)?;
+    state
+        .as_object_mut()
+        .ok_or_else(|| MySgmError::State("Agent state is not an object".to_string()))?
+        .insert(OPENMLS_VALUES.to_string(), Value::Object(values));
+    let state = migrate::decode_value(state)?;
+    state.openmls_values().attach(store);
+    Ok(state)
+}
+
+/// Commits the state to the database at `path`. A state that was not loaded from it, e.g.
+/// a new one, replaces whatever the database held.
+pub fn save(path: &str, state: &MySgmState) -> Result<(), MySgmError> {
+    let values = state.openmls_values();
+    if !values.is_attached() {
+        let store = SqliteStore::open(path)?;
+        store.begin()?;
+        store
+            .conn
+            .execute_batch("DELETE FROM agent_state; DELETE FROM openmls_values;")?;
+        values.for_each(|key, value| store.put(key, value))?;
+        values.attach(store);
+    }
+    let state_json = values.omitting(|| json_encode(state))?;
+    values
+        .with_store(|store| store.commit(&state_json))
+        .unwrap_or_else(|| Err(MySgmError::State("No state database attached".to_string())))
+}
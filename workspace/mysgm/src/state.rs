@@ -1,27 +1,176 @@
-use super::keys::SignatureKeyPair;
+use super::{keys::SignatureKeyPair, migrate::SCHEMA_VERSION, sqlite::SqliteStore};
 
 use hex::{decode as hex_decode, encode as hex_encode};
-use openmls::{key_packages::KeyPackage, versions::ProtocolVersion};
+use openmls::{credentials::Credential, key_packages::KeyPackage, versions::ProtocolVersion};
 use openmls_traits::{
     storage::{CURRENT_VERSION, Entity, StorageProvider, traits},
     types::Ciphersuite,
 };
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MySgmState {
+    /// Version of the schema the state was written with, see [`migrate`](super::migrate)
+    #[serde(default)]
+    schema_version: u64,
     pid: String,
     signature_key_pair: SignatureKeyPair,
+    /// Key pair replaced by a rotation, until every group is updated to the new one
+    #[serde(default)]
+    previous_signature_key_pair: Option<SignatureKeyPair>,
+    /// Credential of the agent, unless it is a basic credential naming the pid
+    #[serde(default)]
+    credential: Option<Credential>,
     mls_version: ProtocolVersion,
     my_ciphersuite: Ciphersuite,
+    /// Namespace prefixed to every adapter key, fixed when the state is created
+    #[serde(default)]
+    namespace: Option<String>,
     welcome_counter: u64,
+    #[serde(default)]
+    mailbox_counter: u64,
     key_package_counter: u64,
+    /// Last-resort key package of each pid, used once its single-use ones ran out
     key_packages: HashMap<String, KeyPackage>,
+    /// Single-use key packages of each pid, oldest first, each consumed by one add
+    #[serde(default)]
+    key_package_queues: HashMap<String, Vec<KeyPackage>>,
+    /// Single-use key packages of ours that are not known to be consumed yet
+    #[serde(default)]
+    key_package_pool: Vec<KeyPackage>,
+    /// Number of single-use key packages of ours kept published
+    #[serde(default)]
+    key_package_pool_size: usize,
+    /// Key packages of ours replaced by a newer one, whose private keys are kept until
+    /// they expire in case a welcome still uses them
+    #[serde(default)]
+    retired_key_packages: Vec<KeyPackage>,
     gids: Vec<String>,
+    #[serde(default)]
+    message_cursors: HashMap<String, EpochCursor>,
+    /// Application messages we sent in the current epoch of each group, which we cannot
+    /// decrypt ourselves
+    #[serde(default)]
+    sent_messages: HashMap<String, SentMessages>,
+    #[serde(default)]
+    inbox: Vec<ReceivedMessage>,
+    #[serde(default)]
+    proposal_cursors: HashMap<String, EpochCursor>,
+    /// Epoch in which we last asked to leave each group we are leaving
+    #[serde(default)]
+    pending_leaves: HashMap<String, u64>,
+    #[serde(default)]
+    group_policies: HashMap<String, GroupPolicy>,
+    /// Index of the next GroupInfo to publish for each group
+    #[serde(default)]
+    group_info_counters: HashMap<String, u64>,
+    /// Fingerprint of the signature key trusted for each pid, pinned when it is first seen
+    #[serde(default)]
+    pins: HashMap<String, String>,
+    /// Latest key package of each pid that came with a key other than the pinned one
+    #[serde(default)]
+    quarantine: HashMap<String, KeyPackage>,
+    /// Fingerprints of revoked signature keys, which are never pinned again
+    #[serde(default)]
+    revoked_keys: HashSet<String>,
+    #[serde(default)]
+    join_policy: JoinPolicy,
+    /// Welcomes staged by the join policy, waiting to be accepted or rejected
+    #[serde(default)]
+    invites: Vec<Invite>,
+    /// Id of the next staged invitation
+    #[serde(default)]
+    invite_counter: u64,
+    /// External commits of ours whose fate is unknown, by gid
+    #[serde(default)]
+    external_joins: HashMap<String, ExternalJoin>,
+    #[serde(skip_serializing_if = "OpenMlsKeyValueStore::is_omitted")]
     openmls_values: OpenMlsKeyValueStore,
 }
 
+/// Position of the next message (application message or proposal) to fetch for a group
+/// within one epoch.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EpochCursor {
+    epoch: u64,
+    index: u64,
+}
+
+/// Indices of the application messages we sent to a group within one epoch.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SentMessages {
+    epoch: u64,
+    indices: Vec<u64>,
+}
+
+/// Local settings of a group.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct GroupPolicy {
+    /// Publish the GroupInfo after each of our commits so that agents can join by
+    /// external commit
+    #[serde(default)]
+    pub external_join: bool,
+}
+
+/// Which welcomes the agent joins the groups of as they are downloaded.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct JoinPolicy {
+    #[serde(default)]
+    pub mode: JoinMode,
+    /// Inviters whose welcomes are accepted whatever the mode
+    #[serde(default)]
+    pub allowlist: Vec<String>,
+}
+
+/// What to do with a welcome from an inviter that is not allowlisted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JoinMode {
+    /// Join the group
+    #[default]
+    AcceptAll,
+    /// Drop the welcome
+    Allowlist,
+    /// Keep the welcome as an invitation until it is accepted or rejected
+    Stage,
+}
+
+/// An external commit of ours that a commit we could not validate outranked. The group
+/// is kept with our commit pending until a later GroupInfo shows whether it was merged.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ExternalJoin {
+    /// Epoch the commit was made for
+    pub epoch: u64,
+    /// Index of the GroupInfo the commit was made from
+    pub group_info_index: u64,
+}
+
+/// A welcome staged by the join policy.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Invite {
+    pub id: u64,
+    pub gid: String,
+    /// pid of the member that committed the welcome
+    pub inviter: String,
+    /// pids of the members of the group, including us
+    pub members: Vec<String>,
+    /// The serialized welcome message
+    pub welcome: Vec<u8>,
+}
+
+/// A decrypted application message waiting to be read.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReceivedMessage {
+    pub gid: String,
+    pub sender: String,
+    pub epoch: u64,
+    pub payload: Vec<u8>,
+}
+
 impl MySgmState {
     pub fn new(
         pid: String,
@@ -30,29 +179,79 @@ impl MySgmState {
         mls_version: ProtocolVersion,
     ) -> Self {
         Self {
+            schema_version: SCHEMA_VERSION,
             pid,
             signature_key_pair,
+            previous_signature_key_pair: None,
+            credential: None,
             my_ciphersuite,
             mls_version,
+            namespace: None,
             welcome_counter: 0,
+            mailbox_counter: 0,
             key_package_counter: 0,
             key_packages: HashMap::new(),
+            key_package_queues: HashMap::new(),
+            key_package_pool: Vec::new(),
+            key_package_pool_size: 0,
+            retired_key_packages: Vec::new(),
             gids: Vec::new(),
+            message_cursors: HashMap::new(),
+            sent_messages: HashMap::new(),
+            inbox: Vec::new(),
+            proposal_cursors: HashMap::new(),
+            pending_leaves: HashMap::new(),
+            group_policies: HashMap::new(),
+            group_info_counters: HashMap::new(),
+            pins: HashMap::new(),
+            quarantine: HashMap::new(),
+            revoked_keys: HashSet::new(),
+            join_policy: JoinPolicy::default(),
+            invites: Vec::new(),
+            invite_counter: 0,
+            external_joins: HashMap::new(),
             openmls_values: Default::default(),
         }
     }
+    pub fn schema_version(&self) -> u64 {
+        self.schema_version
+    }
     pub fn my_ciphersuite(&self) -> Ciphersuite {
         self.my_ciphersuite
     }
     pub fn mls_version(&self) -> ProtocolVersion {
         self.mls_version
     }
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+    pub fn set_namespace(&mut self, namespace: String) {
+        self.namespace = Some(namespace);
+    }
     pub fn my_pid(&self) -> &str {
         &self.pid
     }
     pub fn signature_key_pair(&self) -> &SignatureKeyPair {
         &self.signature_key_pair
     }
+    pub fn previous_signature_key_pair(&self) -> Option<&SignatureKeyPair> {
+        self.previous_signature_key_pair.as_ref()
+    }
+    /// Makes `signature_key_pair` the agent's key pair, keeping the current one as the
+    /// previous key pair.
+    pub fn rotate_signature_key_pair(&mut self, signature_key_pair: SignatureKeyPair) {
+        let previous = std::mem::replace(&mut self.signature_key_pair, signature_key_pair);
+        self.previous_signature_key_pair = Some(previous);
+    }
+    pub fn retire_previous_signature_key_pair(&mut self) -> Option<SignatureKeyPair> {
+        self.previous_signature_key_pair.take()
+    }
+    pub fn credential(&self) -> Option<&Credential> {
+        self.credential.as_ref()
+    }
+    pub fn set_credential(&mut self, credential: Credential) {
+        self.credential = Some(credential);
+    }
     pub fn openmls_values(&self) -> &OpenMlsKeyValueStore {
         &self.openmls_values
     }
@@ -62,8 +261,132 @@ impl MySgmState {
     pub fn set_key_package(&mut self, pid: &str, key_package: KeyPackage) {
         self.key_packages.insert(pid.to_string(), key_package);
     }
+    pub fn remove_key_package(&mut self, pid: &str) -> Option<KeyPackage> {
+        self.key_packages.remove(pid)
+    }
+    /// Stores a key package of `pid` as its last-resort key package, or at the end of its
+    /// queue if it is a single-use one.
+    pub fn store_key_package(&mut self, pid: &str, key_package: KeyPackage) {
+        if key_package.last_resort() {
+            self.set_key_package(pid, key_package);
+        } else {
+            self.queue_key_package(pid, key_package);
+        }
+    }
+    pub fn queued_key_packages(&self, pid: &str) -> &[KeyPackage] {
+        self.key_package_queues
+            .get(pid)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+    pub fn queue_key_package(&mut self, pid: &str, key_package: KeyPackage) {
+        self.key_package_queues
+            .entry(pid.to_string())
+            .or_default()
+            .push(key_package);
+    }
+    /// Keeps the queued key packages of `pid` for which `keep` holds.
+    pub fn retain_queued_key_packages(&mut self, pid: &str, keep: impl Fn(&KeyPackage) -> bool) {
+        if let Some(queue) = self.key_package_queues.get_mut(pid) {
+            queue.retain(|kp| keep(kp));
+            if queue.is_empty() {
+                self.key_package_queues.remove(pid);
+            }
+        }
+    }
+    /// Keeps the queued key packages of every pid for which `keep` holds.
+    pub fn retain_all_queued_key_packages(&mut self, keep: impl Fn(&KeyPackage) -> bool) {
+        for queue in self.key_package_queues.values_mut() {
+            queue.retain(|kp| keep(kp));
+        }
+        self.key_package_queues.retain(|_, queue| !queue.is_empty());
+    }
+    /// Drops `key_package` from the queue of `pid` once an add used it.
+    pub fn consume_key_package(&mut self, pid: &str, key_package: &KeyPackage) {
+        self.retain_queued_key_packages(pid, |kp| kp != key_package);
+    }
+    pub fn key_package_pool(&self) -> &[KeyPackage] {
+        &self.key_package_pool
+    }
+    pub fn add_to_key_package_pool(&mut self, key_package: KeyPackage) {
+        self.key_package_pool.push(key_package);
+    }
+    /// Removes the key packages of the pool that are in `stale`.
+    pub fn remove_from_key_package_pool(&mut self, stale: &[KeyPackage]) {
+        self.key_package_pool.retain(|kp| !stale.contains(kp));
+    }
+    /// Moves the whole pool to the retired key packages.
+    pub fn retire_key_package_pool(&mut self) {
+        let pool = std::mem::take(&mut self.key_package_pool);
+        self.retired_key_packages.extend(pool);
+    }
+    pub fn key_package_pool_size(&self) -> usize {
+        self.key_package_pool_size
+    }
+    pub fn set_key_package_pool_size(&mut self, size: usize) {
+        self.key_package_pool_size = size;
+    }
+    pub fn retired_key_packages(&self) -> &[KeyPackage] {
+        &self.retired_key_packages
+    }
+    pub fn retire_key_package(&mut self, key_package: KeyPackage) {
+        self.retired_key_packages.push(key_package);
+    }
+    /// Removes and returns the retired key packages for which `expired` holds.
+    pub fn prune_retired_key_packages(
+        &mut self,
+        expired: impl Fn(&KeyPackage) -> bool,
+    ) -> Vec<KeyPackage> {
+        let (pruned, kept) = std::mem::take(&mut self.retired_key_packages)
+            .into_iter()
+            .partition(|kp| expired(kp));
+        self.retired_key_packages = kept;
+        pruned
+    }
+    /// pids with a last-resort or a single-use key package, sorted.
     pub fn pids(&self) -> Vec<String> {
-        self.key_packages.keys().cloned().collect()
+        let mut pids: Vec<String> = self
+            .key_packages
+            .keys()
+            .chain(self.key_package_queues.keys())
+            .cloned()
+            .collect();
+        pids.sort();
+        pids.dedup();
+        pids
+    }
+    pub fn pin(&self, pid: &str) -> Option<&str> {
+        self.pins.get(pid).map(String::as_str)
+    }
+    pub fn set_pin(&mut self, pid: &str, fingerprint: &str) {
+        self.pins.insert(pid.to_string(), fingerprint.to_string());
+    }
+    pub fn remove_pin(&mut self, pid: &str) -> Option<String> {
+        self.pins.remove(pid)
+    }
+    pub fn pinned_pids(&self) -> Vec<String> {
+        self.pins.keys().cloned().collect()
+    }
+    pub fn quarantined(&self, pid: &str) -> Option<&KeyPackage> {
+        self.quarantine.get(pid)
+    }
+    pub fn quarantine(&mut self, pid: &str, key_package: KeyPackage) {
+        self.quarantine.insert(pid.to_string(), key_package);
+    }
+    pub fn release(&mut self, pid: &str) -> Option<KeyPackage> {
+        self.quarantine.remove(pid)
+    }
+    pub fn quarantined_pids(&self) -> Vec<String> {
+        self.quarantine.keys().cloned().collect()
+    }
+    pub fn is_revoked(&self, fingerprint: &str) -> bool {
+        self.revoked_keys.contains(fingerprint)
+    }
+    pub fn revoke_key(&mut self, fingerprint: &str) {
+        self.revoked_keys.insert(fingerprint.to_string());
+    }
+    pub fn unrevoke_key(&mut self, fingerprint: &str) {
+        self.revoked_keys.remove(fingerprint);
     }
     pub fn gids(&self) -> Vec<String> {
         self.gids.clone()
@@ -71,8 +394,30 @@ impl MySgmState {
     pub fn add_gid(&mut self, gid: String) {
         self.gids.push(gid);
     }
+    /// Forgets the group along with the cursors, leave request and policy kept for it.
     pub fn remove_gid(&mut self, gid: &str) {
         self.gids.retain(|g| g != gid);
+        self.message_cursors.remove(gid);
+        self.sent_messages.remove(gid);
+        self.proposal_cursors.remove(gid);
+        self.pending_leaves.remove(gid);
+        self.group_policies.remove(gid);
+        self.group_info_counters.remove(gid);
+    }
+    pub fn group_policy(&self, gid: &str) -> GroupPolicy {
+        self.group_policies.get(gid).copied().unwrap_or_default()
+    }
+    pub fn set_group_policy(&mut self, gid: &str, policy: GroupPolicy) {
+        self.group_policies.insert(gid.to_string(), policy);
+    }
+    pub fn group_info_counter(&self, gid: &str) -> u64 {
+        self.group_info_counters
+            .get(gid)
+            .copied()
+            .unwrap_or_default()
+    }
+    pub fn set_group_info_counter(&mut self, gid: &str, counter: u64) {
+        self.group_info_counters.insert(gid.to_string(), counter);
     }
     pub fn welcome_counter(&self) -> u64 {
         self.welcome_counter
@@ -80,24 +425,164 @@ impl MySgmState {
     pub fn increment_welcome_counter(&mut self) {
         self.welcome_counter += 1;
     }
+    pub fn mailbox_counter(&self) -> u64 {
+        self.mailbox_counter
+    }
+    pub fn increment_mailbox_counter(&mut self) {
+        self.mailbox_counter += 1;
+    }
     pub fn key_package_counter(&self) -> u64 {
         self.key_package_counter
     }
     pub fn increment_key_package_counter(&mut self) {
         self.key_package_counter += 1;
     }
+    /// Index of the next application message to fetch for `gid` in `epoch`; a cursor
+    /// left over from an earlier epoch counts as zero.
+    pub fn message_cursor(&self, gid: &str, epoch: u64) -> u64 {
+        match self.message_cursors.get(gid) {
+            Some(cursor) if cursor.epoch == epoch => cursor.index,
+            _ => 0,
+        }
+    }
+    pub fn set_message_cursor(&mut self, gid: &str, epoch: u64, index: u64) {
+        self.message_cursors
+            .insert(gid.to_string(), EpochCursor { epoch, index });
+    }
+    /// Whether the `index`-th application message of `gid` in `epoch` is one we sent.
+    pub fn is_sent_message(&self, gid: &str, epoch: u64, index: u64) -> bool {
+        self.sent_messages
+            .get(gid)
+            .is_some_and(|sent| sent.epoch == epoch && sent.indices.contains(&index))
+    }
+    /// Records that we sent the `index`-th application message of `gid` in `epoch`,
+    /// forgetting the messages we sent in earlier epochs.
+    pub fn add_sent_message(&mut self, gid: &str, epoch: u64, index: u64) {
+        let sent = self.sent_messages.entry(gid.to_string()).or_default();
+        if sent.epoch != epoch {
+            *sent = SentMessages {
+                epoch,
+                indices: Vec::new(),
+            };
+        }
+        sent.indices.push(index);
+    }
+    /// Index of the next proposal to fetch for `gid` in `epoch`.
+    pub fn proposal_cursor(&self, gid: &str, epoch: u64) -> u64 {
+        match self.proposal_cursors.get(gid) {
+            Some(cursor) if cursor.epoch == epoch => cursor.index,
+            _ => 0,
+        }
+    }
+    pub fn set_proposal_cursor(&mut self, gid: &str, epoch: u64, index: u64) {
+        self.proposal_cursors
+            .insert(gid.to_string(), EpochCursor { epoch, index });
+    }
+    /// Epoch of our latest leave proposal for `gid`, if we are leaving it.
+    pub fn pending_leave(&self, gid: &str) -> Option<u64> {
+        self.pending_leaves.get(gid).copied()
+    }
+    pub fn set_pending_leave(&mut self, gid: &str, epoch: u64) {
+        self.pending_leaves.insert(gid.to_string(), epoch);
+    }
+    pub fn join_policy(&self) -> &JoinPolicy {
+        &self.join_policy
+    }
+    pub fn set_join_policy(&mut self, policy: JoinPolicy) {
+        self.join_policy = policy;
+    }
+    pub fn invites(&self) -> &[Invite] {
+        &self.invites
+    }
+    pub fn invite(&self, id: u64) -> Option<&Invite> {
+        self.invites.iter().find(|invite| invite.id == id)
+    }
+    /// Stages `invite` under the next invitation id, which is returned.
+    pub fn stage_invite(&mut self, mut invite: Invite) -> u64 {
+        invite.id = self.invite_counter;
+        self.invite_counter += 1;
+        self.invites.push(invite);
+        self.invite_counter - 1
+    }
+    pub fn remove_invite(&mut self, id: u64) -> Option<Invite> {
+        let index = self.invites.iter().position(|invite| invite.id == id)?;
+        Some(self.invites.remove(index))
+    }
+    pub fn external_join(&self, gid: &str) -> Option<ExternalJoin> {
+        self.external_joins.get(gid).copied()
+    }
+    /// Pending external joins, sorted by gid.
+    pub fn external_joins(&self) -> Vec<(String, ExternalJoin)> {
+        let mut joins: Vec<_> = self
+            .external_joins
+            .iter()
+            .map(|(gid, join)| (gid.clone(), *join))
+            .collect();
+        joins.sort_by(|a, b| a.0.cmp(&b.0));
+        joins
+    }
+    pub fn add_external_join(&mut self, gid: &str, join: ExternalJoin) {
+        self.external_joins.insert(gid.to_string(), join);
+    }
+    pub fn remove_external_join(&mut self, gid: &str) {
+        self.external_joins.remove(gid);
+    }
+    pub fn push_message(&mut self, message: ReceivedMessage) {
+        self.inbox.push(message);
+    }
+    /// Removes and returns all unread messages for `gid`, oldest first.
+    pub fn take_messages(&mut self, gid: &str) -> Vec<ReceivedMessage> {
+        let (taken, kept) = self.inbox.drain(..).partition(|m| m.gid == gid);
+        self.inbox = kept;
+        taken
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct OpenMlsKeyValueStore {
-    values: RwLock<HashMap<String, String>>,
+    values: RwLock<Values>,
+}
+
+/// Hex-encoded keys and values of an [`OpenMlsKeyValueStore`], written through to a
+/// state database if one is attached.
+#[derive(Debug, Default)]
+struct Values {
+    map: HashMap<String, String>,
+    store: Option<SqliteStore>,
+    /// Whether the values are left out of the serialized state
+    omitted: bool,
+}
+
+impl Values {
+    fn get(&self, key: &str) -> Option<&String> {
+        self.map.get(key)
+    }
+    fn insert(&mut self, key: String, value: String) -> Result<(), OpenMlsKeyValueStoreError> {
+        if let Some(store) = &self.store {
+            store.put(&key, &value)?;
+        }
+        self.map.insert(key, value);
+        Ok(())
+    }
+    fn remove(&mut self, key: &str) -> Result<(), OpenMlsKeyValueStoreError> {
+        if let Some(store) = &self.store {
+            store.delete(key)?;
+        }
+        self.map.remove(key);
+        Ok(())
+    }
 }
 
+/// Clones the values only; the clone is not attached to a state database.
 impl Clone for OpenMlsKeyValueStore {
     fn clone(&self) -> Self {
         let values = self.values.read().unwrap();
         Self {
-            values: RwLock::new(values.clone()),
+            values: RwLock::new(Values {
+                map: values.map.clone(),
+                store: None,
+                omitted: false,
+            }),
         }
     }
 }
@@ -108,7 +593,7 @@ impl Serialize for OpenMlsKeyValueStore {
         S: Serializer,
     {
         let values = self.values.read().unwrap();
-        values.serialize(serializer)
+        values.map.serialize(serializer)
     }
 }
 
@@ -117,14 +602,49 @@ impl<'de> Deserialize<'de> for OpenMlsKeyValueStore {
     where
         D: Deserializer<'de>,
     {
-        let values = HashMap::deserialize(deserializer)?;
+        let map = HashMap::deserialize(deserializer)?;
         Ok(Self {
-            values: RwLock::new(values),
+            values: RwLock::new(Values {
+                map,
+                store: None,
+                omitted: false,
+            }),
         })
     }
 }
 
 impl OpenMlsKeyValueStore {
+    /// Writes all changes through to `store` from now on. `store` must already hold the
+    /// current values.
+    pub fn attach(&self, store: SqliteStore) {
+        self.values.write().unwrap().store = Some(store);
+    }
+    pub fn is_attached(&self) -> bool {
+        self.values.read().unwrap().store.is_some()
+    }
+    /// Runs `f` with the values left out of the state when it is serialized, for a state
+    /// database that keeps them in a table of their own.
+    pub fn omitting<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.values.write().unwrap().omitted = true;
+        let result = f();
+        self.values.write().unwrap().omitted = false;
+        result
+    }
+    pub fn is_omitted(&self) -> bool {
+        self.values.read().unwrap().omitted
+    }
+    /// Runs `f` on the attached state database, if any.
+    pub fn with_store<T>(&self, f: impl FnOnce(&SqliteStore) -> T) -> Option<T> {
+        self.values.read().unwrap().store.as_ref().map(f)
+    }
+    /// Calls `f` on every hex-encoded key and value, stopping at the first error.
+    pub fn for_each<E>(&self, mut f: impl FnMut(&str, &str) -> Result<(), E>) -> Result<(), E> {
+        let values = self.values.read().unwrap();
+        for (key, value) in &values.map {
+            f(key, value)?;
+        }
+        Ok(())
+    }
     /// Internal helper to abstract write operations.
     #[inline(always)]
     fn write<const VERSION: u16>(
@@ -138,7 +658,7 @@ impl OpenMlsKeyValueStore {
 
         log::trace!("{}", std::backtrace::Backtrace::capture());
 
-        values.insert(hex_encode(storage_key), hex_encode(value));
+        values.insert(hex_encode(storage_key), hex_encode(value))?;
         Ok(())
     }
 
@@ -154,17 +674,20 @@ impl OpenMlsKeyValueStore {
         log::trace!("{}", std::backtrace::Backtrace::capture());
 
         // fetch value from db, falling back to an empty list if doens't exist
-        let list_bytes = values
-            .entry(hex_encode(storage_key))
-            .or_insert("[]".to_owned());
+        let storage_key = hex_encode(storage_key);
+        let list_bytes = match values.get(&storage_key) {
+            Some(list_hex) => {
+                hex_decode(list_hex).map_err(|_| OpenMlsKeyValueStoreError::SerializationError)?
+            }
+            None => b"[]".to_vec(),
+        };
 
         // parse old value and push new data
-        let mut list: Vec<Vec<u8>> = serde_json::from_slice(&hex_decode(&list_bytes).unwrap())?;
+        let mut list: Vec<Vec<u8>> = serde_json::from_slice(&list_bytes)?;
         list.push(value);
 
-        // write back, reusing the old buffer
-        list_bytes.truncate(0);
-        serde_json::to_writer(hex_decode(list_bytes).unwrap(), &list)?;
+        // write back
+        values.insert(storage_key, hex_encode(serde_json::to_vec(&list)?))?;
 
         Ok(())
     }
@@ -181,19 +704,22 @@ impl OpenMlsKeyValueStore {
         log::trace!("{}", std::backtrace::Backtrace::capture());
 
         // fetch value from db, falling back to an empty list if doens't exist
-        let list_bytes = values
-            .entry(hex_encode(storage_key))
-            .or_insert("[]".to_owned());
+        let storage_key = hex_encode(storage_key);
+        let list_bytes = match values.get(&storage_key) {
+            Some(list_hex) => {
+                hex_decode(list_hex).map_err(|_| OpenMlsKeyValueStoreError::SerializationError)?
+            }
+            None => b"[]".to_vec(),
+        };
 
         // parse old value, find value to delete and remove it from list
-        let mut list: Vec<Vec<u8>> = serde_json::from_slice(&hex_decode(&list_bytes).unwrap())?;
+        let mut list: Vec<Vec<u8>> = serde_json::from_slice(&list_bytes)?;
         if let Some(pos) = list.iter().position(|stored_item| stored_item == &value) {
             list.remove(pos);
         }
 
-        // write back, reusing the old buffer
-        list_bytes.truncate(0);
-        serde_json::to_writer(hex_decode(list_bytes).unwrap(), &list)?;
+        // write back
+        values.insert(storage_key, hex_encode(serde_json::to_vec(&list)?))?;
 
         Ok(())
     }
@@ -263,7 +789,7 @@ impl OpenMlsKeyValueStore {
 
         log::trace!("{}", std::backtrace::Backtrace::capture());
 
-        values.remove(&hex_encode(storage_key));
+        values.remove(&hex_encode(storage_key))?;
 
         Ok(())
     }
@@ -275,6 +801,8 @@ pub enum OpenMlsKeyValueStoreError {
     UnsupportedValueTypeBytes,
     UnsupportedMethod,
     SerializationError,
+    /// The attached state database failed to write a value
+    DatabaseError,
 }
 
 impl core::fmt::Display for OpenMlsKeyValueStoreError {
@@ -361,7 +889,7 @@ impl StorageProvider<CURRENT_VERSION> for OpenMlsKeyValueStore {
         let key = build_key::<CURRENT_VERSION, &GroupId>(INTERIM_TRANSCRIPT_HASH_LABEL, group_id);
         let value = serde_json::to_vec(&interim_transcript_hash).unwrap();
 
-        values.insert(hex_encode(key), hex_encode(value));
+        values.insert(hex_encode(key), hex_encode(value))?;
         Ok(())
     }
 
@@ -377,7 +905,7 @@ impl StorageProvider<CURRENT_VERSION> for OpenMlsKeyValueStore {
         let key = build_key::<CURRENT_VERSION, &GroupId>(GROUP_CONTEXT_LABEL, group_id);
         let value = serde_json::to_vec(&group_context).unwrap();
 
-        values.insert(hex_encode(key), hex_encode(value));
+        values.insert(hex_encode(key), hex_encode(value))?;
         Ok(())
     }
 
@@ -393,7 +921,7 @@ impl StorageProvider<CURRENT_VERSION> for OpenMlsKeyValueStore {
         let key = build_key::<CURRENT_VERSION, &GroupId>(CONFIRMATION_TAG_LABEL, group_id);
         let value = serde_json::to_vec(&confirmation_tag).unwrap();
 
-        values.insert(hex_encode(key), hex_encode(value));
+        values.insert(hex_encode(key), hex_encode(value))?;
         Ok(())
     }
 
@@ -410,7 +938,7 @@ impl StorageProvider<CURRENT_VERSION> for OpenMlsKeyValueStore {
             build_key::<CURRENT_VERSION, &SignaturePublicKey>(SIGNATURE_KEY_PAIR_LABEL, public_key);
         let value = serde_json::to_vec(&signature_key_pair).unwrap();
 
-        values.insert(hex_encode(key), hex_encode(value));
+        values.insert(hex_encode(key), hex_encode(value))?;
         Ok(())
     }
 
@@ -550,9 +1078,6 @@ impl StorageProvider<CURRENT_VERSION> for OpenMlsKeyValueStore {
         let value = serde_json::to_vec(&key_package).unwrap();
 
         self.write::<CURRENT_VERSION>(KEY_PACKAGE_LABEL, &key, value)
-            .unwrap();
-
-        Ok(())
     }
 
     fn write_psk<
@@ -879,14 +1404,15 @@ impl StorageProvider<CURRENT_VERSION> for OpenMlsKeyValueStore {
             self.read_list(PROPOSAL_QUEUE_REFS_LABEL, &serde_json::to_vec(group_id)?)?;
         let mut values = self.values.write().unwrap();
         for proposal_ref in proposal_refs {
-            // Delete all proposals.
-            let key = serde_json::to_vec(&(group_id, proposal_ref))?;
-            values.remove(&hex_encode(key));
+            // Delete all proposals, under the same key queue_proposal wrote them to.
+            let key =
+                build_key::<CURRENT_VERSION, _>(QUEUED_PROPOSAL_LABEL, (group_id, proposal_ref));
+            values.remove(&hex_encode(key))?;
         }
 
         // Delete the proposal refs from the store.
         let key = build_key::<CURRENT_VERSION, &GroupId>(PROPOSAL_QUEUE_REFS_LABEL, group_id);
-        values.remove(&hex_encode(key));
+        values.remove(&hex_encode(key))?;
 
         Ok(())
     }
@@ -1033,3 +1559,10 @@ impl From<serde_json::Error> for OpenMlsKeyValueStoreError {
         Self::SerializationError
     }
 }
+
+impl From<rusqlite::Error> for OpenMlsKeyValueStoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        log::error!("State database error: {e}");
+        Self::DatabaseError
+    }
+}
@@ -0,0 +1,230 @@
+//! X.509 credentials, as an alternative to basic credentials naming the pid.
+//!
+//! An agent created with `--x509-cert` and `--x509-key` signs with the key of its
+//! certificate and advertises the certificate chain in its leaf nodes, leaf first, as the
+//! MLS `Certificate certificates<V>` structure. Its pid is the common name of the
+//! certificate subject. Peers holding a trust anchor bundle validate such credentials when
+//! they download a key package and when a welcome or commit brings the member into a
+//! group: the chain has to lead to one of the anchors, every certificate and the anchor
+//! have to be valid at the time, and the leaf certificate, which may not be a CA, has to
+//! certify the leaf's signature key.
+
+use super::{adapter::check_key_part, error::MySgmError, keys::SignatureKeyPair};
+
+use openmls::credentials::{Credential, CredentialType};
+use openmls_rust_crypto::RustCrypto;
+use openmls_traits::{crypto::OpenMlsCrypto, types::SignatureScheme};
+use pkcs8::{
+    ObjectIdentifier, PrivateKeyInfo,
+    der::{Decode, asn1::OctetStringRef},
+};
+use std::fs::read as read_file;
+use tls_codec::{Deserialize, Serialize, VLBytes};
+use x509_parser::{certificate::X509Certificate, parse_x509_certificate, pem::Pem};
+
+const ED25519: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+const EC_PUBLIC_KEY: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.2.1");
+const SECP256R1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.3.1.7");
+
+/// Certificates a peer's chain has to lead to.
+#[derive(Clone, Debug)]
+pub struct TrustAnchors {
+    /// DER-encoded CA certificates
+    certs: Vec<Vec<u8>>,
+}
+
+impl TrustAnchors {
+    /// Reads every certificate of a PEM bundle.
+    pub fn from_pem_file(path: &str) -> Result<Self, MySgmError> {
+        let certs = read_pem_certs(path)?;
+        if certs.is_empty() {
+            return Err(MySgmError::InvalidArgument(format!(
+                "No certificates in {path}"
+            )));
+        }
+        Ok(Self { certs })
+    }
+}
+
+/// Identity loaded from a certificate chain and its key.
+pub struct X509Identity {
+    pub pid: String,
+    pub signature_key_pair: SignatureKeyPair,
+    /// DER-encoded certificates, leaf first
+    pub chain: Vec<Vec<u8>>,
+}
+
+/// Loads the PEM certificate chain at `cert_path`, leaf first, and the PKCS#8 PEM private
+/// key of the leaf certificate at `key_path`.
+pub fn load_identity(cert_path: &str, key_path: &str) -> Result<X509Identity, MySgmError> {
+    let chain = read_pem_certs(cert_path)?;
+    let leaf = chain
+        .first()
+        .ok_or_else(|| MySgmError::InvalidArgument(format!("No certificates in {cert_path}")))?;
+    let leaf = parse(leaf)?;
+    let public = leaf.public_key().subject_public_key.data.to_vec();
+    let key_pem = read_file(key_path)?;
+    let key = Pem::iter_from_buffer(&key_pem)
+        .filter_map(Result::ok)
+        .find(|pem| pem.label == "PRIVATE KEY")
+        .ok_or_else(|| {
+            MySgmError::InvalidArgument(format!("No PKCS#8 private key in {key_path}"))
+        })?;
+    let signature_key_pair = key_pair(&key.contents, public)?;
+    let pid = subject_pid(&leaf);
+    check_key_part("Certificate common name", &pid)?;
+    Ok(X509Identity {
+        pid,
+        signature_key_pair,
+        chain,
+    })
+}
+
+/// The X.509 credential carrying `chain`.
+pub fn credential(chain: &[Vec<u8>]) -> Result<Credential, MySgmError> {
+    let certificates: Vec<VLBytes> = chain.iter().map(|cert| cert.clone().into()).collect();
+    Ok(Credential::new(
+        CredentialType::X509,
+        certificates.tls_serialize_detached()?,
+    ))
+}
+
+/// pid named by the leaf certificate of an X.509 credential, without validating it.
+pub fn pid_of(credential: &Credential) -> Result<String, MySgmError> {
+    let chain = chain_of(credential)?;
+    Ok(subject_pid(&parse(&chain[0])?))
+}
+
+/// Checks an X.509 credential against `anchors` and the signature key it is used with.
+pub fn validate(
+    credential: &Credential,
+    signature_key: &[u8],
+    anchors: &TrustAnchors,
+) -> Result<(), MySgmError> {
+    let chain = chain_of(credential)?;
+    let certs = chain
+        .iter()
+        .map(|cert| parse(cert))
+        .collect::<Result<Vec<_>, _>>()?;
+    let leaf = &certs[0];
+    let pid = subject_pid(leaf);
+    let invalid = |reason: String| MySgmError::InvalidCredential(format!("{pid}: {reason}"));
+    if leaf.public_key().subject_public_key.data.as_ref() != signature_key {
+        return Err(invalid(
+            "the certificate is for another signature key".to_string(),
+        ));
+    }
+    if leaf.is_ca() {
+        return Err(invalid("the certificate is a CA certificate".to_string()));
+    }
+    for cert in &certs {
+        if !cert.validity().is_valid() {
+            return Err(invalid(format!("{} is not valid now", cert.subject())));
+        }
+    }
+    for pair in certs.windows(2) {
+        let (cert, issuer) = (&pair[0], &pair[1]);
+        if !issuer.is_ca() || cert.issuer() != issuer.subject() {
+            return Err(invalid(format!(
+                "{} is not the issuer of {}",
+                issuer.subject(),
+                cert.subject()
+            )));
+        }
+        cert.verify_signature(Some(issuer.public_key()))
+            .map_err(|e| invalid(format!("{}: {e}", cert.subject())))?;
+    }
+    let last = &certs[certs.len() - 1];
+    let anchored = anchors.certs.iter().any(|der| {
+        parse(der).is_ok_and(|anchor| {
+            anchor.validity().is_valid()
+                && (der == &chain[chain.len() - 1]
+                    || (anchor.subject() == last.issuer()
+                        && last.verify_signature(Some(anchor.public_key())).is_ok()))
+        })
+    });
+    if !anchored {
+        return Err(invalid(format!(
+            "{} is not issued by a currently valid trust anchor",
+            last.issuer()
+        )));
+    }
+    Ok(())
+}
+
+fn invalid_key(e: impl core::fmt::Display) -> MySgmError {
+    MySgmError::InvalidArgument(format!("Private key: {e}"))
+}
+
+fn read_pem_certs(path: &str) -> Result<Vec<Vec<u8>>, MySgmError> {
+    let mut certs = Vec::new();
+    for pem in Pem::iter_from_buffer(&read_file(path)?) {
+        let pem = pem.map_err(|e| MySgmError::InvalidArgument(format!("{path}: {e}")))?;
+        if pem.label == "CERTIFICATE" {
+            certs.push(pem.contents);
+        }
+    }
+    Ok(certs)
+}
+
+fn parse(der: &[u8]) -> Result<X509Certificate<'_>, MySgmError> {
+    let (_, cert) =
+        parse_x509_certificate(der).map_err(|e| MySgmError::InvalidCredential(e.to_string()))?;
+    Ok(cert)
+}
+
+fn chain_of(credential: &Credential) -> Result<Vec<Vec<u8>>, MySgmError> {
+    let certificates = Vec::<VLBytes>::tls_deserialize_exact(credential.serialized_content())
+        .map_err(|e| MySgmError::InvalidCredential(e.to_string()))?;
+    if certificates.is_empty() {
+        return Err(MySgmError::InvalidCredential(
+            "Empty certificate chain".to_string(),
+        ));
+    }
+    Ok(certificates
+        .into_iter()
+        .map(|cert| cert.as_slice().to_vec())
+        .collect())
+}
+
+/// Common name of the certificate subject, or the whole subject if it has none.
+fn subject_pid(cert: &X509Certificate) -> String {
+    cert.subject()
+        .iter_common_name()
+        .find_map(|cn| cn.as_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| cert.subject().to_string())
+}
+
+/// Signature key pair from a PKCS#8 private key and the public key of its certificate,
+/// checking that the two belong together.
+fn key_pair(key_der: &[u8], public: Vec<u8>) -> Result<SignatureKeyPair, MySgmError> {
+    let info = PrivateKeyInfo::try_from(key_der).map_err(invalid_key)?;
+    let algorithm = info.algorithm.oid;
+    let (private, signature_scheme) = if algorithm == ED25519 {
+        let seed = OctetStringRef::from_der(info.private_key).map_err(invalid_key)?;
+        (seed.as_bytes().to_vec(), SignatureScheme::ED25519)
+    } else if algorithm == EC_PUBLIC_KEY && info.algorithm.parameters_oid().ok() == Some(SECP256R1)
+    {
+        let key = sec1::EcPrivateKey::try_from(info.private_key).map_err(invalid_key)?;
+        (
+            key.private_key.to_vec(),
+            SignatureScheme::ECDSA_SECP256R1_SHA256,
+        )
+    } else {
+        return Err(invalid_key(format!("unsupported algorithm {algorithm}")));
+    };
+    let crypto = RustCrypto::default();
+    let message = b"mysgm x509 key check";
+    let signature = crypto
+        .sign(signature_scheme, message, &private)
+        .map_err(|e| invalid_key(format!("{e:?}")))?;
+    crypto
+        .verify_signature(signature_scheme, message, &public, &signature)
+        .map_err(|_| invalid_key("it does not belong to the certificate"))?;
+    Ok(SignatureKeyPair::from_raw(
+        private,
+        public,
+        signature_scheme,
+    ))
+}
@@ -0,0 +1,63 @@
+//! Agents using a ciphersuite other than the default, and agents of different
+//! ciphersuites meeting.
+
+mod common;
+
+use common::{CIPHERSUITE, MemoryStore, agent, group};
+use mysgm::{Agent, MySgmError, state::GroupPolicy};
+use openmls_traits::types::{Ciphersuite, SignatureScheme};
+
+const P256: Ciphersuite = Ciphersuite::MLS_128_DHKEMP256_AES128GCM_SHA256_P256;
+
+fn p256_agent(pid: &str, store: &MemoryStore) -> Agent {
+    Agent::new(
+        Agent::generate_state(pid, P256).unwrap(),
+        Box::new(store.clone()),
+    )
+}
+
+#[test]
+fn agents_of_another_ciphersuite_form_groups() {
+    let store = MemoryStore::default();
+    let mut alice = p256_agent("alice", &store);
+    let mut bob = p256_agent("bob", &store);
+    assert_eq!(alice.state().my_ciphersuite(), P256);
+    assert_eq!(
+        alice.state().signature_key_pair().signature_scheme(),
+        SignatureScheme::ECDSA_SECP256R1_SHA256
+    );
+
+    let gid = group("p256", &mut alice, &mut [&mut bob]);
+    alice.send_message(&gid, b"hello").unwrap();
+    bob.sync().unwrap();
+    assert_eq!(bob.read_messages(&gid).unwrap()[0].payload, b"hello");
+}
+
+#[test]
+fn unsupported_ciphersuite_is_refused() {
+    let Err(MySgmError::InvalidArgument(_)) = Agent::generate_state(
+        "alice",
+        Ciphersuite::MLS_256_DHKEMX448_AES256GCM_SHA512_Ed448,
+    ) else {
+        panic!("state generated with an unsupported ciphersuite");
+    };
+}
+
+#[test]
+fn agents_of_another_ciphersuite_are_not_added() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = p256_agent("bob", &store);
+    assert_ne!(CIPHERSUITE, P256);
+    bob.advertise(0).unwrap();
+    alice.sync().unwrap();
+    let gid = alice.create_group("mixed", GroupPolicy::default()).unwrap();
+
+    let Err(MySgmError::CiphersuiteMismatch(_)) =
+        alice.add_members(&gid, &[bob.state().my_pid().to_string()])
+    else {
+        panic!("agent of another ciphersuite added");
+    };
+    assert_eq!(alice.members(&gid).unwrap().len(), 1);
+    assert!(store.keys("wm_").is_empty());
+}
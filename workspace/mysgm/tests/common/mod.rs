@@ -0,0 +1,149 @@
+//! Fixtures shared by the integration tests.
+
+// every test crate uses only some of the fixtures
+#![allow(dead_code)]
+
+use mysgm::{
+    Agent,
+    adapter::{KeyExists, StorageAdapter},
+    keys::SignatureKeyPair,
+    state::{GroupPolicy, MySgmState},
+};
+use openmls::{
+    credentials::{BasicCredential, CredentialWithKey},
+    framing::MlsMessageOut,
+    key_packages::KeyPackage,
+};
+use openmls_rust_crypto::{OpenMlsRustCrypto, RustCrypto};
+use openmls_traits::types::Ciphersuite;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env::temp_dir,
+    error::Error,
+    fs::{create_dir_all, remove_dir_all},
+    path::PathBuf,
+    process,
+    rc::Rc,
+};
+use tls_codec::Serialize;
+
+pub const CIPHERSUITE: Ciphersuite =
+    Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519;
+
+/// Directory for the files of one test, removed when dropped.
+pub struct TestDir(pub PathBuf);
+
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let dir = temp_dir().join(format!("mysgm-{name}-{}", process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+    /// Path of the file `name` in the directory.
+    pub fn path(&self, name: &str) -> String {
+        self.0.join(name).to_str().unwrap().to_string()
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
+
+/// In-memory store keeping every distinct value put under a key, like the DHT proxy.
+#[derive(Clone, Default)]
+pub struct MemoryStore(pub Rc<RefCell<HashMap<String, Vec<Vec<u8>>>>>);
+
+impl MemoryStore {
+    /// Stores `value` next to the values already under `key`, as anyone can on the DHT.
+    pub fn insert(&self, key: &str, value: &[u8]) {
+        let mut values = self.0.borrow_mut();
+        let stored = values.entry(key.to_string()).or_default();
+        if !stored.iter().any(|stored| stored == value) {
+            stored.push(value.to_vec());
+        }
+    }
+    /// Keys holding at least one value whose name starts with `prefix`.
+    pub fn keys(&self, prefix: &str) -> Vec<String> {
+        let mut keys: Vec<_> = self
+            .0
+            .borrow()
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        keys.sort();
+        keys
+    }
+}
+
+impl StorageAdapter for MemoryStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Ok(self.get_all(key)?.into_iter().next())
+    }
+    fn get_all(&self, key: &str) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        Ok(self.0.borrow().get(key).cloned().unwrap_or_default())
+    }
+    /// Refuses a key that holds a value, like the adapters; `insert` races past that.
+    fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        if self.0.borrow().contains_key(key) {
+            return Err(Box::new(KeyExists));
+        }
+        self.insert(key, value);
+        Ok(())
+    }
+}
+
+/// Fresh state of an agent named after `pid`.
+pub fn state(pid: &str) -> MySgmState {
+    Agent::generate_state(pid, CIPHERSUITE).unwrap()
+}
+
+/// Fresh agent named after `pid`, sharing `store` with the other agents of the test.
+pub fn agent(pid: &str, store: &MemoryStore) -> Agent {
+    Agent::new(state(pid), Box::new(store.clone()))
+}
+
+/// Group `name` created by `creator`, which adds `members` and has them join.
+pub fn group(name: &str, creator: &mut Agent, members: &mut [&mut Agent]) -> String {
+    for member in members.iter_mut() {
+        member.advertise(0).unwrap();
+    }
+    creator.sync().unwrap();
+    let gid = creator.create_group(name, GroupPolicy::default()).unwrap();
+    let pids: Vec<_> = members
+        .iter()
+        .map(|member| member.state().my_pid().to_string())
+        .collect();
+    if !pids.is_empty() {
+        creator.add_members(&gid, &pids).unwrap();
+    }
+    for member in members.iter_mut() {
+        member.sync().unwrap();
+        assert!(member.state().gids().contains(&gid));
+    }
+    gid
+}
+
+/// Key package message of an agent naming itself `pid`, whatever it is, with a fresh
+/// signature key, and that key.
+pub fn key_package_message(pid: &str) -> (Vec<u8>, Vec<u8>) {
+    let provider = OpenMlsRustCrypto::default();
+    let signer =
+        SignatureKeyPair::from_crypto(&RustCrypto::default(), CIPHERSUITE.signature_algorithm())
+            .unwrap();
+    let credential_with_key = CredentialWithKey {
+        credential: BasicCredential::new(pid.as_bytes().to_vec()).into(),
+        signature_key: signer.public_key_raw().into(),
+    };
+    let bundle = KeyPackage::builder()
+        .build(CIPHERSUITE, &provider, &signer, credential_with_key)
+        .unwrap();
+    let message = MlsMessageOut::from(bundle.key_package().clone())
+        .tls_serialize_detached()
+        .unwrap();
+    (message, signer.public_key_raw().to_vec())
+}
@@ -0,0 +1,130 @@
+//! The daemon's socket protocol: one JSON request per connection answered by one JSON
+//! response, invalid requests refused, and ticks on wake-ups.
+
+mod common;
+
+use common::TestDir;
+use mysgm::{
+    MySgmError,
+    daemon::{DaemonHandler, DaemonResponse, request, serve},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::write,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
+};
+
+#[derive(Serialize, Deserialize)]
+enum Command {
+    Echo(String),
+    Fail,
+    Ticks,
+}
+
+struct Handler {
+    ticks: Arc<AtomicUsize>,
+    woken: Arc<AtomicBool>,
+}
+
+impl DaemonHandler<Command> for Handler {
+    fn tick(&mut self) {
+        self.ticks.fetch_add(1, Ordering::SeqCst);
+    }
+    fn request(&mut self, command: Command) -> DaemonResponse {
+        match command {
+            Command::Echo(text) => DaemonResponse::ok(text),
+            Command::Fail => DaemonResponse::error(&MySgmError::UnknownGroup("g".to_string())),
+            Command::Ticks => DaemonResponse::ok(self.ticks.load(Ordering::SeqCst).to_string()),
+        }
+    }
+    fn wake(&mut self) -> bool {
+        self.woken.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// Starts a daemon on `socket` that only ticks when woken, and waits for it to listen.
+fn start(socket: &str) -> Arc<AtomicBool> {
+    let woken = Arc::new(AtomicBool::new(false));
+    let mut handler = Handler {
+        ticks: Arc::default(),
+        woken: woken.clone(),
+    };
+    let path = socket.to_string();
+    spawn(move || serve(&path, Duration::from_secs(3600), &mut handler));
+    let started = Instant::now();
+    while UnixStream::connect(socket).is_err() {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "daemon not listening"
+        );
+        sleep(Duration::from_millis(10));
+    }
+    woken
+}
+
+#[test]
+fn requests_get_one_response_each() {
+    let dir = TestDir::new("daemon-requests");
+    let socket = dir.path("daemon.sock");
+    start(&socket);
+
+    let response = request(&socket, &Command::Echo("hello".to_string())).unwrap();
+    assert_eq!(response.output, "hello");
+    assert_eq!(response.error, None);
+    assert_eq!(response.exit_code, 0);
+    let response = request(&socket, &Command::Fail).unwrap();
+    assert_eq!(response.output, "");
+    assert!(response.error.unwrap().contains("g"));
+    assert_eq!(response.exit_code, 7);
+}
+
+#[test]
+fn invalid_requests_are_refused() {
+    let dir = TestDir::new("daemon-invalid");
+    let socket = dir.path("daemon.sock");
+    start(&socket);
+
+    let mut stream = UnixStream::connect(&socket).unwrap();
+    writeln!(stream, "not json").unwrap();
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line).unwrap();
+    let response: DaemonResponse = serde_json::from_str(&line).unwrap();
+    assert_eq!(response.exit_code, 11);
+    // the daemon keeps serving
+    let response = request(&socket, &Command::Echo("still".to_string())).unwrap();
+    assert_eq!(response.output, "still");
+}
+
+#[test]
+fn waking_the_daemon_ticks_it() {
+    let dir = TestDir::new("daemon-wake");
+    let socket = dir.path("daemon.sock");
+    let woken = start(&socket);
+    assert_eq!(request(&socket, &Command::Ticks).unwrap().output, "0");
+
+    woken.store(true, Ordering::SeqCst);
+    let started = Instant::now();
+    while request(&socket, &Command::Ticks).unwrap().output == "0" {
+        assert!(started.elapsed() < Duration::from_secs(10), "not ticked");
+        sleep(Duration::from_millis(10));
+    }
+    assert_eq!(request(&socket, &Command::Ticks).unwrap().output, "1");
+}
+
+#[test]
+fn stale_socket_file_is_replaced() {
+    let dir = TestDir::new("daemon-stale");
+    let socket = dir.path("daemon.sock");
+    write(&socket, b"left behind").unwrap();
+    start(&socket);
+
+    let response = request(&socket, &Command::Echo("fresh".to_string())).unwrap();
+    assert_eq!(response.output, "fresh");
+}
@@ -0,0 +1,202 @@
+//! Requests of the OpenDHT adapter against a scripted proxy: which failures are retried,
+//! puts that are not sent again once they may have taken effect, and the TLS and token
+//! settings.
+
+mod common;
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use common::TestDir;
+use mysgm::opendht::{OpenDhtConfig, OpenDhtRestAdapter};
+use std::{
+    fs::write as write_file,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread::spawn,
+    time::Duration,
+};
+
+/// Status the proxy never answers with, holding the connection open instead.
+const NO_ANSWER: u16 = 0;
+
+/// Head of a request: the request line and the headers, one per line.
+fn read_request(stream: &TcpStream) -> String {
+    let mut reader = BufReader::new(stream);
+    let mut head = String::new();
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line.trim_end().is_empty() {
+            break;
+        }
+        let lower = line.to_ascii_lowercase();
+        if let Some(value) = lower.strip_prefix("content-length:") {
+            length = value.trim().parse().unwrap();
+        }
+        head.push_str(&line);
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+    head
+}
+
+/// Proxy answering its requests, one per connection, with `responses` in turn and with
+/// server errors once they ran out. Returns its URL and the heads of the requests it got.
+fn proxy(responses: Vec<(u16, String)>) -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let recorded = requests.clone();
+    spawn(move || {
+        let mut responses = responses.into_iter();
+        let mut unanswered = Vec::new();
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let head = read_request(&stream);
+            recorded.lock().unwrap().push(head);
+            let (status, body) = responses.next().unwrap_or((500, String::new()));
+            if status == NO_ANSWER {
+                unanswered.push(stream);
+                continue;
+            }
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {status} Scripted\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+        }
+    });
+    (url, requests)
+}
+
+/// Body of a get finding `value`.
+fn stored(value: &[u8]) -> String {
+    format!(r#"[{{"data":"{}"}}]"#, STANDARD.encode(value))
+}
+
+fn config() -> OpenDhtConfig {
+    OpenDhtConfig {
+        timeout: Duration::from_millis(500),
+        max_attempts: 3,
+        backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(1),
+        ..OpenDhtConfig::default()
+    }
+}
+
+/// Methods of the requests the proxy got, in order.
+fn methods(requests: &Mutex<Vec<String>>) -> Vec<String> {
+    requests
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|head| head.split(' ').next().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn server_errors_and_timeouts_are_retried() {
+    let (url, requests) = proxy(vec![
+        (500, String::new()),
+        (NO_ANSWER, String::new()),
+        (200, stored(b"value")),
+    ]);
+    let adapter = OpenDhtRestAdapter::new(&url, config()).unwrap();
+
+    assert_eq!(adapter.get("kp0").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(methods(&requests), ["GET", "GET", "GET"]);
+}
+
+#[test]
+fn requests_fail_once_the_attempts_run_out() {
+    let (url, requests) = proxy(Vec::new());
+    let adapter = OpenDhtRestAdapter::new(&url, config()).unwrap();
+
+    assert!(adapter.get("kp0").is_err());
+    assert_eq!(requests.lock().unwrap().len(), 3);
+}
+
+#[test]
+fn client_errors_are_not_retried() {
+    let (url, requests) = proxy(vec![(404, String::new())]);
+    let adapter = OpenDhtRestAdapter::new(&url, config()).unwrap();
+
+    assert!(adapter.get("kp0").is_err());
+    assert_eq!(requests.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn failed_put_is_sent_again_only_if_the_value_is_missing() {
+    let (url, requests) = proxy(vec![
+        (500, String::new()),
+        (200, "[]".to_string()),
+        (200, String::new()),
+    ]);
+    let adapter = OpenDhtRestAdapter::new(&url, config()).unwrap();
+    adapter.put("cm0", b"value").unwrap();
+    assert_eq!(methods(&requests), ["POST", "GET", "POST"]);
+
+    // the value made it, so the put is not sent a second time
+    let (url, requests) = proxy(vec![(500, String::new()), (200, stored(b"value"))]);
+    let adapter = OpenDhtRestAdapter::new(&url, config()).unwrap();
+    assert!(adapter.put("cm0", b"value").is_err());
+    assert_eq!(methods(&requests), ["POST", "GET"]);
+}
+
+#[test]
+fn urls_of_other_schemes_are_refused() {
+    assert!(OpenDhtRestAdapter::new("ftp://127.0.0.1:8080", config()).is_err());
+    assert!(OpenDhtRestAdapter::new("127.0.0.1:8080", config()).is_err());
+}
+
+#[test]
+fn ca_bundles_and_client_certificates_need_https() {
+    let ca = format!("{}/tests/fixtures/x509/ca.pem", env!("CARGO_MANIFEST_DIR"));
+    let with_ca = OpenDhtConfig {
+        ca_bundle: Some(ca.clone()),
+        ..config()
+    };
+    assert!(OpenDhtRestAdapter::new("http://127.0.0.1:8080", with_ca.clone()).is_err());
+    assert!(OpenDhtRestAdapter::new("https://127.0.0.1:8080", with_ca).is_ok());
+    let with_identity = OpenDhtConfig {
+        client_identity: Some(ca),
+        ..config()
+    };
+    assert!(OpenDhtRestAdapter::new("http://127.0.0.1:8080", with_identity).is_err());
+}
+
+#[test]
+fn ca_bundles_without_certificates_are_refused() {
+    let dir = TestDir::new("dht-ca-bundle");
+    write_file(dir.path("empty.pem"), "").unwrap();
+    for path in [dir.path("empty.pem"), dir.path("missing.pem")] {
+        let config = OpenDhtConfig {
+            ca_bundle: Some(path),
+            ..config()
+        };
+        assert!(OpenDhtRestAdapter::new("https://127.0.0.1:8080", config).is_err());
+    }
+}
+
+#[test]
+fn bearer_token_is_sent_with_every_attempt() {
+    let (url, requests) = proxy(vec![(503, String::new()), (200, stored(b"value"))]);
+    let config = OpenDhtConfig {
+        bearer_token: Some("secret".to_string()),
+        ..config()
+    };
+    let adapter = OpenDhtRestAdapter::new(&url, config).unwrap();
+    adapter.get("kp0").unwrap();
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    for head in requests.iter() {
+        assert!(
+            head.to_ascii_lowercase()
+                .contains("authorization: bearer secret")
+        );
+    }
+    // the token is kept out of logs
+    assert!(!format!("{adapter:?}").contains("secret"));
+}
@@ -0,0 +1,72 @@
+//! The library's typed errors: what the agent returns for common mistakes, and the
+//! distinct exit code each error maps to.
+
+mod common;
+
+use common::{MemoryStore, agent};
+use mysgm::{MySgmError, state::GroupPolicy};
+use std::collections::HashSet;
+
+#[test]
+fn every_error_has_its_own_exit_code() {
+    let errors = [
+        MySgmError::Io(String::new()),
+        MySgmError::State(String::new()),
+        MySgmError::Adapter(String::new()),
+        MySgmError::Codec(String::new()),
+        MySgmError::Mls(String::new()),
+        MySgmError::UnknownGroup(String::new()),
+        MySgmError::GroupExists(String::new()),
+        MySgmError::UnknownAgent(String::new()),
+        MySgmError::UnexpectedMessage(String::new()),
+        MySgmError::InvalidArgument(String::new()),
+        MySgmError::CommitConflict(String::new()),
+        MySgmError::CiphersuiteMismatch(String::new()),
+        MySgmError::StateKey(String::new()),
+        MySgmError::UntrustedKey(String::new()),
+        MySgmError::InvalidCredential(String::new()),
+        MySgmError::ExpiredKeyPackage(String::new()),
+    ];
+    let codes: HashSet<_> = errors.iter().map(MySgmError::exit_code).collect();
+    assert_eq!(codes.len(), errors.len());
+    // 0 is success, 1 is left to clap and 101 to panics
+    for code in [0, 1, 101] {
+        assert!(!codes.contains(&code), "{code}");
+    }
+}
+
+#[test]
+fn unknown_groups_and_agents_are_reported_as_such() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+
+    let Err(MySgmError::UnknownGroup(gid)) = alice.send_message("nowhere_000", b"hi") else {
+        panic!("message sent to an unknown group");
+    };
+    assert_eq!(gid, "nowhere_000");
+    let Err(MySgmError::UnknownGroup(_)) = alice.read_messages("nowhere_000") else {
+        panic!("messages read from an unknown group");
+    };
+    let gid = alice.create_group("known", GroupPolicy::default()).unwrap();
+    let Err(MySgmError::UnknownAgent(pid)) = alice.add_members(&gid, &["nobody".to_string()])
+    else {
+        panic!("agent without a key package added");
+    };
+    assert_eq!(pid, "nobody");
+    assert_eq!(alice.members(&gid).unwrap().len(), 1);
+}
+
+#[test]
+fn creating_a_group_twice_fails() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let gid = alice.create_group("twice", GroupPolicy::default()).unwrap();
+
+    let Err(MySgmError::GroupExists(existing)) =
+        alice.create_group("twice", GroupPolicy::default())
+    else {
+        panic!("group created twice");
+    };
+    assert_eq!(existing, gid);
+    assert_eq!(alice.state().gids(), [gid]);
+}
@@ -0,0 +1,102 @@
+//! Key packages past their lifetime: refused when downloaded or used for an add, reported
+//! by `expired_key_packages`, dropped from the queues, and renewed by their owner.
+
+mod common;
+
+use common::{MemoryStore, agent};
+use mysgm::{MySgmError, state::GroupPolicy};
+use std::{slice, thread::sleep, time::Duration};
+
+/// Seconds the key packages of the tests are valid for.
+const LIFETIME: u64 = 2;
+
+fn wait_for_expiry() {
+    sleep(Duration::from_secs(LIFETIME + 1));
+}
+
+#[test]
+fn expired_key_packages_are_reported_and_not_used() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    alice.set_key_package_lifetime(LIFETIME);
+    alice.advertise(0).unwrap();
+    bob.sync().unwrap();
+    let alice_pid = alice.state().my_pid().to_string();
+    assert!(bob.state().key_package(&alice_pid).is_some());
+    assert!(bob.expired_key_packages().is_empty());
+
+    wait_for_expiry();
+    let not_after = bob
+        .state()
+        .key_package(&alice_pid)
+        .unwrap()
+        .life_time()
+        .not_after();
+    assert_eq!(
+        bob.expired_key_packages(),
+        [(alice_pid.clone(), not_after)].to_vec()
+    );
+    let gid = bob.create_group("late", GroupPolicy::default()).unwrap();
+    let Err(MySgmError::ExpiredKeyPackage(pid)) =
+        bob.add_members(&gid, slice::from_ref(&alice_pid))
+    else {
+        panic!("expired key package used");
+    };
+    assert_eq!(pid, alice_pid);
+    assert_eq!(bob.members(&gid).unwrap().len(), 1);
+}
+
+#[test]
+fn expired_key_packages_are_not_downloaded() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    alice.set_key_package_lifetime(LIFETIME);
+    alice.advertise(0).unwrap();
+
+    wait_for_expiry();
+    bob.sync().unwrap();
+    assert!(bob.state().key_package(alice.state().my_pid()).is_none());
+    assert_eq!(bob.state().key_package_counter(), 1);
+}
+
+#[test]
+fn expired_single_use_key_packages_are_dropped_from_the_queue() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    alice.set_key_package_lifetime(LIFETIME);
+    alice.advertise(2).unwrap();
+    bob.sync().unwrap();
+    let alice_pid = alice.state().my_pid().to_string();
+    assert_eq!(bob.state().queued_key_packages(&alice_pid).len(), 2);
+
+    wait_for_expiry();
+    bob.sync().unwrap();
+    assert!(bob.state().queued_key_packages(&alice_pid).is_empty());
+}
+
+#[test]
+fn own_key_package_is_renewed_before_it_expires() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    // less than a quarter of the lifetime is left from the start
+    alice.set_key_package_lifetime(LIFETIME);
+    alice.advertise(0).unwrap();
+    let advertised = alice.state().key_package(alice.state().my_pid()).cloned();
+
+    alice.set_key_package_lifetime(60 * 60);
+    alice.sync().unwrap();
+    let renewed = alice.state().key_package(alice.state().my_pid()).cloned();
+    assert_ne!(renewed, advertised);
+    assert_eq!(alice.state().retired_key_packages().len(), 1);
+    assert_eq!(store.keys("kp").len(), 2);
+
+    wait_for_expiry();
+    bob.sync().unwrap();
+    let alice_pid = alice.state().my_pid();
+    assert_eq!(bob.state().key_package(alice_pid), renewed.as_ref());
+    assert!(bob.expired_key_packages().is_empty());
+}
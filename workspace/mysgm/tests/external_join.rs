@@ -0,0 +1,79 @@
+//! External joins racing with invalid values stored under the commit key.
+//!
+//! The OpenDHT proxy keeps every value put under a key, so anyone who reads a published
+//! GroupInfo can store values next to the joiner's external commit. Members skip the
+//! values that are not valid commits; the joiner has to come to the same conclusion, or
+//! the members end up with a leaf for an agent that dropped the group.
+
+mod common;
+
+use common::{MemoryStore, agent, state};
+use mysgm::{
+    Agent,
+    adapter::{StorageAdapter, group_info_key},
+    state::GroupPolicy,
+};
+use std::error::Error;
+
+/// Store where invalid values race every put under `key`, landing next to the value put.
+struct Racing {
+    store: MemoryStore,
+    key: String,
+}
+
+impl StorageAdapter for Racing {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.store.get(key)
+    }
+    fn get_all(&self, key: &str) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        self.store.get_all(key)
+    }
+    fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        let put = self.store.put_checked(key, value);
+        if key == self.key {
+            // with this many, some rank ahead of the external commit whatever its hash
+            for i in 0..16 {
+                self.store.insert(key, format!("garbage {i}").as_bytes());
+            }
+        }
+        put
+    }
+}
+
+/// Key the next commit of the group goes to, as published with its GroupInfo.
+fn published_commit_key(store: &MemoryStore, gid: &str) -> String {
+    let published = store.get(&group_info_key(gid, 0)).unwrap().unwrap();
+    let published: serde_json::Value = serde_json::from_slice(&published).unwrap();
+    published["commit_key"].as_str().unwrap().to_string()
+}
+
+#[test]
+fn external_join_wins_over_invalid_values() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let gid = alice
+        .create_group(
+            "race",
+            GroupPolicy {
+                external_join: true,
+            },
+        )
+        .unwrap();
+    let racing = Racing {
+        store: store.clone(),
+        key: published_commit_key(&store, &gid),
+    };
+    let mut bob = Agent::new(state("bob"), Box::new(racing));
+
+    bob.join_external(&gid).unwrap();
+    assert!(bob.state().gids().contains(&gid));
+    assert!(bob.state().external_join(&gid).is_none());
+
+    alice.sync().unwrap();
+    assert_eq!(alice.members(&gid).unwrap().len(), 2);
+    assert_eq!(bob.members(&gid).unwrap().len(), 2);
+    assert_eq!(
+        alice.export_secret(&gid, "test", 32).unwrap(),
+        bob.export_secret(&gid, "test", 32).unwrap()
+    );
+}
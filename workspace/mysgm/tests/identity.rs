@@ -0,0 +1,79 @@
+//! Exporting an identity, with or without its groups, and importing it elsewhere.
+
+mod common;
+
+use common::{MemoryStore, agent, group, state};
+use mysgm::{
+    Agent, MySgmError,
+    envelope::{StateKey, is_sealed},
+    identity::{export, fingerprint, import, public_identity},
+};
+
+fn key() -> StateKey {
+    StateKey::from_passphrase("correct horse").unwrap()
+}
+
+#[test]
+fn identity_is_imported_without_groups() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    group("team", &mut alice, &mut [&mut bob]);
+
+    let bundle = export(alice.state(), &key(), false).unwrap();
+    assert!(is_sealed(&bundle));
+    let imported = import(&bundle, &key()).unwrap();
+    assert_eq!(imported.my_pid(), alice.state().my_pid());
+    assert_eq!(
+        imported.signature_key_pair().public_key_raw(),
+        alice.state().signature_key_pair().public_key_raw()
+    );
+    assert_eq!(imported.my_ciphersuite(), alice.state().my_ciphersuite());
+    assert!(imported.gids().is_empty());
+}
+
+#[test]
+fn identity_is_imported_with_groups() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    let gid = group("team", &mut alice, &mut [&mut bob]);
+
+    let bundle = export(alice.state(), &key(), true).unwrap();
+    drop(alice);
+    let mut moved = Agent::new(import(&bundle, &key()).unwrap(), Box::new(store.clone()));
+    assert_eq!(moved.state().gids(), [gid.as_str()]);
+    moved.send_message(&gid, b"moved").unwrap();
+    bob.sync().unwrap();
+    let read = bob.read_messages(&gid).unwrap();
+    assert_eq!(read[0].payload, b"moved");
+    assert_eq!(read[0].sender, moved.state().my_pid());
+}
+
+#[test]
+fn wrong_passphrase_is_refused() {
+    let bundle = export(&state("alice"), &key(), false).unwrap();
+    let wrong = StateKey::from_passphrase("battery staple").unwrap();
+
+    let Err(MySgmError::StateKey(_)) = import(&bundle, &wrong) else {
+        panic!("bundle imported with the wrong passphrase");
+    };
+    let Err(MySgmError::StateKey(_)) = import("{}", &key()) else {
+        panic!("unsealed bundle imported");
+    };
+}
+
+#[test]
+fn public_identity_carries_the_fingerprint_of_the_key() {
+    let state = state("alice");
+    let public = public_identity(&state).unwrap();
+    let public_key = state.signature_key_pair().public_key_raw();
+
+    assert_eq!(public.pid, state.my_pid());
+    assert_eq!(public.public_key, hex::encode(public_key));
+    assert_eq!(public.fingerprint, fingerprint(public_key).unwrap());
+    assert_eq!(public.fingerprint.len(), 64);
+    // the public identity holds no private key
+    let json = serde_json::to_string(&public).unwrap();
+    assert!(!json.contains("private"));
+}
@@ -0,0 +1,81 @@
+//! Which welcomes an agent joins: all of them, those of allowlisted inviters, or none
+//! until the staged invitation is accepted.
+
+mod common;
+
+use common::{MemoryStore, agent};
+use mysgm::{
+    Agent, MySgmError,
+    state::{GroupPolicy, JoinMode, JoinPolicy},
+};
+
+/// Has `inviter` create group `name` and add `invitee`, returning the gid.
+fn invite(inviter: &mut Agent, invitee: &Agent, name: &str) -> String {
+    inviter.sync().unwrap();
+    let gid = inviter.create_group(name, GroupPolicy::default()).unwrap();
+    inviter
+        .add_members(&gid, &[invitee.state().my_pid().to_string()])
+        .unwrap();
+    gid
+}
+
+#[test]
+fn allowlist_drops_welcomes_of_other_inviters() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut mallory = agent("mallory", &store);
+    let mut bob = agent("bob", &store);
+    bob.advertise(0).unwrap();
+    bob.set_join_policy(JoinPolicy {
+        mode: JoinMode::Allowlist,
+        allowlist: vec![alice.state().my_pid().to_string()],
+    });
+
+    let wanted = invite(&mut alice, &bob, "wanted");
+    let unwanted = invite(&mut mallory, &bob, "unwanted");
+    bob.sync().unwrap();
+    assert!(bob.state().gids().contains(&wanted));
+    assert!(!bob.state().gids().contains(&unwanted));
+    assert!(bob.state().invites().is_empty());
+    assert_eq!(bob.state().mailbox_counter(), 2);
+}
+
+#[test]
+fn staged_invitations_wait_for_a_decision() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut carol = agent("carol", &store);
+    let mut bob = agent("bob", &store);
+    bob.advertise(0).unwrap();
+    bob.set_join_policy(JoinPolicy {
+        mode: JoinMode::Stage,
+        allowlist: Vec::new(),
+    });
+
+    let accepted = invite(&mut alice, &bob, "accepted");
+    let rejected = invite(&mut carol, &bob, "rejected");
+    bob.sync().unwrap();
+    assert!(bob.state().gids().is_empty());
+    let invites = bob.state().invites().to_vec();
+    assert_eq!(invites.len(), 2);
+    let invite_of = |gid: &str| invites.iter().find(|invite| invite.gid == gid).unwrap();
+    assert_eq!(invite_of(&accepted).inviter, alice.state().my_pid());
+    assert_eq!(invite_of(&accepted).members.len(), 2);
+
+    assert_eq!(
+        bob.accept_invite(invite_of(&accepted).id).unwrap(),
+        accepted
+    );
+    bob.reject_invite(invite_of(&rejected).id).unwrap();
+    assert_eq!(bob.state().gids(), [accepted.as_str()]);
+    assert!(bob.state().invites().is_empty());
+    let Err(MySgmError::InvalidArgument(_)) = bob.accept_invite(invite_of(&rejected).id) else {
+        panic!("rejected invitation accepted");
+    };
+    bob.send_message(&accepted, b"joined").unwrap();
+    alice.sync().unwrap();
+    assert_eq!(
+        alice.read_messages(&accepted).unwrap()[0].payload,
+        b"joined"
+    );
+}
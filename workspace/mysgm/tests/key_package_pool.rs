@@ -0,0 +1,81 @@
+//! Single-use key packages: published next to the last-resort one, used before it by
+//! peers, and replenished by syncs once welcomes consumed them.
+
+mod common;
+
+use common::{MemoryStore, agent};
+use mysgm::state::GroupPolicy;
+use std::slice;
+
+#[test]
+fn advertise_publishes_a_pool_next_to_the_last_resort_key_package() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    alice.advertise(2).unwrap();
+    assert_eq!(alice.state().key_package_pool().len(), 2);
+    assert_eq!(alice.state().key_package_pool_size(), 2);
+    assert_eq!(store.keys("kp").len(), 3);
+
+    bob.sync().unwrap();
+    let alice_pid = alice.state().my_pid();
+    assert_eq!(
+        bob.state().queued_key_packages(alice_pid),
+        alice.state().key_package_pool()
+    );
+    assert_eq!(
+        bob.state().key_package(alice_pid),
+        alice.state().key_package(alice_pid)
+    );
+}
+
+#[test]
+fn single_use_key_packages_are_used_before_the_last_resort_one() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    alice.advertise(2).unwrap();
+    bob.sync().unwrap();
+    let alice_pid = alice.state().my_pid().to_string();
+
+    let mut gids = Vec::new();
+    for (name, left) in [("first", 1), ("second", 0), ("third", 0)] {
+        let gid = bob.create_group(name, GroupPolicy::default()).unwrap();
+        bob.add_members(&gid, slice::from_ref(&alice_pid)).unwrap();
+        assert_eq!(bob.state().queued_key_packages(&alice_pid).len(), left);
+        gids.push(gid);
+    }
+    // the last-resort key package stays available
+    assert!(bob.state().key_package(&alice_pid).is_some());
+    alice.sync().unwrap();
+    for gid in &gids {
+        assert!(alice.state().gids().contains(gid));
+    }
+}
+
+#[test]
+fn syncs_replenish_consumed_key_packages() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    alice.advertise(2).unwrap();
+    bob.sync().unwrap();
+    let alice_pid = alice.state().my_pid().to_string();
+    let advertised = alice.state().key_package_pool().to_vec();
+    let gid = bob.create_group("pool", GroupPolicy::default()).unwrap();
+    bob.add_members(&gid, slice::from_ref(&alice_pid)).unwrap();
+
+    alice.sync().unwrap();
+    assert!(alice.state().gids().contains(&gid));
+    let pool = alice.state().key_package_pool();
+    assert_eq!(pool.len(), 2);
+    assert!(pool.contains(&advertised[1]));
+    assert!(!pool.contains(&advertised[0]));
+    assert_eq!(store.keys("kp").len(), 4);
+    // nothing is published while the pool is full
+    alice.sync().unwrap();
+    assert_eq!(store.keys("kp").len(), 4);
+
+    bob.sync().unwrap();
+    assert_eq!(bob.state().queued_key_packages(&alice_pid).len(), 2);
+}
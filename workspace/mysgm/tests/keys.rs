@@ -0,0 +1,82 @@
+//! Storage keys built from pids and gids that peers choose.
+//!
+//! Mailbox, key rotation and GroupInfo keys carry a pid or gid as it is, and the file
+//! adapter and the DHT proxy turn keys into file paths and URLs. A peer naming itself
+//! `../x` must not get its key package accepted, nor make anyone write outside the store.
+
+mod common;
+
+use common::{CIPHERSUITE, MemoryStore, TestDir, agent, key_package_message};
+use mysgm::{Agent, MySgmError, agent::pid_of, file_adapter::FileAdapter, state::GroupPolicy};
+use openmls::credentials::BasicCredential;
+use std::fs::{create_dir_all, exists as file_exists};
+
+const HOSTILE: [&str; 6] = ["../../tmp/x", "a/b", "a b", "..", "a.b", ""];
+
+#[test]
+fn hostile_pids_are_refused_for_new_agents() {
+    for pid in HOSTILE {
+        let Err(MySgmError::InvalidArgument(_)) = Agent::generate_state(pid, CIPHERSUITE) else {
+            panic!("agent created with pid {pid:?}");
+        };
+    }
+}
+
+#[test]
+fn hostile_pids_are_refused_in_credentials() {
+    for pid in HOSTILE {
+        let credential = BasicCredential::new(pid.as_bytes().to_vec()).into();
+        let Err(MySgmError::InvalidCredential(_)) = pid_of(&credential) else {
+            panic!("credential with pid {pid:?} accepted");
+        };
+    }
+    let credential = BasicCredential::new(b"alice-1_x".to_vec()).into();
+    assert_eq!(pid_of(&credential).unwrap(), "alice-1_x");
+}
+
+#[test]
+fn key_package_with_hostile_pid_is_skipped() {
+    let store = MemoryStore::default();
+    store.insert("kp0", &key_package_message("../../tmp/x").0);
+    let mut alice = agent("alice", &store);
+    alice.advertise(0).unwrap();
+    let mut bob = agent("bob", &store);
+
+    bob.sync().unwrap();
+    assert_eq!(bob.state().key_package_counter(), 2);
+    assert!(bob.state().key_package("../../tmp/x").is_none());
+    assert!(bob.state().key_package(alice.state().my_pid()).is_some());
+    assert!(store.keys("wm_").is_empty());
+}
+
+#[test]
+fn hostile_gids_are_refused() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    for gid in HOSTILE {
+        let Err(MySgmError::InvalidArgument(_)) = alice.create_group(gid, GroupPolicy::default())
+        else {
+            panic!("group {gid:?} created");
+        };
+        let Err(MySgmError::InvalidArgument(_)) = alice.join_external(gid) else {
+            panic!("group {gid:?} joined");
+        };
+    }
+    assert!(store.keys("").is_empty());
+}
+
+#[test]
+fn file_adapter_keys_stay_in_the_directory() {
+    let dir = TestDir::new("keys-file-adapter");
+    let store = dir.0.join("store");
+    create_dir_all(&store).unwrap();
+    let adapter = FileAdapter::new(store.to_str().unwrap());
+
+    for key in ["../escape", "a/b", ".hidden", ""] {
+        assert!(adapter.put_checked(key, b"value").is_err(), "{key:?}");
+        assert!(adapter.get(key).is_err(), "{key:?}");
+    }
+    assert!(!file_exists(dir.0.join("escape")).unwrap());
+    adapter.put_checked("wm_alice_0", b"value").unwrap();
+    assert_eq!(adapter.get("wm_alice_0").unwrap(), Some(b"value".to_vec()));
+}
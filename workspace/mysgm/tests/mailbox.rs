@@ -0,0 +1,104 @@
+//! Welcomes delivered to the mailbox of each invitee, and the legacy shared sequence
+//! still being read.
+
+mod common;
+
+use common::{MemoryStore, agent};
+use mysgm::{
+    adapter::{StorageAdapter, welcome_mailbox_key, welcome_message_key},
+    state::GroupPolicy,
+};
+use std::slice;
+
+#[test]
+fn welcomes_go_to_the_mailbox_of_each_invitee() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    let mut carol = agent("carol", &store);
+    bob.advertise(0).unwrap();
+    carol.advertise(0).unwrap();
+    alice.sync().unwrap();
+    let gid = alice.create_group("trio", GroupPolicy::default()).unwrap();
+    let bob_pid = bob.state().my_pid().to_string();
+    let carol_pid = carol.state().my_pid().to_string();
+    alice
+        .add_members(&gid, &[bob_pid.clone(), carol_pid.clone()])
+        .unwrap();
+
+    assert!(
+        store
+            .get(&welcome_mailbox_key(&bob_pid, 0))
+            .unwrap()
+            .is_some()
+    );
+    assert!(
+        store
+            .get(&welcome_mailbox_key(&carol_pid, 0))
+            .unwrap()
+            .is_some()
+    );
+    assert!(store.get(&welcome_message_key(0)).unwrap().is_none());
+    bob.sync().unwrap();
+    carol.sync().unwrap();
+    assert!(bob.state().gids().contains(&gid));
+    assert!(carol.state().gids().contains(&gid));
+    assert_eq!(bob.state().mailbox_counter(), 1);
+    assert_eq!(alice.members(&gid).unwrap().len(), 3);
+}
+
+#[test]
+fn welcomes_from_several_inviters_fill_the_mailbox_in_turn() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut dave = agent("dave", &store);
+    let mut bob = agent("bob", &store);
+    // a single-use key package would serve only the first of the inviters
+    bob.advertise(0).unwrap();
+    alice.sync().unwrap();
+    dave.sync().unwrap();
+    let bob_pid = bob.state().my_pid().to_string();
+    let first = alice.create_group("first", GroupPolicy::default()).unwrap();
+    alice
+        .add_members(&first, slice::from_ref(&bob_pid))
+        .unwrap();
+    dave.sync().unwrap();
+    let second = dave.create_group("second", GroupPolicy::default()).unwrap();
+    dave.add_members(&second, slice::from_ref(&bob_pid))
+        .unwrap();
+
+    assert_eq!(
+        store.keys(&format!("wm_{bob_pid}_")),
+        [
+            welcome_mailbox_key(&bob_pid, 0),
+            welcome_mailbox_key(&bob_pid, 1)
+        ]
+    );
+    bob.sync().unwrap();
+    assert!(bob.state().gids().contains(&first));
+    assert!(bob.state().gids().contains(&second));
+    assert_eq!(bob.state().mailbox_counter(), 2);
+}
+
+#[test]
+fn legacy_welcomes_are_still_read() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    bob.advertise(0).unwrap();
+    alice.sync().unwrap();
+    let gid = alice
+        .create_group("legacy", GroupPolicy::default())
+        .unwrap();
+    let bob_pid = bob.state().my_pid().to_string();
+    alice.add_members(&gid, slice::from_ref(&bob_pid)).unwrap();
+    // as an inviter of an earlier version would have stored it
+    let mailbox = welcome_mailbox_key(&bob_pid, 0);
+    let welcome = store.0.borrow_mut().remove(&mailbox).unwrap();
+    store.insert(&welcome_message_key(0), &welcome[0]);
+
+    bob.sync().unwrap();
+    assert!(bob.state().gids().contains(&gid));
+    assert_eq!(bob.state().welcome_counter(), 1);
+    assert_eq!(bob.state().mailbox_counter(), 0);
+}
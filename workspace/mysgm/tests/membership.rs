@@ -0,0 +1,74 @@
+//! Removing members by pid, and members leaving on their own.
+
+mod common;
+
+use common::{MemoryStore, agent, group};
+use mysgm::{Agent, MySgmError};
+use std::slice;
+
+fn pids(agent: &Agent, gid: &str) -> Vec<String> {
+    let mut pids: Vec<_> = agent
+        .members(gid)
+        .unwrap()
+        .into_iter()
+        .map(|(_, pid)| pid)
+        .collect();
+    pids.sort();
+    pids
+}
+
+#[test]
+fn members_are_removed_by_pid() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    let mut carol = agent("carol", &store);
+    let gid = group("team", &mut alice, &mut [&mut bob, &mut carol]);
+    let bob_pid = bob.state().my_pid().to_string();
+
+    alice
+        .remove_members_by_pid(&gid, slice::from_ref(&bob_pid))
+        .unwrap();
+    assert!(!pids(&alice, &gid).contains(&bob_pid));
+    carol.sync().unwrap();
+    assert_eq!(pids(&carol, &gid), pids(&alice, &gid));
+    bob.sync().unwrap();
+    assert!(!bob.state().gids().contains(&gid));
+}
+
+#[test]
+fn removing_a_non_member_fails() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    let gid = group("team", &mut alice, &mut [&mut bob]);
+    let writes = alice.writes();
+
+    let Err(MySgmError::InvalidArgument(_)) =
+        alice.remove_members_by_pid(&gid, &["mallory_000".to_string()])
+    else {
+        panic!("non-member removed");
+    };
+    assert_eq!(alice.writes(), writes);
+    assert_eq!(alice.members(&gid).unwrap().len(), 2);
+}
+
+#[test]
+fn leaving_member_is_removed_by_the_next_member_to_sync() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    let mut carol = agent("carol", &store);
+    let gid = group("team", &mut alice, &mut [&mut bob, &mut carol]);
+    let bob_pid = bob.state().my_pid().to_string();
+
+    bob.leave_group(&gid).unwrap();
+    assert!(bob.state().gids().contains(&gid));
+    assert_eq!(store.keys("pr").len(), 1);
+    alice.sync().unwrap();
+    assert!(!pids(&alice, &gid).contains(&bob_pid));
+    carol.sync().unwrap();
+    assert_eq!(pids(&carol, &gid), pids(&alice, &gid));
+    bob.sync().unwrap();
+    assert!(!bob.state().gids().contains(&gid));
+}
@@ -0,0 +1,68 @@
+//! Application messages between members, including the ones we sent ourselves, which we
+//! cannot decrypt and have to skip.
+
+mod common;
+
+use common::{MemoryStore, agent};
+use mysgm::{Agent, state::GroupPolicy};
+
+/// Group of `alice` and `bob`, created by `alice`.
+fn group_of_two(alice: &mut Agent, bob: &mut Agent) -> String {
+    bob.advertise(0).unwrap();
+    alice.sync().unwrap();
+    let gid = alice.create_group("chat", GroupPolicy::default()).unwrap();
+    alice
+        .add_members(&gid, &[bob.state().my_pid().to_string()])
+        .unwrap();
+    bob.sync().unwrap();
+    assert!(bob.state().gids().contains(&gid));
+    gid
+}
+
+#[test]
+fn members_read_each_other_but_not_themselves() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    let gid = group_of_two(&mut alice, &mut bob);
+
+    alice.send_message(&gid, b"hello").unwrap();
+    bob.send_message(&gid, b"hi").unwrap();
+    alice.send_message(&gid, b"bye").unwrap();
+    assert_eq!(store.keys("am").len(), 3);
+    alice.sync().unwrap();
+    bob.sync().unwrap();
+
+    let read = alice.read_messages(&gid).unwrap();
+    assert_eq!(read.len(), 1);
+    assert_eq!(read[0].sender, bob.state().my_pid());
+    assert_eq!(read[0].payload, b"hi");
+    let read = bob.read_messages(&gid).unwrap();
+    let payloads: Vec<_> = read.iter().map(|m| m.payload.as_slice()).collect();
+    assert_eq!(payloads, [b"hello".as_slice(), b"bye".as_slice()]);
+    assert!(read.iter().all(|m| m.sender == alice.state().my_pid()));
+    // read messages are removed from the inbox
+    assert!(bob.read_messages(&gid).unwrap().is_empty());
+}
+
+#[test]
+fn own_messages_move_the_cursor() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    let gid = group_of_two(&mut alice, &mut bob);
+
+    // created in epoch 0, bob added in epoch 1
+    alice.send_message(&gid, b"hello").unwrap();
+    assert_eq!(alice.state().message_cursor(&gid, 1), 0);
+    alice.sync().unwrap();
+    assert_eq!(alice.state().message_cursor(&gid, 1), 1);
+    assert!(alice.read_messages(&gid).unwrap().is_empty());
+
+    // the next message goes after it, and bob reads it next
+    alice.send_message(&gid, b"again").unwrap();
+    bob.sync().unwrap();
+    let read = bob.read_messages(&gid).unwrap();
+    assert_eq!(read.len(), 2);
+    assert_eq!(read[1].payload, b"again");
+}
@@ -0,0 +1,119 @@
+//! Loading states written with every earlier schema version, and refusing newer ones.
+//!
+//! The fixture for a version is a fresh state with the fields added by later versions
+//! taken out, as that version of mysgm wrote it. Migrated, it has to come out as the
+//! fresh state it was made from.
+
+use mysgm::{
+    Agent, MySgmError,
+    migrate::{SCHEMA_VERSION, decode, upgrade},
+};
+use openmls_traits::types::Ciphersuite;
+use serde_json::{Value, json, to_value};
+
+/// Fields added by each migration, `ADDED[n]` by the one from version `n` to `n + 1`.
+const ADDED: [&[&str]; SCHEMA_VERSION as usize] = [
+    &[
+        "mailbox_counter",
+        "message_cursors",
+        "sent_messages",
+        "inbox",
+        "proposal_cursors",
+        "pending_leaves",
+        "group_policies",
+        "group_info_counters",
+        "external_joins",
+    ],
+    &["pins", "quarantine", "revoked_keys"],
+    &["join_policy", "invites", "invite_counter"],
+    &["credential"],
+    &["previous_signature_key_pair"],
+    &["retired_key_packages"],
+    &[
+        "key_package_queues",
+        "key_package_pool",
+        "key_package_pool_size",
+    ],
+    &["namespace"],
+];
+
+fn current() -> Value {
+    let state = Agent::generate_state(
+        "alice",
+        Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519,
+    )
+    .unwrap();
+    to_value(&state).unwrap()
+}
+
+/// `state` as written with schema `version`.
+fn fixture(state: &Value, version: u64) -> Value {
+    let mut fixture = state.clone();
+    let fields = fixture.as_object_mut().unwrap();
+    for added in &ADDED[version as usize..] {
+        for field in *added {
+            assert!(fields.remove(*field).is_some(), "no field {field}");
+        }
+    }
+    if version == 0 {
+        fields.remove("schema_version");
+    } else {
+        fields.insert("schema_version".to_string(), version.into());
+    }
+    fixture
+}
+
+#[test]
+fn current_state_is_at_the_schema_version() {
+    let mut state = current();
+    assert_eq!(state["schema_version"], json!(SCHEMA_VERSION));
+    assert_eq!(upgrade(&mut state).unwrap(), Vec::<String>::new());
+}
+
+#[test]
+fn states_of_every_version_are_migrated() {
+    let state = current();
+    for version in 0..SCHEMA_VERSION {
+        let fixture = fixture(&state, version);
+        let mut upgraded = fixture.clone();
+        let changes = upgrade(&mut upgraded).unwrap();
+        assert_eq!(upgraded, state, "from v{version}");
+        for from in version..SCHEMA_VERSION {
+            let prefix = format!("v{from} -> v{}: ", from + 1);
+            assert!(
+                changes.iter().any(|change| change.starts_with(&prefix)),
+                "no change {prefix}from v{version}"
+            );
+        }
+
+        let decoded = decode(&fixture.to_string()).unwrap();
+        assert_eq!(decoded.schema_version(), SCHEMA_VERSION);
+        assert_eq!(
+            to_value(&decoded).unwrap(),
+            state,
+            "decoded from v{version}"
+        );
+    }
+}
+
+#[test]
+fn newer_schema_is_refused() {
+    let mut state = current();
+    state["schema_version"] = json!(SCHEMA_VERSION + 1);
+    let before = state.clone();
+
+    let Err(MySgmError::State(_)) = upgrade(&mut state) else {
+        panic!("state of a newer schema upgraded");
+    };
+    assert_eq!(state, before);
+    let Err(MySgmError::State(_)) = decode(&state.to_string()) else {
+        panic!("state of a newer schema decoded");
+    };
+}
+
+#[test]
+fn invalid_schema_version_is_refused() {
+    let mut state = current();
+    state["schema_version"] = json!("9");
+    assert!(upgrade(&mut state).is_err());
+}
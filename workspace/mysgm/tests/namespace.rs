@@ -0,0 +1,79 @@
+//! Namespaced adapters over a file adapter: keys are prefixed with the namespace, so that
+//! deployments sharing a directory never see each other's values.
+
+mod common;
+
+use common::TestDir;
+use mysgm::{
+    MySgmError,
+    adapter::{KeyExists, NamespacedAdapter, StorageAdapter, WatchEvent, check_namespace},
+    file_adapter::FileAdapter,
+};
+use std::{fs::exists as file_exists, sync::mpsc::channel, time::Duration};
+
+fn adapter(dir: &TestDir) -> FileAdapter {
+    FileAdapter::new(dir.0.to_str().unwrap())
+}
+
+fn namespaced(dir: &TestDir, namespace: &str) -> NamespacedAdapter {
+    check_namespace(namespace).unwrap();
+    NamespacedAdapter::new(Box::new(adapter(dir)), namespace)
+}
+
+#[test]
+fn keys_are_prefixed_with_the_namespace() {
+    let dir = TestDir::new("namespace-prefix");
+    let red = namespaced(&dir, "red");
+    red.put_checked("kp0", b"red key package").unwrap();
+
+    assert!(file_exists(dir.0.join("red.kp0")).unwrap());
+    assert!(!file_exists(dir.0.join("kp0")).unwrap());
+    assert_eq!(red.get("kp0").unwrap(), Some(b"red key package".to_vec()));
+    assert_eq!(red.get_all("kp0").unwrap(), [b"red key package".to_vec()]);
+    assert_eq!(
+        adapter(&dir).get("red.kp0").unwrap(),
+        Some(b"red key package".to_vec())
+    );
+    assert_eq!(adapter(&dir).get("kp0").unwrap(), None);
+}
+
+#[test]
+fn namespaces_do_not_share_keys() {
+    let dir = TestDir::new("namespace-isolation");
+    let red = namespaced(&dir, "red");
+    let blue = namespaced(&dir, "blue");
+    red.put_checked("wm0", b"red").unwrap();
+
+    assert_eq!(blue.get("wm0").unwrap(), None);
+    blue.put_checked("wm0", b"blue").unwrap();
+    assert_eq!(red.get("wm0").unwrap(), Some(b"red".to_vec()));
+    assert_eq!(blue.get("wm0").unwrap(), Some(b"blue".to_vec()));
+    let taken = red.put_checked("wm0", b"again").unwrap_err();
+    assert!(taken.is::<KeyExists>());
+}
+
+#[test]
+fn watches_report_the_key_without_the_namespace() {
+    let dir = TestDir::new("namespace-watch");
+    let red = namespaced(&dir, "red");
+    let (events, received) = channel();
+    red.watch("cm0", events).unwrap();
+    red.put_checked("cm0", b"commit").unwrap();
+
+    let Ok(WatchEvent::Stored(key)) = received.recv_timeout(Duration::from_secs(10)) else {
+        panic!("no notification for the stored key");
+    };
+    assert_eq!(key, "cm0");
+}
+
+#[test]
+fn namespaces_are_checked() {
+    for namespace in ["red", "exp-1", "exp_2", "A9"] {
+        check_namespace(namespace).unwrap();
+    }
+    for namespace in ["", "a.b", "a/b", "../a", "a b", "é", "a\n"] {
+        let Err(MySgmError::InvalidArgument(_)) = check_namespace(namespace) else {
+            panic!("namespace {namespace:?} accepted");
+        };
+    }
+}
@@ -0,0 +1,158 @@
+//! Saving and loading the JSON state file: atomic replacement, backup rotation, the
+//! fallback to a backup when the state file cannot be read, and sealing.
+
+mod common;
+
+use common::{TestDir, state};
+use mysgm::{
+    MySgmError,
+    envelope::{StateKey, is_sealed},
+    persist::{backup_path, candidates, load, read, save},
+    state::MySgmState,
+};
+use std::{
+    fs::{metadata, read_dir, read_to_string, write},
+    os::unix::fs::PermissionsExt,
+    time::{Duration, Instant},
+};
+
+/// Name a state was generated for, without the suffix of its pid.
+fn name(state: &MySgmState) -> String {
+    state.my_pid().split('_').next().unwrap().to_string()
+}
+
+fn save_state(path: &str, pid: &str, backups: usize) -> String {
+    let json = serde_json::to_string(&state(pid)).unwrap();
+    save(path, &json, None, backups).unwrap();
+    json
+}
+
+#[test]
+fn save_replaces_the_state_file() {
+    let dir = TestDir::new("persist-save");
+    let path = dir.path("state.json");
+    save_state(&path, "alice", 3);
+    let json = save_state(&path, "bob", 3);
+
+    assert_eq!(read_to_string(&path).unwrap(), json);
+    assert_eq!(metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    // the temporary file was renamed over the state file
+    let mut files: Vec<_> = read_dir(&dir.0)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(files, ["state.json", "state.json.1"]);
+    assert_eq!(name(&load(&path, None).unwrap().state), "bob");
+}
+
+#[test]
+fn load_falls_back_to_the_newest_readable_backup() {
+    let dir = TestDir::new("persist-fallback");
+    let path = dir.path("state.json");
+    save_state(&path, "alice", 3);
+    save_state(&path, "bob", 3);
+    save_state(&path, "carol", 3);
+    write(&path, "{ truncated").unwrap();
+    write(backup_path(&path, 1), "").unwrap();
+
+    let loaded = load(&path, None).unwrap();
+    assert_eq!(loaded.path, backup_path(&path, 2));
+    assert_eq!(name(&loaded.state), "alice");
+}
+
+#[test]
+fn load_fails_when_no_file_can_be_read() {
+    let dir = TestDir::new("persist-unreadable");
+    let path = dir.path("state.json");
+    save_state(&path, "alice", 3);
+    save_state(&path, "bob", 3);
+    write(&path, "{ truncated").unwrap();
+    write(backup_path(&path, 1), "{ truncated").unwrap();
+
+    assert!(load(&path, None).is_err());
+}
+
+#[test]
+fn save_keeps_the_configured_number_of_backups() {
+    let dir = TestDir::new("persist-rotation");
+    let path = dir.path("state.json");
+    for pid in ["a", "b", "c", "d", "e", "f"] {
+        save_state(&path, pid, 3);
+    }
+    assert_eq!(
+        candidates(&path).unwrap(),
+        [
+            path.clone(),
+            backup_path(&path, 1),
+            backup_path(&path, 2),
+            backup_path(&path, 3)
+        ]
+    );
+    let pids: Vec<_> = candidates(&path)
+        .unwrap()
+        .iter()
+        .map(|path| name(&load(path, None).unwrap().state))
+        .collect();
+    assert_eq!(pids, ["f", "e", "d", "c"]);
+
+    let path = dir.path("unbacked.json");
+    save_state(&path, "a", 0);
+    save_state(&path, "b", 0);
+    assert_eq!(candidates(&path).unwrap(), [path]);
+}
+
+#[test]
+fn plaintext_state_is_refused_with_a_key() {
+    let dir = TestDir::new("persist-plaintext");
+    let path = dir.path("state.json");
+    save_state(&path, "alice", 3);
+    let key = StateKey::from_passphrase("passphrase").unwrap();
+
+    let Err(MySgmError::StateKey(_)) = load(&path, Some(&key)) else {
+        panic!("plaintext state loaded with a key");
+    };
+    let key = key.sealing_plaintext(true);
+    assert_eq!(name(&load(&path, Some(&key)).unwrap().state), "alice");
+}
+
+#[test]
+fn first_sealed_save_seals_the_backups() {
+    let dir = TestDir::new("persist-seal-backups");
+    let path = dir.path("state.json");
+    save_state(&path, "alice", 3);
+    save_state(&path, "bob", 3);
+    let key = StateKey::from_passphrase("passphrase")
+        .unwrap()
+        .sealing_plaintext(true);
+    let json = serde_json::to_string(&load(&path, Some(&key)).unwrap().state).unwrap();
+    save(&path, &json, Some(&key), 3).unwrap();
+
+    let key = StateKey::from_passphrase("passphrase").unwrap();
+    let mut pids = Vec::new();
+    for path in candidates(&path).unwrap() {
+        assert!(is_sealed(&read_to_string(&path).unwrap()), "{path}");
+        pids.push(name(&read(&path, Some(&key)).unwrap()));
+    }
+    assert_eq!(pids, ["bob", "bob", "alice"]);
+}
+
+#[test]
+fn excessive_kdf_costs_are_refused_before_deriving() {
+    let dir = TestDir::new("persist-kdf-costs");
+    let path = dir.path("state.json");
+    let json = serde_json::to_string(&state("alice")).unwrap();
+    let key = StateKey::from_passphrase("passphrase").unwrap();
+    save(&path, &json, Some(&key), 0).unwrap();
+    let mut envelope: serde_json::Value =
+        serde_json::from_str(&read_to_string(&path).unwrap()).unwrap();
+    envelope["m_cost"] = u32::MAX.into();
+    write(&path, envelope.to_string()).unwrap();
+
+    let key = StateKey::from_passphrase("passphrase").unwrap();
+    let started = Instant::now();
+    let Err(MySgmError::StateKey(_)) = read(&path, Some(&key)) else {
+        panic!("state with excessive KDF costs read");
+    };
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
@@ -0,0 +1,69 @@
+//! Proposals published by one member and committed later, by any member.
+
+mod common;
+
+use common::{MemoryStore, agent, group};
+use mysgm::MySgmError;
+
+#[test]
+fn another_member_commits_a_proposed_add() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    let mut carol = agent("carol", &store);
+    let gid = group("team", &mut alice, &mut [&mut bob]);
+    carol.advertise(0).unwrap();
+    alice.sync().unwrap();
+    let carol_pid = carol.state().my_pid().to_string();
+
+    alice.propose_add(&gid, &[carol_pid]).unwrap();
+    assert_eq!(alice.members(&gid).unwrap().len(), 2);
+    bob.sync().unwrap();
+    bob.commit_pending_proposals(&gid).unwrap();
+    assert_eq!(bob.members(&gid).unwrap().len(), 3);
+    carol.sync().unwrap();
+    alice.sync().unwrap();
+    assert!(carol.state().gids().contains(&gid));
+    assert_eq!(alice.members(&gid).unwrap().len(), 3);
+    let secret = alice.export_secret(&gid, "test", 32).unwrap();
+    assert_eq!(bob.export_secret(&gid, "test", 32).unwrap(), secret);
+    assert_eq!(carol.export_secret(&gid, "test", 32).unwrap(), secret);
+}
+
+#[test]
+fn proposer_commits_its_own_proposals() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    let mut carol = agent("carol", &store);
+    let gid = group("team", &mut alice, &mut [&mut bob, &mut carol]);
+    let carol_pid = carol.state().my_pid().to_string();
+
+    alice.propose_remove_by_pid(&gid, &[carol_pid]).unwrap();
+    alice.propose_self_update(&gid).unwrap();
+    assert_eq!(store.keys("pr").len(), 2);
+    alice.commit_pending_proposals(&gid).unwrap();
+    assert_eq!(alice.members(&gid).unwrap().len(), 2);
+    bob.sync().unwrap();
+    carol.sync().unwrap();
+    assert_eq!(bob.members(&gid).unwrap().len(), 2);
+    assert!(!carol.state().gids().contains(&gid));
+}
+
+#[test]
+fn committing_without_proposals_writes_nothing() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    let gid = group("team", &mut alice, &mut [&mut bob]);
+    let writes = bob.writes();
+
+    bob.commit_pending_proposals(&gid).unwrap();
+    assert_eq!(bob.writes(), writes);
+    let Err(MySgmError::InvalidArgument(_)) =
+        bob.propose_remove_by_pid(&gid, &["mallory_000".to_string()])
+    else {
+        panic!("removal of a non-member proposed");
+    };
+    assert!(store.keys("pr").is_empty());
+}
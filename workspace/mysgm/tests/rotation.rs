@@ -0,0 +1,99 @@
+//! Rotating the signature key: the announcement under `kr_{pid}_{index}`, the groups
+//! updated to the new key, and peers moving their pins along.
+
+mod common;
+
+use common::{CIPHERSUITE, MemoryStore, agent, group};
+use mysgm::{
+    Agent, adapter::key_rotation_key, identity::fingerprint, keys::SignatureKeyPair,
+    rotation::KeyRotation, state::GroupPolicy,
+};
+use openmls_rust_crypto::RustCrypto;
+
+fn key_of(agent: &Agent) -> String {
+    fingerprint(agent.state().signature_key_pair().public_key_raw()).unwrap()
+}
+
+#[test]
+fn rotated_key_is_announced_and_used_in_groups() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    let gid = group("rotating", &mut alice, &mut [&mut bob]);
+    let alice_pid = alice.state().my_pid().to_string();
+    let old = key_of(&alice);
+
+    assert!(alice.rotate_signature_key().unwrap().is_empty());
+    let new = key_of(&alice);
+    assert_ne!(new, old);
+    assert!(alice.state().previous_signature_key_pair().is_none());
+    assert_eq!(
+        store.keys("kr_"),
+        [key_rotation_key(&alice_pid, 0)].to_vec()
+    );
+    // bob sees the key change in the commit updating alice's leaf
+    bob.sync().unwrap();
+    assert_eq!(bob.pinned_fingerprint(&alice_pid).unwrap(), Some(new));
+    alice.send_message(&gid, b"new key").unwrap();
+    bob.sync().unwrap();
+    let read = bob.read_messages(&gid).unwrap();
+    assert_eq!(read[0].payload, b"new key");
+    assert_eq!(read[0].sender, alice_pid);
+}
+
+#[test]
+fn peers_outside_groups_follow_the_announcement() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut carol = agent("carol", &store);
+    alice.advertise(0).unwrap();
+    carol.sync().unwrap();
+    let alice_pid = alice.state().my_pid().to_string();
+
+    alice.rotate_signature_key().unwrap();
+    carol.sync().unwrap();
+    assert_eq!(
+        carol.pinned_fingerprint(&alice_pid).unwrap(),
+        Some(key_of(&alice))
+    );
+    assert!(carol.state().quarantined(&alice_pid).is_none());
+    let gid = carol
+        .create_group("followed", GroupPolicy::default())
+        .unwrap();
+    carol.add_members(&gid, &[alice_pid]).unwrap();
+    alice.sync().unwrap();
+    assert!(alice.state().gids().contains(&gid));
+}
+
+#[test]
+fn announcements_not_signed_by_the_pinned_key_are_ignored() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut carol = agent("carol", &store);
+    alice.advertise(0).unwrap();
+    carol.sync().unwrap();
+    let alice_pid = alice.state().my_pid().to_string();
+    let old = key_of(&alice);
+
+    // mallory announces a rotation from alice's key, signed with a key of hers
+    let crypto = RustCrypto::default();
+    let mallory =
+        SignatureKeyPair::from_crypto(&crypto, CIPHERSUITE.signature_algorithm()).unwrap();
+    let new = SignatureKeyPair::from_crypto(&crypto, CIPHERSUITE.signature_algorithm()).unwrap();
+    let rotation = KeyRotation::sign(&alice_pid, &mallory, new.public_key_raw()).unwrap();
+    let mut forged = serde_json::to_value(&rotation).unwrap();
+    forged["old_public_key"] =
+        hex::encode(alice.state().signature_key_pair().public_key_raw()).into();
+    store.insert(
+        &key_rotation_key(&alice_pid, 0),
+        &serde_json::to_vec(&forged).unwrap(),
+    );
+    alice.rotate_signature_key().unwrap();
+
+    // alice's own announcement lands next to the forged one, and is the one followed
+    carol.sync().unwrap();
+    let followed = carol.pinned_fingerprint(&alice_pid).unwrap();
+    assert_ne!(followed, Some(old));
+    assert_ne!(followed, Some(fingerprint(new.public_key_raw()).unwrap()));
+    assert_eq!(followed, Some(key_of(&alice)));
+}
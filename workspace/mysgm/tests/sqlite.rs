@@ -0,0 +1,47 @@
+//! The SQLite state backend: the agent state row, and writers waiting for each other.
+
+mod common;
+
+use common::{TestDir, state};
+use mysgm::sqlite::{self, BUSY_TIMEOUT};
+use rusqlite::Connection;
+use serde_json::Value;
+use std::{thread, time::Duration};
+
+#[test]
+fn agent_state_row_leaves_out_the_openmls_values() {
+    let dir = TestDir::new("sqlite-agent-state");
+    let path = dir.path("state.db");
+    let state = state("alice");
+    let values = serde_json::to_value(state.openmls_values()).unwrap();
+    sqlite::save(&path, &state).unwrap();
+
+    let row: Value = serde_json::from_str(&sqlite::read_json(&path).unwrap()).unwrap();
+    assert!(row.get("openmls_values").is_none());
+    assert_eq!(row["pid"], state.my_pid());
+    let loaded = sqlite::load(&path).unwrap();
+    assert_eq!(
+        serde_json::to_value(loaded.openmls_values()).unwrap(),
+        values
+    );
+    // the state itself still serializes in full, e.g. for JSON export
+    assert!(serde_json::to_value(&loaded).unwrap()["openmls_values"].is_object());
+}
+
+#[test]
+fn save_waits_for_another_writer() {
+    let dir = TestDir::new("sqlite-busy");
+    let path = dir.path("state.db");
+    sqlite::save(&path, &state("alice")).unwrap();
+    let state = sqlite::load(&path).unwrap();
+
+    let other = Connection::open(&path).unwrap();
+    other.execute_batch("BEGIN IMMEDIATE").unwrap();
+    let holder = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(500));
+        other.execute_batch("COMMIT").unwrap();
+    });
+    assert!(Duration::from_millis(500) < BUSY_TIMEOUT);
+    sqlite::save(&path, &state).unwrap();
+    holder.join().unwrap();
+}
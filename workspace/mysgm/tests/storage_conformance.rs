@@ -0,0 +1,373 @@
+//! Conformance suite for the OpenMLS storage provider.
+//!
+//! [`run_suite`] exercises every `StorageProvider` method against a [`Backend`], reopening
+//! the store between writing and reading so that every value has to survive the way the
+//! backend persists it. A new storage backend is covered by implementing [`Backend`] for
+//! it and adding a test that calls [`run_suite`].
+
+mod common;
+
+use common::TestDir;
+use mysgm::{
+    Agent, sqlite,
+    state::{MySgmState, OpenMlsKeyValueStore},
+};
+use openmls_traits::{
+    storage::{CURRENT_VERSION, Entity, Key, StorageProvider, traits},
+    types::Ciphersuite,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Storage under test, along with how it is persisted.
+trait Backend: Sized {
+    type Store: StorageProvider<CURRENT_VERSION>;
+    fn store(&self) -> &Self::Store;
+    /// Persists the store and loads it again, as the next run of an agent would.
+    fn reopen(self) -> Self;
+    /// Number of entries held, to check that deleting leaves nothing behind.
+    fn len(&self) -> usize;
+}
+
+/// Stand-in for every type OpenMLS uses as a storage key.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct TestKey(Vec<u8>);
+
+/// Stand-in for every type OpenMLS stores.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct TestValue(Vec<u8>);
+
+macro_rules! impl_storage_traits {
+    ($ty:ty: $($name:ident),* $(,)?) => {
+        $(impl traits::$name<CURRENT_VERSION> for $ty {})*
+    };
+}
+
+impl Key<CURRENT_VERSION> for TestKey {}
+impl Entity<CURRENT_VERSION> for TestKey {}
+impl_storage_traits!(TestKey:
+    GroupId, SignaturePublicKey, HashReference, PskId, EncryptionKey, EpochKey, ProposalRef,
+);
+
+impl Entity<CURRENT_VERSION> for TestValue {}
+impl_storage_traits!(TestValue:
+    QueuedProposal, TreeSync, GroupContext, InterimTranscriptHash, ConfirmationTag,
+    SignatureKeyPair, PskBundle, HpkeKeyPair, GroupState, GroupEpochSecrets, LeafNodeIndex,
+    MessageSecrets, ResumptionPskStore, KeyPackage, MlsGroupJoinConfig, LeafNode,
+);
+
+fn key(name: &str) -> TestKey {
+    TestKey(name.as_bytes().to_vec())
+}
+
+fn value(name: &str) -> TestValue {
+    TestValue(name.as_bytes().to_vec())
+}
+
+/// Writes a single value under `key`, reads it back after reopening, then deletes it.
+macro_rules! check_value {
+    ($backend:ident, $key:expr, $write:ident, $read:ident, $delete:ident) => {{
+        let written = value(stringify!($read));
+        $backend.store().$write(&$key, &written).unwrap();
+        $backend = $backend.reopen();
+        let read: Option<TestValue> = $backend.store().$read(&$key).unwrap();
+        assert_eq!(
+            read,
+            Some(written),
+            "{} after {}",
+            stringify!($read),
+            stringify!($write)
+        );
+        let other: Option<TestValue> = $backend.store().$read(&key("other")).unwrap();
+        assert_eq!(other, None, "{} of another key", stringify!($read));
+        $backend.store().$delete(&$key).unwrap();
+        $backend = $backend.reopen();
+        let read: Option<TestValue> = $backend.store().$read(&$key).unwrap();
+        assert_eq!(
+            read,
+            None,
+            "{} after {}",
+            stringify!($read),
+            stringify!($delete)
+        );
+    }};
+}
+
+fn check_group_values<B: Backend>(mut backend: B) -> B {
+    let group = key("group");
+    check_value!(backend, group, write_tree, tree, delete_tree);
+    check_value!(backend, group, write_context, group_context, delete_context);
+    check_value!(
+        backend,
+        group,
+        write_interim_transcript_hash,
+        interim_transcript_hash,
+        delete_interim_transcript_hash
+    );
+    check_value!(
+        backend,
+        group,
+        write_confirmation_tag,
+        confirmation_tag,
+        delete_confirmation_tag
+    );
+    check_value!(
+        backend,
+        group,
+        write_group_state,
+        group_state,
+        delete_group_state
+    );
+    check_value!(
+        backend,
+        group,
+        write_message_secrets,
+        message_secrets,
+        delete_message_secrets
+    );
+    check_value!(
+        backend,
+        group,
+        write_resumption_psk_store,
+        resumption_psk_store,
+        delete_all_resumption_psk_secrets
+    );
+    check_value!(
+        backend,
+        group,
+        write_own_leaf_index,
+        own_leaf_index,
+        delete_own_leaf_index
+    );
+    check_value!(
+        backend,
+        group,
+        write_group_epoch_secrets,
+        group_epoch_secrets,
+        delete_group_epoch_secrets
+    );
+    check_value!(
+        backend,
+        group,
+        write_mls_join_config,
+        mls_group_join_config,
+        delete_group_config
+    );
+    backend
+}
+
+fn check_key_values<B: Backend>(mut backend: B) -> B {
+    check_value!(
+        backend,
+        key("signature public key"),
+        write_signature_key_pair,
+        signature_key_pair,
+        delete_signature_key_pair
+    );
+    check_value!(
+        backend,
+        key("hash reference"),
+        write_key_package,
+        key_package,
+        delete_key_package
+    );
+    check_value!(backend, key("psk id"), write_psk, psk, delete_psk);
+    check_value!(
+        backend,
+        key("encryption key"),
+        write_encryption_key_pair,
+        encryption_key_pair,
+        delete_encryption_key_pair
+    );
+    backend
+}
+
+fn check_proposals<B: Backend>(mut backend: B) -> B {
+    let group = key("group");
+    let queued_refs =
+        |backend: &B| -> Vec<TestKey> { backend.store().queued_proposal_refs(&group).unwrap() };
+    let queued = |backend: &B| -> Vec<(TestKey, TestValue)> {
+        backend.store().queued_proposals(&group).unwrap()
+    };
+
+    for name in ["first", "second", "third"] {
+        backend
+            .store()
+            .queue_proposal(&group, &key(name), &value(name))
+            .unwrap();
+    }
+    backend = backend.reopen();
+    assert_eq!(
+        queued_refs(&backend),
+        [key("first"), key("second"), key("third")]
+    );
+    assert_eq!(
+        queued(&backend),
+        [
+            (key("first"), value("first")),
+            (key("second"), value("second")),
+            (key("third"), value("third")),
+        ]
+    );
+    let other: Vec<TestKey> = backend.store().queued_proposal_refs(&key("other")).unwrap();
+    assert!(other.is_empty());
+
+    backend
+        .store()
+        .remove_proposal(&group, &key("second"))
+        .unwrap();
+    backend = backend.reopen();
+    assert_eq!(queued_refs(&backend), [key("first"), key("third")]);
+    assert_eq!(
+        queued(&backend),
+        [
+            (key("first"), value("first")),
+            (key("third"), value("third"))
+        ]
+    );
+
+    backend
+        .store()
+        .clear_proposal_queue::<TestKey, TestKey>(&group)
+        .unwrap();
+    backend = backend.reopen();
+    assert!(queued_refs(&backend).is_empty());
+    assert!(queued(&backend).is_empty());
+    backend
+}
+
+fn check_own_leaf_nodes<B: Backend>(mut backend: B) -> B {
+    let group = key("group");
+    let leaf_nodes =
+        |backend: &B| -> Vec<TestValue> { backend.store().own_leaf_nodes(&group).unwrap() };
+
+    backend
+        .store()
+        .append_own_leaf_node(&group, &value("first"))
+        .unwrap();
+    backend = backend.reopen();
+    backend
+        .store()
+        .append_own_leaf_node(&group, &value("second"))
+        .unwrap();
+    backend = backend.reopen();
+    assert_eq!(leaf_nodes(&backend), [value("first"), value("second")]);
+
+    backend.store().delete_own_leaf_nodes(&group).unwrap();
+    backend = backend.reopen();
+    assert!(leaf_nodes(&backend).is_empty());
+    backend
+}
+
+fn check_epoch_key_pairs<B: Backend>(mut backend: B) -> B {
+    let group = key("group");
+    let epoch = key("epoch");
+    let key_pairs = |backend: &B, epoch: &TestKey, leaf_index| -> Vec<TestValue> {
+        backend
+            .store()
+            .encryption_epoch_key_pairs(&group, epoch, leaf_index)
+            .unwrap()
+    };
+
+    let written = [value("first"), value("second")];
+    backend
+        .store()
+        .write_encryption_epoch_key_pairs(&group, &epoch, 0, &written)
+        .unwrap();
+    backend = backend.reopen();
+    assert_eq!(key_pairs(&backend, &epoch, 0), written);
+    assert!(key_pairs(&backend, &epoch, 1).is_empty());
+    assert!(key_pairs(&backend, &key("other"), 0).is_empty());
+
+    backend
+        .store()
+        .delete_encryption_epoch_key_pairs(&group, &epoch, 0)
+        .unwrap();
+    backend = backend.reopen();
+    assert!(key_pairs(&backend, &epoch, 0).is_empty());
+    backend
+}
+
+/// A check of the suite, handing the backend back once it cleaned up.
+type Check<B> = fn(B) -> B;
+
+/// Runs every check against a fresh backend from `new`, and checks that each leaves the
+/// store empty once it deleted what it wrote.
+fn run_suite<B: Backend>(new: impl Fn() -> B) {
+    let checks: [(&str, Check<B>); 5] = [
+        ("group values", check_group_values),
+        ("key values", check_key_values),
+        ("proposals", check_proposals),
+        ("own leaf nodes", check_own_leaf_nodes),
+        ("epoch key pairs", check_epoch_key_pairs),
+    ];
+    for (name, check) in checks {
+        let backend = check(new());
+        assert_eq!(backend.len(), 0, "{name} left entries behind");
+    }
+}
+
+/// The store as serialized into the JSON state file.
+struct JsonStore(OpenMlsKeyValueStore);
+
+impl Backend for JsonStore {
+    type Store = OpenMlsKeyValueStore;
+    fn store(&self) -> &Self::Store {
+        &self.0
+    }
+    fn reopen(self) -> Self {
+        Self(serde_json::from_str(&serde_json::to_string(&self.0).unwrap()).unwrap())
+    }
+    fn len(&self) -> usize {
+        let values = serde_json::to_value(&self.0).unwrap();
+        values.as_object().unwrap().len()
+    }
+}
+
+/// The store of a state kept in a SQLite database.
+struct SqliteState {
+    path: String,
+    state: MySgmState,
+}
+
+impl Backend for SqliteState {
+    type Store = OpenMlsKeyValueStore;
+    fn store(&self) -> &Self::Store {
+        self.state.openmls_values()
+    }
+    fn reopen(self) -> Self {
+        let Self { path, state } = self;
+        sqlite::save(&path, &state).unwrap();
+        drop(state);
+        let state = sqlite::load(&path).unwrap();
+        Self { path, state }
+    }
+    fn len(&self) -> usize {
+        let values = serde_json::to_value(self.state.openmls_values()).unwrap();
+        values.as_object().unwrap().len()
+    }
+}
+
+#[test]
+fn key_value_store_json() {
+    run_suite(|| JsonStore(OpenMlsKeyValueStore::default()));
+}
+
+#[test]
+fn key_value_store_sqlite() {
+    let dir = TestDir::new("storage-conformance");
+    let databases = AtomicUsize::new(0);
+    run_suite(|| {
+        let n = databases.fetch_add(1, Ordering::Relaxed);
+        let path = dir.0.join(format!("state{n}.db"));
+        let state = Agent::generate_state(
+            "conformance",
+            Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519,
+        )
+        .unwrap();
+        SqliteState {
+            path: path.to_string_lossy().to_string(),
+            state,
+        }
+    });
+}
@@ -0,0 +1,102 @@
+//! Trust on first use: keys pinned for pids, key packages with other keys quarantined,
+//! and pins changed or revoked by hand.
+
+mod common;
+
+use common::{MemoryStore, agent, key_package_message};
+use mysgm::{MySgmError, identity::fingerprint, state::GroupPolicy};
+
+#[test]
+fn first_key_is_pinned_and_other_keys_are_quarantined() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    bob.advertise(0).unwrap();
+    alice.sync().unwrap();
+    let bob_pid = bob.state().my_pid().to_string();
+    let bob_key = fingerprint(bob.state().signature_key_pair().public_key_raw()).unwrap();
+    assert_eq!(
+        alice.pinned_fingerprint(&bob_pid).unwrap(),
+        Some(bob_key.clone())
+    );
+
+    // someone else claiming bob's pid
+    let (impostor, _) = key_package_message(&bob_pid);
+    store.insert("kp1", &impostor);
+    alice.sync().unwrap();
+    assert!(alice.state().quarantined(&bob_pid).is_some());
+    assert_eq!(alice.pinned_fingerprint(&bob_pid).unwrap(), Some(bob_key));
+    let gid = alice
+        .create_group("pinned", GroupPolicy::default())
+        .unwrap();
+    alice.add_members(&gid, &[bob_pid]).unwrap();
+    bob.sync().unwrap();
+    assert!(bob.state().gids().contains(&gid));
+}
+
+#[test]
+fn trusting_a_new_key_releases_its_key_package() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    bob.advertise(0).unwrap();
+    alice.sync().unwrap();
+    let bob_pid = bob.state().my_pid().to_string();
+    let (replacement, public_key) = key_package_message(&bob_pid);
+    store.insert("kp1", &replacement);
+    alice.sync().unwrap();
+
+    let new_key = fingerprint(&public_key).unwrap();
+    alice.trust_set(&bob_pid, &new_key).unwrap();
+    assert!(alice.state().quarantined(&bob_pid).is_none());
+    assert_eq!(alice.pinned_fingerprint(&bob_pid).unwrap(), Some(new_key));
+    // the released key package is a single-use one, and bob's last-resort one with the
+    // key that is no longer pinned is dropped
+    assert_eq!(alice.state().queued_key_packages(&bob_pid).len(), 1);
+    assert!(alice.state().key_package(&bob_pid).is_none());
+    let gid = alice
+        .create_group("repinned", GroupPolicy::default())
+        .unwrap();
+    alice.add_members(&gid, &[bob_pid]).unwrap();
+    bob.sync().unwrap();
+    assert!(!bob.state().gids().contains(&gid));
+}
+
+#[test]
+fn revoked_keys_are_refused_from_then_on() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let mut bob = agent("bob", &store);
+    bob.advertise(0).unwrap();
+    alice.sync().unwrap();
+    let bob_pid = bob.state().my_pid().to_string();
+    let bob_key = fingerprint(bob.state().signature_key_pair().public_key_raw()).unwrap();
+
+    alice.trust_revoke(&bob_pid, &bob_key).unwrap();
+    assert!(alice.state().is_revoked(&bob_key));
+    assert_eq!(alice.pinned_fingerprint(&bob_pid).unwrap(), None);
+    assert!(alice.state().key_package(&bob_pid).is_none());
+    // advertised again, the revoked key is not pinned anew
+    bob.advertise(0).unwrap();
+    alice.sync().unwrap();
+    assert!(alice.state().key_package(&bob_pid).is_none());
+    assert_eq!(alice.pinned_fingerprint(&bob_pid).unwrap(), None);
+}
+
+#[test]
+fn own_key_and_unknown_keys_cannot_be_changed() {
+    let store = MemoryStore::default();
+    let mut alice = agent("alice", &store);
+    let alice_pid = alice.state().my_pid().to_string();
+    let alice_key = fingerprint(alice.state().signature_key_pair().public_key_raw()).unwrap();
+
+    let Err(MySgmError::InvalidArgument(_)) = alice.trust_set(&alice_pid, &alice_key) else {
+        panic!("own key pinned");
+    };
+    let Err(MySgmError::InvalidArgument(_)) = alice.trust_set("bob_000", "not hex") else {
+        panic!("invalid fingerprint pinned");
+    };
+    let Err(MySgmError::InvalidArgument(_)) = alice.trust_revoke("bob_000", &alice_key) else {
+        panic!("key revoked for a pid it is not known for");
+    };
+}
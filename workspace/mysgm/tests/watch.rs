@@ -0,0 +1,87 @@
+//! Watches of the file adapter and the OpenDHT adapter: which events count as a stored
+//! value, and how many `LISTEN` streams are held open.
+
+mod common;
+
+use common::TestDir;
+use mysgm::{
+    adapter::WatchEvent,
+    file_adapter::FileAdapter,
+    opendht::{OpenDhtConfig, OpenDhtRestAdapter},
+};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    net::TcpListener,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+        mpsc::channel,
+    },
+    thread::{sleep, spawn},
+    time::Duration,
+};
+
+#[test]
+fn file_watch_reports_only_the_creation_of_its_key() {
+    let dir = TestDir::new("watch-file");
+    let adapter = FileAdapter::new(dir.0.to_str().unwrap());
+    adapter.put_checked("cm0", b"earlier").unwrap();
+    let (events, received) = channel();
+    adapter.watch("cm1", events).unwrap();
+
+    // changes to other files, and to files that already hold a value, are not values
+    let mut earlier = OpenOptions::new()
+        .append(true)
+        .open(dir.0.join("cm0"))
+        .unwrap();
+    earlier.write_all(b"00").unwrap();
+    earlier.sync_all().unwrap();
+    adapter.put_checked("cm2", b"other").unwrap();
+    assert!(received.recv_timeout(Duration::from_secs(1)).is_err());
+
+    adapter.put_checked("cm1", b"value").unwrap();
+    let Ok(WatchEvent::Stored(key)) = received.recv_timeout(Duration::from_secs(10)) else {
+        panic!("no notification for the stored key");
+    };
+    assert_eq!(key, "cm1");
+}
+
+#[test]
+fn file_watch_of_a_stored_key_reports_at_once() {
+    let dir = TestDir::new("watch-file-stored");
+    let adapter = FileAdapter::new(dir.0.to_str().unwrap());
+    adapter.put_checked("kp0", b"value").unwrap();
+    let (events, received) = channel();
+    adapter.watch("kp0", events).unwrap();
+
+    let Ok(WatchEvent::Stored(key)) = received.recv_timeout(Duration::from_secs(1)) else {
+        panic!("no notification for the key stored before the watch");
+    };
+    assert_eq!(key, "kp0");
+}
+
+#[test]
+fn dht_watches_share_a_bounded_set_of_streams() {
+    // a proxy that accepts LISTEN requests and never answers them
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", proxy.local_addr().unwrap());
+    let connections = Arc::new(AtomicUsize::new(0));
+    let accepted = connections.clone();
+    spawn(move || {
+        let mut streams = Vec::new();
+        for stream in proxy.incoming() {
+            accepted.fetch_add(1, Ordering::SeqCst);
+            streams.push(stream);
+        }
+    });
+    let adapter = OpenDhtRestAdapter::new(&url, OpenDhtConfig::default()).unwrap();
+    let (events, _received) = channel();
+    for n in 0..40 {
+        adapter.watch(&format!("cm{n}"), events.clone()).unwrap();
+    }
+
+    sleep(Duration::from_secs(2));
+    let open = connections.load(Ordering::SeqCst);
+    assert!(0 < open && open <= 16, "{open} streams open");
+}
@@ -0,0 +1,102 @@
+//! Agents with X.509 credentials, validated against trust anchors.
+//!
+//! The certificates in `tests/fixtures/x509` are Ed25519 ones valid for a century: `alice`
+//! and `bob` issued by `ca.pem`, and `carol` by `rogue-ca.pem`, which no agent trusts.
+
+mod common;
+
+use common::{CIPHERSUITE, MemoryStore, agent};
+use mysgm::{
+    Agent, MySgmError,
+    state::GroupPolicy,
+    x509::{TrustAnchors, load_identity},
+};
+use openmls_traits::types::Ciphersuite;
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/x509/{name}", env!("CARGO_MANIFEST_DIR"))
+}
+
+/// Agent with the certificate `name`, trusting `ca.pem`.
+fn x509_agent(name: &str, store: &MemoryStore) -> Agent {
+    let identity = load_identity(
+        &fixture(&format!("{name}.pem")),
+        &fixture(&format!("{name}.key")),
+    )
+    .unwrap();
+    let state = Agent::generate_x509_state(identity, CIPHERSUITE).unwrap();
+    let mut agent = Agent::new(state, Box::new(store.clone()));
+    agent.set_trust_anchors(Some(
+        TrustAnchors::from_pem_file(&fixture("ca.pem")).unwrap(),
+    ));
+    agent
+}
+
+#[test]
+fn agents_with_certificates_of_a_trusted_ca_form_groups() {
+    let store = MemoryStore::default();
+    let mut alice = x509_agent("alice", &store);
+    let mut bob = x509_agent("bob", &store);
+    assert_eq!(alice.state().my_pid(), "alice");
+    bob.advertise(0).unwrap();
+    alice.sync().unwrap();
+    assert!(alice.state().key_package("bob").is_some());
+
+    let gid = alice
+        .create_group("certified", GroupPolicy::default())
+        .unwrap();
+    alice.add_members(&gid, &["bob".to_string()]).unwrap();
+    bob.sync().unwrap();
+    assert!(bob.state().gids().contains(&gid));
+    alice.send_message(&gid, b"signed").unwrap();
+    bob.sync().unwrap();
+    assert_eq!(bob.read_messages(&gid).unwrap()[0].sender, "alice");
+}
+
+#[test]
+fn certificates_of_other_cas_are_refused() {
+    let store = MemoryStore::default();
+    let mut alice = x509_agent("alice", &store);
+    let mut carol = x509_agent("carol", &store);
+    carol.advertise(0).unwrap();
+
+    alice.sync().unwrap();
+    assert!(alice.state().key_package("carol").is_none());
+    assert_eq!(alice.state().key_package_counter(), 1);
+}
+
+#[test]
+fn certificates_are_refused_without_trust_anchors() {
+    let store = MemoryStore::default();
+    let mut bob = x509_agent("bob", &store);
+    let mut dave = agent("dave", &store);
+    bob.advertise(0).unwrap();
+
+    dave.sync().unwrap();
+    assert!(dave.state().key_package("bob").is_none());
+}
+
+#[test]
+fn mismatched_keys_and_ciphersuites_are_refused() {
+    let Err(MySgmError::InvalidArgument(_)) =
+        load_identity(&fixture("alice.pem"), &fixture("bob.key"))
+    else {
+        panic!("certificate loaded with the key of another");
+    };
+    let identity = load_identity(&fixture("alice.pem"), &fixture("alice.key")).unwrap();
+    let Err(MySgmError::InvalidArgument(_)) = Agent::generate_x509_state(
+        identity,
+        Ciphersuite::MLS_128_DHKEMP256_AES128GCM_SHA256_P256,
+    ) else {
+        panic!("Ed25519 certificate used with a P-256 ciphersuite");
+    };
+}
+
+#[test]
+fn x509_identities_are_not_rotated() {
+    let store = MemoryStore::default();
+    let mut alice = x509_agent("alice", &store);
+    let Err(MySgmError::InvalidArgument(_)) = alice.rotate_signature_key() else {
+        panic!("X.509 identity rotated");
+    };
+}